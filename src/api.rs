@@ -0,0 +1,381 @@
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+
+use crate::common::control::{self, FolderStatus, Request, Response};
+use crate::common::daemon::PidFile;
+use crate::common::data::DeviceId;
+use crate::common::error::{IllegalState, NotInitialized};
+use crate::common::folder::{folder_id, set_folder_id, FolderId, Folders};
+use crate::common::identity::{self, device_id, fingerprint};
+use crate::common::net;
+use crate::common::peers::Peers;
+use crate::common::transport::Keypair;
+use crate::common::util::is_safe_relative_path;
+use crate::common::version_control::{self, ChangeEvent, Inbound, Settings};
+use crate::{peer, server};
+
+/// An initialized folder that is not being synced yet.
+pub struct SyncFolder {
+    root: PathBuf,
+    keypair: Keypair,
+}
+
+impl SyncFolder {
+    /// Sets up the directory as a synchronized folder, like the init command. The id defaults
+    /// to the name of the directory. An existing folder is opened as it is.
+    pub fn init(root: &Path, id: Option<&str>, threads: usize) -> Result<Self, Box<dyn Error>> {
+        if id.is_some_and(|id| id.trim().is_empty()) {
+            return Err(Box::new(IllegalState::new("The folder id cannot be empty".to_string())));
+        }
+        if version_control::init(root, threads)? {
+            set_folder_id(root, &id.map(str::to_string).unwrap_or(folder_id(root)?))?;
+        }
+        Self::open(root)
+    }
+
+    /// Opens a folder set up with `init`, creating the device identity if it has none.
+    pub fn open(root: &Path) -> Result<Self, Box<dyn Error>> {
+        if !version_control::is_initialized(root) {
+            return Err(Box::new(NotInitialized { dir: root.to_path_buf() }));
+        }
+        // Paths reported by the watcher are absolute
+        let root = root.canonicalize()?;
+        let keypair = identity::load_or_create_keypair(&root.join(".rdovetail"))?;
+        Ok(SyncFolder {
+            root,
+            keypair,
+        })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn id(&self) -> io::Result<FolderId> {
+        folder_id(&self.root)
+    }
+
+    pub fn device(&self) -> DeviceId {
+        device_id(&self.keypair.public)
+    }
+
+    /// The fingerprint peers pair with.
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&self.keypair.public)
+    }
+
+    /// Journals the changes made while the folder was not synced, like the scan command.
+    /// Returns the number of changed and removed files.
+    pub fn scan(&self, settings: &Settings) -> Result<(usize, usize), Box<dyn Error>> {
+        let _pid_file = PidFile::acquire(&self.root)?;
+        version_control::scan(&self.root, self.device(), settings)
+    }
+
+    /// Locks the folder and starts watching it. No peers are connected until asked to.
+    pub fn start(self, settings: &Settings) -> Result<Syncing, Box<dyn Error>> {
+        let pid_file = PidFile::acquire(&self.root)?;
+        let peers = Arc::new(Peers::new());
+        let folders = Folders::start(std::slice::from_ref(&self.root), self.device(), settings, &peers)?;
+        let tx_to_vcs = folders.iter()
+            .next()
+            .map(|folder| folder.tx_to_vcs.clone())
+            .ok_or_else(|| IllegalState::new("The folder did not start".to_string()))?;
+        Ok(Syncing {
+            root: self.root,
+            keypair: self.keypair,
+            folders,
+            peers,
+            tx_to_vcs,
+            stop: Arc::new(AtomicBool::new(false)),
+            _pid_file: pid_file,
+        })
+    }
+}
+
+/// A change made to the folder by the embedding program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LocalChange {
+    /// Creates or replaces the file at the path relative to the folder.
+    Write {
+        path: PathBuf,
+        content: Vec<u8>,
+    },
+    Remove {
+        path: PathBuf,
+    },
+}
+
+/// A folder being watched and synced with the connected peers, answering its control socket
+/// like the start command.
+pub struct Syncing {
+    root: PathBuf,
+    keypair: Keypair,
+    folders: Folders,
+    peers: Arc<Peers>,
+    tx_to_vcs: Sender<Inbound>,
+    /// Set on shutdown, ends the dial and accept loops.
+    stop: Arc<AtomicBool>,
+    _pid_file: PidFile,
+}
+
+impl Syncing {
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Every change journaled from now on, whether made locally or received from a peer.
+    pub fn subscribe(&self) -> io::Result<Receiver<ChangeEvent>> {
+        let (events, rx_events) = channel();
+        self.send(Inbound::Subscribe { events })?;
+        Ok(rx_events)
+    }
+
+    /// Writes or removes the file and journals the change, which is then sent to every peer.
+    pub fn apply(&self, change: LocalChange) -> io::Result<()> {
+        let path = match &change {
+            LocalChange::Write { path, .. } | LocalChange::Remove { path } => path,
+        };
+        if !is_safe_relative_path(path) || !path.components().any(|component| matches!(component, Component::Normal(_))) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{:?} is not a path inside the folder", path)));
+        }
+        let path = self.root.join(path);
+        // The watcher reports the change as well, the VCS ignores whichever comes second
+        let inbound = match change {
+            LocalChange::Write { content, .. } => {
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::write(&path, content)?;
+                Inbound::FileCreated { path }
+            },
+            LocalChange::Remove { .. } => {
+                fs::remove_file(&path)?;
+                Inbound::FileRemoved { path }
+            },
+        };
+        self.send(inbound)
+    }
+
+    pub fn status(&self) -> io::Result<FolderStatus> {
+        match self.control(Request::Status)? {
+            Response::Status(status) => Ok(status),
+            response => Err(io::Error::other(format!("Unexpected answer {:?}", response))),
+        }
+    }
+
+    /// Stops exchanging changes with peers, local changes are still journaled.
+    pub fn pause(&self) -> io::Result<()> {
+        self.control(Request::Pause).map(|_| ())
+    }
+
+    /// Catches up with every connected peer after a pause.
+    pub fn resume(&self) -> io::Result<()> {
+        self.control(Request::Resume).map(|_| ())
+    }
+
+    /// Keeps a connection to the peer open in the background, reconnecting when it drops. The
+    /// peer has to be paired with the folder.
+    pub fn connect(&self, address: &str) {
+        let address = address.to_string();
+        let keypair = self.keypair.clone();
+        let folders = self.folders.clone();
+        let peers = Arc::clone(&self.peers);
+        let stop = Arc::clone(&self.stop);
+        thread::spawn(move || peer::dial(address, None, keypair, folders, peers, stop));
+    }
+
+    /// Accepts connections from paired peers on the addresses in the background, on the default
+    /// address if none are given. Returns the addresses bound.
+    pub fn listen(&self, addresses: &[String]) -> io::Result<Vec<SocketAddr>> {
+        let listeners = net::bind(addresses)?;
+        let bound = listeners.iter()
+            .map(|listener| listener.local_addr())
+            .collect::<io::Result<Vec<_>>>()?;
+        let keypair = self.keypair.clone();
+        let folders = self.folders.clone();
+        let peers = Arc::clone(&self.peers);
+        let stop = Arc::clone(&self.stop);
+        thread::spawn(move || server::serve(listeners, keypair, folders, peers, stop));
+        Ok(bound)
+    }
+
+    /// The metrics of the process in the Prometheus text format.
+    pub fn metrics(&self) -> String {
+        self.peers.metrics.render()
+    }
+
+    /// Stops syncing and unlocks the folder, like dropping it does.
+    pub fn shutdown(self) {
+        drop(self);
+    }
+
+    /// Answers the request like the control socket does, errors of the VCS included.
+    fn control(&self, request: Request) -> io::Result<Response> {
+        match control::ask(&self.tx_to_vcs, request) {
+            Response::Error(message) => Err(io::Error::other(message)),
+            response => Ok(response),
+        }
+    }
+
+    fn send(&self, inbound: Inbound) -> io::Result<()> {
+        self.tx_to_vcs.send(inbound)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "The folder has stopped"))
+    }
+}
+
+/// Stops dialing peers and accepting connections, closes the open ones, then writes out the
+/// state of the folder and stops watching it. The folder is unlocked once the pid file drops.
+impl Drop for Syncing {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        self.peers.close();
+        self.folders.shutdown();
+        control::remove_socket(&self.root);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::identity::TrustedPeers;
+    use crate::common::peers::Origin;
+    use std::env;
+    use std::time::Duration;
+
+    /// Waits up to five seconds for the condition to hold.
+    fn eventually(condition: impl Fn() -> bool) -> bool {
+        for _ in 0..250 {
+            if condition() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        false
+    }
+
+    /// Folders with the same id that all trust each other.
+    fn trusting_folders(name: &str, count: usize) -> (PathBuf, Vec<SyncFolder>) {
+        let dir = env::temp_dir().join(format!("rdovetail-api-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let folders: Vec<SyncFolder> = (0..count)
+            .map(|index| {
+                let root = dir.join(index.to_string());
+                fs::create_dir_all(&root).unwrap();
+                SyncFolder::init(&root, Some(name), 1).unwrap()
+            })
+            .collect();
+        for folder in &folders {
+            let mut trusted = TrustedPeers::from_file(&folder.root.join(".rdovetail")).unwrap();
+            for other in folders.iter().filter(|other| other.root != folder.root) {
+                trusted.add(other.keypair.public.clone(), other.fingerprint());
+            }
+            trusted.write_to_file().unwrap();
+        }
+        (dir, folders)
+    }
+
+    #[test]
+    fn applied_changes_are_journaled_and_published() {
+        let root = env::temp_dir().join(format!("rdovetail-api-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+
+        let folder = SyncFolder::init(&root, Some("notes"), 1).unwrap();
+        assert_eq!(folder.id().unwrap(), "notes");
+        let syncing = folder.start(&Settings::default()).unwrap();
+        assert!(SyncFolder::open(&root).unwrap().start(&Settings::default()).is_err());
+        let events = syncing.subscribe().unwrap();
+
+        syncing.apply(LocalChange::Write { path: PathBuf::from("a/b.txt"), content: b"hello".to_vec() }).unwrap();
+        let (origin, change) = events.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(origin, Origin::Local);
+        assert_eq!(change.file_path, Path::new(".").join("a").join("b.txt"));
+        assert_eq!(fs::read(root.join("a").join("b.txt")).unwrap(), b"hello");
+        assert!(syncing.apply(LocalChange::Remove { path: PathBuf::from("../b.txt") }).is_err());
+        assert_eq!(syncing.status().unwrap().tracked_files, 1);
+
+        syncing.shutdown();
+        assert!(SyncFolder::open(&root).is_ok_and(|folder| folder.scan(&Settings::default()).is_ok()));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn shutdown_stops_accepting_connections() {
+        let root = env::temp_dir().join(format!("rdovetail-api-listen-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+
+        let syncing = SyncFolder::init(&root, None, 1).unwrap().start(&Settings::default()).unwrap();
+        let bound = syncing.listen(&["127.0.0.1:0".to_string()]).unwrap();
+        syncing.connect(&bound[0].to_string());
+        syncing.shutdown();
+
+        // The listener is dropped once its loop sees the flag, which frees the port
+        let mut released = false;
+        for _ in 0..50 {
+            if std::net::TcpListener::bind(bound[0]).is_ok() {
+                released = true;
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert!(released);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn dropping_closes_connections_and_the_control_socket() {
+        let (dir, folders) = trusting_folders("drop", 2);
+        let mut started = folders.into_iter().map(|folder| folder.start(&Settings::default()).unwrap());
+        let (a, b) = (started.next().unwrap(), started.next().unwrap());
+        let bound = a.listen(&["127.0.0.1:0".to_string()]).unwrap();
+        b.connect(&bound[0].to_string());
+        assert!(eventually(|| a.status().unwrap().connected_peers == 1));
+        // Once the messages in flight have arrived nothing is sent while paused, a message
+        // reaching the stopped folder would end the session on its own
+        a.pause().unwrap();
+        thread::sleep(Duration::from_millis(200));
+
+        let root = b.root().to_path_buf();
+        drop(b);
+        assert!(control::request(&root, &Request::Status).unwrap().is_none());
+        // The dial thread would reconnect if the session were still running
+        assert!(eventually(|| a.status().unwrap().connected_peers == 0));
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(a.status().unwrap().connected_peers, 0);
+        assert!(SyncFolder::open(&root).unwrap().start(&Settings::default()).is_ok());
+
+        drop(a);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn changes_reach_every_client_of_a_server() {
+        let (dir, folders) = trusting_folders("fanout", 3);
+        let started: Vec<Syncing> = folders.into_iter().map(|folder| folder.start(&Settings::default()).unwrap()).collect();
+        let (server, clients) = started.split_first().unwrap();
+        let bound = server.listen(&["127.0.0.1:0".to_string()]).unwrap();
+        for client in clients {
+            client.connect(&bound[0].to_string());
+        }
+        assert!(eventually(|| server.status().unwrap().connected_peers == 2));
+
+        for (index, client) in clients.iter().enumerate() {
+            let path = PathBuf::from(format!("from-{}.txt", index));
+            client.apply(LocalChange::Write { path: path.clone(), content: vec![index as u8; 3] }).unwrap();
+            for folder in &started {
+                assert!(eventually(|| fs::read(folder.root().join(&path)).is_ok_and(|content| content == [index as u8; 3])));
+            }
+        }
+
+        drop(started);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,242 @@
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use clap::{Parser, Subcommand, ValueEnum};
+use rdovetail::{DEFAULT_KEEP_DAYS, DEFAULT_KEEP_VERSIONS, DEFAULT_TRASH_DAYS};
+
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+pub struct Args {
+    /// The synchronized folder, defaults to the root in the config of the user or the current
+    /// directory
+    #[arg(long, global = true)]
+    pub root: Option<PathBuf>,
+
+    /// Host name or IP-address of the server being linked to, or the address to listen on. The
+    /// port defaults to 50010.
+    #[arg(short, long, global = true)]
+    pub ip: Option<String>,
+
+    /// Address to accept connections on, can be given several times. Defaults to --ip, or to
+    /// port 50010 on all interfaces over IPv6 and IPv4.
+    #[arg(long = "listen", global = true)]
+    pub listen: Vec<String>,
+
+    /// Indicates that the current machine should act as a server, and receive incoming connections
+    /// from other machines.
+    #[arg(short, action, global = true)]
+    pub server_mode: bool,
+
+    /// Host name or address of a peer to keep in sync with, can be given several times. Runs the
+    /// machine as a peer in a mesh, listening like a server while dialing every peer.
+    #[arg(short, long = "peer", global = true)]
+    pub peers: Vec<String>,
+
+    /// Find trusted peers on the local network through UDP multicast, and announce this machine
+    /// to them. Runs the machine as a peer in a mesh.
+    #[arg(long, action, global = true)]
+    pub discover: bool,
+
+    /// Local interface address to use for discovery, the OS picks one by default
+    #[arg(long, global = true)]
+    pub discovery_interface: Option<Ipv4Addr>,
+
+    /// Id under which init sets up the folder, devices sync the folders with the same id.
+    /// Defaults to the name of the root directory.
+    #[arg(long, global = true)]
+    pub folder: Option<String>,
+
+    /// Another initialized folder to sync over the same connections, can be given several
+    /// times. Every folder has its own id, ignore rules and trusted peers.
+    #[arg(long = "extra-root", global = true)]
+    pub extra_roots: Vec<PathBuf>,
+
+    /// How concurrent edits of the same file are resolved [default: keep-both]
+    #[arg(long, value_enum, global = true)]
+    pub conflict_policy: Option<ConflictPolicyArg>,
+
+    /// Name of the trusted peer whose edits win conflicts, required by --conflict-policy
+    /// prefer-peer
+    #[arg(long, global = true)]
+    pub preferred_peer: Option<String>,
+
+    /// Number of recent versions of every file kept for restoring
+    #[arg(long, default_value_t = DEFAULT_KEEP_VERSIONS, global = true)]
+    pub keep_versions: usize,
+
+    /// Days for which the last version of each day is kept for restoring
+    #[arg(long, default_value_t = DEFAULT_KEEP_DAYS, global = true)]
+    pub keep_days: u64,
+
+    /// Days for which files deleted by other devices are kept in the trash
+    #[arg(long, default_value_t = DEFAULT_TRASH_DAYS, global = true)]
+    pub trash_days: u64,
+
+    /// Hours between snapshots of the whole folder taken while running, the last 10 are kept
+    #[arg(long, global = true)]
+    pub snapshot_every: Option<u64>,
+
+    /// Number of threads hashing files when a folder is indexed [default: 4]
+    #[arg(long, global = true)]
+    pub threads: Option<usize>,
+
+    /// Maximum rate at which file contents are sent, in KiB per second
+    #[arg(long, global = true)]
+    pub upload_limit: Option<u64>,
+
+    /// Maximum rate at which file contents are received, in KiB per second
+    #[arg(long, global = true)]
+    pub download_limit: Option<u64>,
+
+    /// Log more, -vv for everything
+    #[arg(short, long, action = clap::ArgAction::Count, global = true)]
+    pub verbose: u8,
+
+    /// Log less, -qq for errors only and -qqq for nothing
+    #[arg(short, long, action = clap::ArgAction::Count, global = true, conflicts_with = "verbose")]
+    pub quiet: u8,
+
+    /// Also log as JSON lines to .rdovetail/logs, rotated at 10 MiB
+    #[arg(long, action, global = true)]
+    pub json_log: bool,
+
+    /// Address to serve Prometheus metrics on while running, e.g. 127.0.0.1:9464
+    #[arg(long, global = true)]
+    pub metrics: Option<String>,
+
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Set up the folder given by --root, or the current directory, as a synchronized folder.
+    Init,
+    /// Keep the folder in sync: connect to the server given by --ip, act as the server with -s,
+    /// or join a mesh with --peer and --discover.
+    Start {
+        /// Keep running in the background, with the output going to .rdovetail/daemon.log
+        #[arg(long, action)]
+        daemon: bool,
+    },
+    /// Summarize the state of the folder and the changes waiting for peers.
+    Status,
+    /// Exchange all changes with the server given by --ip or the peers given by --peer, then
+    /// exit. With rdovetail running, makes it exchange sync states with its connected peers.
+    Sync,
+    /// Journal the changes made to the folder while rdovetail was not running, or have the
+    /// running process look for changes its watcher missed.
+    Scan,
+    /// List the trusted peers.
+    Peers,
+    /// Stop exchanging changes with peers in the running process, local changes are still
+    /// journaled.
+    Pause,
+    /// Catch up with the peers after a pause.
+    Resume,
+    /// Pair with another device, which runs the same command in the opposite mode. Both devices
+    /// show a pairing code that has to be confirmed before the other device is trusted.
+    Pair {
+        /// Name to store the other device under, defaults to its IP-address
+        #[arg(short, long)]
+        name: Option<String>,
+    },
+    /// List the conflicts detected between concurrent edits, and whether the conflict copies
+    /// still have to be looked at.
+    Conflicts,
+    /// List the stored versions of a file.
+    History {
+        path: PathBuf,
+    },
+    /// Write a previous version of a file back into the directory.
+    Restore {
+        path: PathBuf,
+        /// Restores the last version from before this time, as YYYYMMDD-HHMMSS in UTC like in
+        /// the history listing
+        #[arg(long)]
+        at: String,
+    },
+    /// Manage the files deleted by other devices.
+    Trash {
+        #[command(subcommand)]
+        action: TrashAction,
+    },
+    /// Manage snapshots of the whole folder.
+    Snapshot {
+        #[command(subcommand)]
+        action: SnapshotAction,
+    },
+    /// Inspect the options read from .rdovetail/config and the config of the user.
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+}
+
+impl Command {
+    /// Whether the command writes to the folder, rather than only reading it.
+    pub fn writes(&self) -> bool {
+        !matches!(
+            self,
+            Command::Status
+                | Command::Peers
+                | Command::Pause
+                | Command::Resume
+                | Command::Conflicts
+                | Command::History { .. }
+                | Command::Trash { action: TrashAction::List }
+                | Command::Snapshot { action: SnapshotAction::List | SnapshotAction::Diff { .. } }
+                | Command::Config { .. }
+        )
+    }
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum ConfigAction {
+    /// Print the options in effect, after applying the arguments over the config of the folder
+    /// and the config of the user.
+    Show,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum TrashAction {
+    /// List the files in the trash.
+    List,
+    /// Put a file from the trash back at its original path.
+    Restore {
+        /// Id of the entry as shown by the listing
+        id: String,
+    },
+    /// Remove every file from the trash.
+    Empty,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum SnapshotAction {
+    /// Snapshot the folder as it is now.
+    Create {
+        /// Defaults to the current time as YYYYMMDD-HHMMSS in UTC
+        name: Option<String>,
+    },
+    /// List the snapshots, oldest first.
+    List,
+    /// Show the files added (A), modified (M) and removed (D) since a snapshot.
+    Diff {
+        from: String,
+        /// Snapshot to compare with, defaults to the folder as it is now
+        to: Option<String>,
+    },
+    /// Roll the whole folder back to a snapshot, after snapshotting the current state.
+    Checkout {
+        name: String,
+    },
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictPolicyArg {
+    /// The newest edit wins, the other one is kept as a conflict copy
+    KeepBoth,
+    /// The newest edit wins, the other one is discarded
+    NewestWins,
+    /// Edits from the peer given by --preferred-peer win
+    PreferPeer,
+}
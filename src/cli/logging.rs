@@ -0,0 +1,229 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+use rdovetail::format_utc;
+
+/// Size at which the JSON log is rotated.
+const MAX_LOG_SIZE: u64 = 10 * 1024 * 1024;
+
+/// Number of rotated JSON logs kept next to the current one.
+const KEEP_LOGS: usize = 5;
+
+/// Which messages are logged: a default level and levels for single modules, parsed from
+/// directives like `info,peers=debug,version_control=trace`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Filter {
+    default: LevelFilter,
+    modules: Vec<(String, LevelFilter)>,
+}
+
+impl Filter {
+    pub fn new(default: LevelFilter) -> Self {
+        Filter {
+            default,
+            modules: Vec::new(),
+        }
+    }
+
+    /// Applies the directives over the filter, a bare level replaces the default.
+    pub fn parse(mut self, directives: &str) -> Result<Self, String> {
+        for directive in directives.split(',').map(str::trim).filter(|directive| !directive.is_empty()) {
+            let parse_level = |level: &str| level.trim().parse::<LevelFilter>()
+                .map_err(|_| format!("Invalid log level {}", level.trim()));
+            match directive.split_once('=') {
+                Some((module, level)) => self.modules.push((module.trim().to_string(), parse_level(level)?)),
+                None => self.default = parse_level(directive)?,
+            }
+        }
+        Ok(self)
+    }
+
+    /// Makes every level more verbose by the given number of steps, or quieter for negative ones.
+    pub fn shift(mut self, steps: i8) -> Self {
+        self.default = shift(self.default, steps);
+        for (_, level) in &mut self.modules {
+            *level = shift(*level, steps);
+        }
+        self
+    }
+
+    fn level_for(&self, target: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .rev()
+            .find(|(module, _)| module == target)
+            .map(|(_, level)| *level)
+            .unwrap_or(self.default)
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.modules.iter().map(|(_, level)| *level).fold(self.default, Ord::max)
+    }
+}
+
+fn shift(level: LevelFilter, steps: i8) -> LevelFilter {
+    let levels = LevelFilter::iter().collect::<Vec<_>>();
+    let index = (level as usize as i64 + steps as i64).clamp(0, levels.len() as i64 - 1);
+    levels[index as usize]
+}
+
+/// The module a message was logged from, without the crate and `common`, e.g. `peers`.
+fn short_target(target: &str) -> &str {
+    let target = target.strip_prefix("rdovetail::").unwrap_or(target);
+    target.strip_prefix("common::").unwrap_or(target)
+}
+
+/// Writes messages to stderr, and as JSON lines to the log file if there is one.
+struct Logger {
+    filter: Filter,
+    json: Option<Mutex<RotatingFile>>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.filter.level_for(short_target(metadata.target()))
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let time = timestamp();
+        let target = short_target(record.target());
+        let _ = writeln!(io::stderr().lock(), "{} {:<5} {}: {}", time, record.level(), target, record.args());
+
+        if let Some(json) = &self.json {
+            let line = serde_json::json!({
+                "time": time,
+                "level": record.level().as_str(),
+                "target": target,
+                "message": record.args().to_string(),
+            });
+            let result = json.lock().unwrap().write_line(&line.to_string());
+            if let Err(err) = result {
+                let _ = writeln!(io::stderr().lock(), "{} {:<5} logging: {:?}", time, Level::Error, err);
+            }
+        }
+    }
+
+    fn flush(&self) {
+        let _ = io::stderr().flush();
+    }
+}
+
+/// UTC time with milliseconds, e.g. `20240131-235959.123`.
+fn timestamp() -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    format!("{}.{:03}", format_utc(now.as_secs()), now.subsec_millis())
+}
+
+/// Directory of the JSON logs of the folder.
+pub fn log_dir(root: &Path) -> PathBuf {
+    root.join(".rdovetail").join("logs")
+}
+
+/// Installs the logger for the rest of the process. With a log directory, every message is
+/// also appended to `rdovetail.log` in there as a JSON object per line.
+pub fn init(filter: Filter, json_dir: Option<&Path>) -> io::Result<()> {
+    let json = match json_dir {
+        Some(dir) => Some(Mutex::new(RotatingFile::open(&dir.join("rdovetail.log"), MAX_LOG_SIZE, KEEP_LOGS)?)),
+        None => None,
+    };
+    let max_level = filter.max_level();
+    log::set_boxed_logger(Box::new(Logger { filter, json }))
+        .map_err(|err| io::Error::new(io::ErrorKind::AlreadyExists, err.to_string()))?;
+    log::set_max_level(max_level);
+    Ok(())
+}
+
+/// A log file that is moved to `<name>.1` once it reaches its maximum size, shifting the older
+/// ones up to `<name>.<keep>`.
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    keep: usize,
+}
+
+impl RotatingFile {
+    fn open(path: &Path, max_size: u64, keep: usize) -> io::Result<Self> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path: path.to_path_buf(),
+            file,
+            size,
+            max_size,
+            keep,
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 + 1 > self.max_size {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let rotated = |n: usize| PathBuf::from(format!("{}.{}", self.path.display(), n));
+        let _ = fs::remove_file(rotated(self.keep));
+        for n in (1..self.keep).rev() {
+            match fs::rename(rotated(n), rotated(n + 1)) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                _ => {},
+            }
+        }
+        fs::rename(&self.path, rotated(1))?;
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn modules_get_their_own_levels() {
+        let filter = Filter::new(LevelFilter::Info).parse("warn, peers=debug").unwrap();
+        assert_eq!(filter.level_for("version_control"), LevelFilter::Warn);
+        assert_eq!(filter.level_for("peers"), LevelFilter::Debug);
+        assert_eq!(filter.max_level(), LevelFilter::Debug);
+
+        let louder = filter.shift(1);
+        assert_eq!(louder.level_for("version_control"), LevelFilter::Info);
+        assert_eq!(louder.level_for("peers"), LevelFilter::Trace);
+        assert_eq!(Filter::new(LevelFilter::Error).shift(-3).level_for("peers"), LevelFilter::Off);
+        assert!(Filter::new(LevelFilter::Info).parse("peers=loud").is_err());
+    }
+
+    #[test]
+    fn logs_are_rotated_when_full() {
+        let dir = env::temp_dir().join(format!("rdovetail-logs-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let path = dir.join("rdovetail.log");
+
+        let mut file = RotatingFile::open(&path, 10, 2).unwrap();
+        for line in ["first", "second", "third", "fourth"] {
+            file.write_line(line).unwrap();
+        }
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth\n");
+        assert_eq!(fs::read_to_string(dir.join("rdovetail.log.1")).unwrap(), "third\n");
+        assert_eq!(fs::read_to_string(dir.join("rdovetail.log.2")).unwrap(), "second\n");
+        assert!(!dir.join("rdovetail.log.3").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
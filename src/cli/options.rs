@@ -0,0 +1,173 @@
+use std::env;
+use std::error::Error;
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use clap::ValueEnum;
+use log::LevelFilter;
+use rdovetail::{is_initialized, pair, validate_root, Config, ConfigFile, ConflictPolicy, IllegalState, Mode, Retention, Settings};
+use super::args::{Args, ConflictPolicyArg};
+use super::logging::{self, Filter};
+
+/// Threads hashing files when neither the arguments nor the config give a number.
+const DEFAULT_THREADS: usize = 4;
+
+/// Resolves the synchronized folder: --root, the root in the config of the user or the current
+/// directory, in that order. Checks that the command can use it.
+pub fn sync_root(args: &Args) -> Result<PathBuf, Box<dyn Error>> {
+    let root = match &args.root {
+        Some(root) => root.clone(),
+        None => match ConfigFile::user_path().map(|path| ConfigFile::load(&path)).transpose()?.and_then(|config| config.root) {
+            Some(root) => root,
+            None => env::current_dir()
+                .map_err(|err| IllegalState::new(format!("Failed to read current directory: {}", err)))?,
+        },
+    };
+    Ok(validate_root(&root, args.command.writes())?)
+}
+
+/// Resolves the policy arguments, looking up the preferred peer among the trusted peers.
+fn conflict_policy(policy: ConflictPolicyArg, preferred_peer: Option<String>, root: &Path) -> Result<ConflictPolicy, IllegalState> {
+    match (policy, preferred_peer) {
+        (ConflictPolicyArg::KeepBoth, _) => Ok(ConflictPolicy::KeepBoth),
+        (ConflictPolicyArg::NewestWins, _) => Ok(ConflictPolicy::NewestWins),
+        (ConflictPolicyArg::PreferPeer, None) => {
+            Err(IllegalState::new("--conflict-policy prefer-peer requires --preferred-peer".to_string()))
+        },
+        (ConflictPolicyArg::PreferPeer, Some(name)) => {
+            let preferred = pair::find(root, &name)
+                .map_err(|err| IllegalState::new(format!("Failed to read trusted peers: {}", err)))?;
+            match preferred {
+                Some(device) => Ok(ConflictPolicy::PreferPeer(device)),
+                None => Err(IllegalState::new(format!("No trusted peer named {}", name))),
+            }
+        },
+    }
+}
+
+/// Resolves the arguments and config files into the options of a running process.
+pub fn build_config(args: Args, root: PathBuf) -> Result<Config, IllegalState> {
+    let effective = effective_config(&args, &root)?;
+    let address = effective.ip.clone();
+    let peers = effective.peers.clone().unwrap_or_default();
+    let listen = match &effective.listen {
+        Some(listen) => listen.clone(),
+        None => address.iter().cloned().collect(),
+    };
+    let mode = if !peers.is_empty() || args.discover {
+        Mode::Peer
+    } else if args.server_mode {
+        Mode::Server
+    } else {
+        Mode::Client
+    };
+
+    if mode == Mode::Client && address.is_none() {
+        return Err(IllegalState::new("Client mode needs the address of the server, given by --ip".to_string()))
+    }
+
+    let extra_roots = extra_roots(&args, &root)?;
+    let settings = settings(&args, &root, &effective)?;
+    let config = Config {
+        address,
+        listen,
+        mode,
+        peers,
+        discover: args.discover,
+        discovery_interface: args.discovery_interface.unwrap_or(Ipv4Addr::UNSPECIFIED),
+        root,
+        extra_roots,
+        settings,
+        upload_limit: effective.upload_limit.map(|kib| kib.saturating_mul(1024)),
+        download_limit: effective.download_limit.map(|kib| kib.saturating_mul(1024)),
+        metrics: effective.metrics,
+    };
+    config.check_addresses()?;
+    Ok(config)
+}
+
+/// Resolves the folders given by --extra-root, which have to be initialized already.
+pub fn extra_roots(args: &Args, root: &Path) -> Result<Vec<PathBuf>, IllegalState> {
+    let mut extra_roots: Vec<PathBuf> = Vec::new();
+    for extra_root in &args.extra_roots {
+        let extra_root = validate_root(extra_root, args.command.writes())?;
+        if extra_root == root || extra_roots.contains(&extra_root) {
+            return Err(IllegalState::new(format!("Folder {:?} is given twice", extra_root)));
+        }
+        if !is_initialized(&extra_root) {
+            return Err(IllegalState::new(format!("Folder {:?} is not initialized, run the init command there", extra_root)));
+        }
+        extra_roots.push(extra_root);
+    }
+    Ok(extra_roots)
+}
+
+/// The options in effect: the arguments take precedence over the config of the folder, which
+/// takes precedence over the config of the user. Options set nowhere keep their defaults.
+pub fn effective_config(args: &Args, root: &Path) -> Result<ConfigFile, IllegalState> {
+    let arguments = ConfigFile {
+        ip: args.ip.clone(),
+        listen: Some(args.listen.clone()).filter(|listen| !listen.is_empty()),
+        peers: Some(args.peers.clone()).filter(|peers| !peers.is_empty()),
+        ignore: Vec::new(),
+        threads: args.threads,
+        upload_limit: args.upload_limit,
+        download_limit: args.download_limit,
+        conflict_policy: args.conflict_policy
+            .and_then(|policy| policy.to_possible_value())
+            .map(|value| value.get_name().to_string()),
+        preferred_peer: args.preferred_peer.clone(),
+        log: None,
+        json_log: args.json_log.then_some(true),
+        metrics: args.metrics.clone(),
+        root: args.root.clone(),
+    };
+    let files = ConfigFile::load_layered(root).map_err(|err| IllegalState::new(err.to_string()))?;
+    let mut effective = arguments.over(files);
+    effective.threads.get_or_insert(DEFAULT_THREADS);
+    effective.conflict_policy.get_or_insert_with(|| "keep-both".to_string());
+    if effective.threads == Some(0) {
+        return Err(IllegalState::new("At least one thread is needed".to_string()));
+    }
+    Ok(effective)
+}
+
+/// Logs at the level given by the config, info by default, made louder by -v and quieter by -q.
+pub fn init_logging(args: &Args, root: &Path) -> Result<(), Box<dyn Error>> {
+    let effective = effective_config(args, root)?;
+    let filter = Filter::new(LevelFilter::Info)
+        .parse(effective.log.as_deref().unwrap_or_default())
+        .map_err(IllegalState::new)?
+        .shift(args.verbose as i8 - args.quiet as i8);
+    let json_dir = match effective.json_log {
+        Some(true) => Some(logging::log_dir(root)),
+        _ => None,
+    };
+    logging::init(filter, json_dir.as_deref())?;
+    Ok(())
+}
+
+pub fn threads(effective: &ConfigFile) -> usize {
+    effective.threads.unwrap_or(DEFAULT_THREADS)
+}
+
+/// The options of the VCS, needed by every command that journals changes.
+pub fn settings(args: &Args, root: &Path, effective: &ConfigFile) -> Result<Settings, IllegalState> {
+    if args.snapshot_every == Some(0) {
+        return Err(IllegalState::new("--snapshot-every needs at least one hour".to_string()))
+    }
+    let policy = match effective.conflict_policy.as_deref() {
+        Some(policy) => ConflictPolicyArg::from_str(policy, true)
+            .map_err(|_| IllegalState::new(format!("Invalid conflict policy {}", policy)))?,
+        None => ConflictPolicyArg::KeepBoth,
+    };
+    Ok(Settings {
+        conflict_policy: conflict_policy(policy, effective.preferred_peer.clone(), root)?,
+        retention: Retention {
+            keep_last: args.keep_versions,
+            keep_daily_days: args.keep_days,
+        },
+        trash_days: args.trash_days,
+        snapshot_interval: args.snapshot_every.map(|hours| Duration::from_secs(hours.saturating_mul(3600))),
+    })
+}
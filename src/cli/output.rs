@@ -0,0 +1,178 @@
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use rdovetail::conflicts::{self, Conflict, Resolution};
+use rdovetail::history::FileVersion;
+use rdovetail::init::Initialized;
+use rdovetail::pair::{Paired, Pairing, PeerSummary};
+use rdovetail::snapshot::{Difference, Snapshot};
+use rdovetail::status::FolderReport;
+use rdovetail::sync::Scanned;
+use rdovetail::trash::TrashEntry;
+use rdovetail::{format_utc, short_device_id};
+
+pub fn initialized(dir: &Path, initialized: &Initialized) {
+    match initialized {
+        Initialized::Created { id, fingerprint } => {
+            println!("Initialized {} as folder {}", dir.display(), id);
+            println!("Device fingerprint: {}", fingerprint);
+        },
+        Initialized::Renamed { id } => println!("Changed the id of {} to {}", dir.display(), id),
+        Initialized::Existing => println!("{} is already an rdovetail folder", dir.display()),
+    }
+}
+
+pub fn status(dir: &Path, report: &FolderReport) {
+    println!("Folder: {}", dir.display());
+    match &report.device {
+        Some(fingerprint) => println!("Device: {}", fingerprint),
+        None => println!("Device: no identity yet, it is created when the folder is first synced or paired"),
+    }
+    println!("Tracked files: {}", report.tracked_files);
+    match report.last_change {
+        Some(last) => println!("Journaled changes: {}, the last from {}", report.journaled_changes, time(last)),
+        None => println!("Journaled changes: 0"),
+    }
+    if report.unscanned_changed + report.unscanned_removed > 0 {
+        println!(
+            "Not journaled yet: {} changed, {} removed, run the scan command or start syncing",
+            report.unscanned_changed,
+            report.unscanned_removed
+        );
+    }
+    println!("Open conflicts: {}", report.open_conflicts);
+    println!("Trusted peers: {}", report.trusted_peers);
+    for (name, unacknowledged) in &report.unacknowledged {
+        println!("  {}: {} changes not acknowledged", name, unacknowledged);
+    }
+    println!("Files in the trash: {}", report.trashed_files);
+    println!("Snapshots: {}", report.snapshots);
+    match &report.running {
+        Some(status) => {
+            let state = if status.paused { "paused" } else { "syncing" };
+            println!("Running: pid {}, {}", status.pid, state);
+            println!("  Connected peers: {}", status.connected_peers);
+            println!("  Files being received: {}", status.pending_transfers);
+        },
+        None => println!("Running: no"),
+    }
+}
+
+pub fn scanned(scanned: &[Scanned]) {
+    for scanned in scanned {
+        println!("Scanned folder {}: {} changed, {} removed", scanned.folder, scanned.changed, scanned.removed);
+    }
+}
+
+pub fn peers(peers: &[PeerSummary]) {
+    if peers.is_empty() {
+        println!("No trusted peers, run the pair command to add one");
+    }
+    for peer in peers {
+        println!(
+            "{} {} unacknowledged changes: {}{}",
+            peer.name,
+            peer.fingerprint,
+            peer.unacknowledged,
+            if peer.connected { ", connected" } else { "" }
+        );
+    }
+}
+
+/// Shows both devices and the pairing code, and asks whether the other device shows the same.
+pub fn confirm_pairing(pairing: &Pairing) -> io::Result<bool> {
+    println!("This device: {}", pairing.this_device);
+    println!("Other device: {}", pairing.other_device);
+    println!("Pairing code: {}", pairing.code);
+    print!("Does the other device show the same code? [y/N] ");
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+pub fn paired(paired: &Paired) {
+    for folder in &paired.folders {
+        println!("Paired with {} for folder {}", paired.name, folder);
+    }
+}
+
+pub fn conflicts(dir: &Path, conflicts: &[Conflict]) {
+    if conflicts.is_empty() {
+        println!("No conflicts");
+    }
+    for conflict in conflicts {
+        let time = time(conflict.timestamp);
+        let kept = match conflict.resolution {
+            Resolution::KeptLocal => "kept local",
+            Resolution::KeptRemote => "kept remote",
+            Resolution::Merged => "merged",
+        };
+        let open = conflicts::is_open(dir, conflict);
+        match (conflict.resolution, &conflict.copy) {
+            (Resolution::Merged, _) if open => {
+                println!("{} {:?} {}, open: edit the conflict markers", time, conflict.file_path, kept);
+            },
+            (_, Some(copy)) if open => {
+                println!("{} {:?} {}, open: compare with {:?}", time, conflict.file_path, kept, copy);
+            },
+            (Resolution::Merged, _) | (_, Some(_)) => println!("{} {:?} {}, resolved", time, conflict.file_path, kept),
+            (_, None) => println!("{} {:?} {}", time, conflict.file_path, kept),
+        }
+    }
+}
+
+pub fn history(path: &Path, versions: &[FileVersion]) {
+    if versions.is_empty() {
+        println!("No stored versions of {:?}", path);
+    }
+    for version in versions {
+        let content: String = version.content_hash[..6].iter().map(|byte| format!("{:02x}", byte)).collect();
+        println!("{} from {} content {}", time(version.timestamp), short_device_id(version.origin), content);
+    }
+}
+
+pub fn restored(path: &Path, version: &FileVersion) {
+    println!("Restored {:?} to the version from {}", path, time(version.timestamp));
+}
+
+pub fn trash(entries: &[(String, TrashEntry)]) {
+    if entries.is_empty() {
+        println!("The trash is empty");
+    }
+    for (id, entry) in entries {
+        println!(
+            "{} {:?} deleted {} by {}",
+            id,
+            entry.original_path,
+            time(entry.deleted_at),
+            short_device_id(entry.origin)
+        );
+    }
+}
+
+pub fn snapshots(snapshots: &[Snapshot]) {
+    if snapshots.is_empty() {
+        println!("No snapshots");
+    }
+    for snapshot in snapshots {
+        println!("{} created {} with {} files", snapshot.name, time(snapshot.created), snapshot.files.len());
+    }
+}
+
+pub fn differences(differences: &[Difference]) {
+    if differences.is_empty() {
+        println!("No differences");
+    }
+    for difference in differences {
+        match difference {
+            Difference::Added(path) => println!("A {}", path.display()),
+            Difference::Modified(path) => println!("M {}", path.display()),
+            Difference::Removed(path) => println!("D {}", path.display()),
+        }
+    }
+}
+
+/// Nanoseconds since the epoch as YYYYMMDD-HHMMSS in UTC.
+fn time(nanos: u64) -> String {
+    format_utc(nanos / 1_000_000_000)
+}
@@ -1,28 +1,90 @@
-use notify::{Result};
-use crate::common::{version_control, message::Message};
-use crate::Config;
-use core::panic;
-use std::io::Write;
-use std::net::TcpStream;
-
-pub fn init(config: &Config) -> Result<()> {
-    let res = version_control::start();
-    let mut stream = TcpStream::connect(config.address)?;
-    
-    let (tx_to_vcs, rx_from_vcs) = match res {
-        Ok((tx, rx)) => (tx, rx),
-        Err(err) => panic!("An error occurred: {:?}", err),
-    };
-    println!("Ready");
-
-    let message = rx_from_vcs.recv().unwrap();
-    let encoded = bincode::serialize(&message).unwrap();
-    let message_length = encoded.len() as u64;
-    stream.write_all(&message_length.to_be_bytes())?;
-    stream.write_all(&encoded)?;
-    stream.flush()?;
-
-    println!("Message sent");
-    
-    Ok(())
+use crate::common::backoff::Backoff;
+use crate::common::compression::{negotiate, Codec, SUPPORTED_CODECS};
+use crate::common::error::ProtocolError;
+use crate::common::folder::Folders;
+use crate::common::framing::FrameCodec;
+use crate::common::message::Message;
+use crate::common::metrics;
+use crate::common::net;
+use crate::common::identity::{self, device_id, fingerprint};
+use crate::common::peers::{self, Peers};
+use crate::common::transport::{self, Keypair, SecureConnection, SecureReader, SecureWriter};
+use crate::{server, Config};
+use std::error::Error;
+
+use std::sync::Arc;
+use std::thread;
+use log::{info, warn};
+
+pub fn init(config: &Config) -> Result<(), Box<dyn Error>> {
+    let dovetail_dir = config.root.join(".rdovetail");
+    let keypair = identity::load_or_create_keypair(&dovetail_dir)?;
+
+    // The server is the only peer, everything the VCS of every folder emits for it goes through
+    // its queue. Changes made while disconnected are journaled and replayed once the session
+    // resumes.
+    let peers = Arc::new(Peers::with_limits(config.upload_limit, config.download_limit));
+    let folders = Folders::start(&config.roots(), device_id(&keypair.public), &config.settings, &peers)?;
+    folders.stop_on_signal()?;
+    if let Some(address) = &config.metrics {
+        metrics::serve(address, Arc::clone(&peers.metrics))?;
+    }
+
+    let frame_codec = FrameCodec::default();
+    let mut backoff = Backoff::default();
+    loop {
+        match connect(config.server(), &keypair, &folders, &frame_codec) {
+            Ok((mut reader, writer, codec, remote_static)) => {
+                info!("Ready, using codec {:?}", codec);
+                backoff.reset();
+                let result = peers::run_session(frame_codec, &mut reader, writer, codec, &remote_static, &peers, &folders);
+                server::report_closed(result);
+            },
+            // Retrying will not make the server trusted
+            Err(err @ ProtocolError::Untrusted(_)) => return Err(Box::new(err)),
+            Err(err) => warn!("Failed to connect to {}: {}", config.server(), err),
+        }
+
+        let delay = backoff.next_delay();
+        info!("Reconnecting in {} ms", delay.as_millis());
+        thread::sleep(delay);
+    }
+}
+
+/// Opens an encrypted connection to a peer trusted with any of the folders and negotiates the
+/// codec for file payloads. Host names are resolved, and every address they resolve to is tried
+/// in turn. Also returns the public key of the peer.
+pub fn connect(
+    address: &str,
+    keypair: &Keypair,
+    folders: &Folders,
+    frame_codec: &FrameCodec,
+) -> Result<(SecureReader, SecureWriter, Codec, Vec<u8>), ProtocolError> {
+    let stream = net::connect(address)?;
+    let SecureConnection {
+        mut reader,
+        mut writer,
+        remote_static,
+        ..
+    } = transport::connect(stream, keypair)?;
+
+    if !folders.is_trusted(&remote_static)? {
+        return Err(ProtocolError::Untrusted(fingerprint(&remote_static)));
+    }
+    let codec = handshake(frame_codec, &mut writer, &mut reader)?;
+    Ok((reader, writer, codec, remote_static))
+}
+
+/// Exchanges supported codecs with the server and returns the one to use for file payloads.
+fn handshake(
+    frame_codec: &FrameCodec,
+    writer: &mut SecureWriter,
+    reader: &mut SecureReader,
+) -> Result<Codec, ProtocolError> {
+    frame_codec.write_message(writer, &Message::Hello { codecs: SUPPORTED_CODECS.to_vec() })?;
+    match frame_codec.read_message(reader)? {
+        Message::Hello { codecs } => Ok(negotiate(&SUPPORTED_CODECS, &codecs)),
+        Message::Error { message } => Err(ProtocolError::Remote(message)),
+        other => Err(ProtocolError::UnexpectedMessage(format!("{:?}", other))),
+    }
 }
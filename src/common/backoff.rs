@@ -0,0 +1,77 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+/// Exponentially growing delay between reconnection attempts. Every delay is randomized, so
+/// peers that lost their connection at the same time do not reconnect in lockstep.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new(Duration::from_millis(500), Duration::from_secs(60))
+    }
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Backoff {
+            initial,
+            max,
+            current: initial,
+        }
+    }
+
+    /// Returns the delay before the next attempt, somewhere between half and all of the current
+    /// step, and doubles the step up to the maximum.
+    pub fn next_delay(&mut self) -> Duration {
+        let step = self.current;
+        self.current = (self.current * 2).min(self.max);
+
+        let half = step / 2;
+        let jitter_range = (step - half).as_millis() as u64;
+        let jitter = if jitter_range == 0 { 0 } else { random() % jitter_range };
+        half + Duration::from_millis(jitter)
+    }
+
+    /// Starts over from the initial delay, called once a connection succeeds.
+    pub fn reset(&mut self) {
+        self.current = self.initial;
+    }
+}
+
+/// Randomly seeded hasher output, good enough for jitter without pulling in a random crate.
+fn random() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays_grow_up_to_the_maximum() {
+        let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_millis(400));
+        let delays: Vec<Duration> = (0..5).map(|_| backoff.next_delay()).collect();
+
+        let steps = [100, 200, 400, 400, 400];
+        for (delay, step) in delays.iter().zip(steps) {
+            assert!(*delay >= Duration::from_millis(step / 2));
+            assert!(*delay <= Duration::from_millis(step));
+        }
+    }
+
+    #[test]
+    fn reset_starts_over() {
+        let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_secs(10));
+        for _ in 0..5 {
+            backoff.next_delay();
+        }
+        backoff.reset();
+        assert!(backoff.next_delay() <= Duration::from_millis(100));
+    }
+}
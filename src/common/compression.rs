@@ -0,0 +1,211 @@
+use std::fmt::Display;
+use std::io;
+use std::path::Path;
+use serde::{Serialize, Deserialize};
+
+use super::framing::DEFAULT_MAX_FRAME_SIZE;
+
+/// Extensions of file formats that are already compressed. Running these through a codec costs
+/// time and usually makes the payload slightly larger.
+const COMPRESSED_EXTENSIONS: [&str; 24] = [
+    "7z", "avi", "br", "bz2", "docx", "flac", "gif", "gz", "heic", "jar", "jpeg", "jpg",
+    "lz4", "mkv", "mov", "mp3", "mp4", "ogg", "png", "pptx", "rar", "webp", "xlsx", "zip",
+];
+
+/// Payloads smaller than this are sent as is, since the codec overhead outweighs any savings.
+const MIN_COMPRESSIBLE_SIZE: usize = 64;
+
+/// The compression codecs a peer is able to encode and decode, in order of preference.
+pub const SUPPORTED_CODECS: [Codec; 2] = [Codec::Lz4, Codec::None];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Codec {
+    None,
+    Lz4,
+}
+
+/// Picks the first codec in the local preference list that the remote peer also supports. Falls
+/// back to sending uncompressed data, which every peer understands.
+pub fn negotiate(local: &[Codec], remote: &[Codec]) -> Codec {
+    local.iter()
+        .find(|codec| remote.contains(codec))
+        .copied()
+        .unwrap_or(Codec::None)
+}
+
+/// Checks the file extension to determine if compressing the file is worth the effort.
+pub fn is_compressible(path: &Path) -> bool {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some(ext) => {
+            let ext = ext.to_ascii_lowercase();
+            !COMPRESSED_EXTENSIONS.contains(&ext.as_str())
+        },
+        None => true,
+    }
+}
+
+/// File content as it is sent over the wire.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Payload {
+    codec: Codec,
+    original_size: u64,
+    data: Vec<u8>,
+}
+
+impl Payload {
+    /// Wraps raw file content without compressing it.
+    pub fn raw(data: Vec<u8>) -> Self {
+        Payload {
+            codec: Codec::None,
+            original_size: data.len() as u64,
+            data,
+        }
+    }
+
+    pub fn get_original_size(&self) -> u64 {
+        self.original_size
+    }
+
+    pub fn get_wire_size(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    /// Splits uncompressed content into payloads of at most `size` bytes, so each can be
+    /// compressed and sent on its own. Compressed payloads are returned as they are.
+    pub fn split(self, size: usize) -> Vec<Payload> {
+        if self.codec != Codec::None || self.data.len() <= size {
+            return vec![self];
+        }
+        self.data.chunks(size).map(|chunk| Payload::raw(chunk.to_vec())).collect()
+    }
+
+    /// Compresses the payload with the given codec, unless the file type is already compressed,
+    /// the payload is too small, or compressing would not make it any smaller.
+    pub fn compress(&mut self, codec: Codec, path: &Path) {
+        if self.codec != Codec::None || codec == Codec::None {
+            return;
+        }
+        if self.data.len() < MIN_COMPRESSIBLE_SIZE || !is_compressible(path) {
+            return;
+        }
+
+        let compressed = match codec {
+            Codec::Lz4 => lz4_flex::compress(&self.data),
+            Codec::None => return,
+        };
+
+        if compressed.len() < self.data.len() {
+            self.data = compressed;
+            self.codec = codec;
+        }
+    }
+
+    /// Returns the original file content. The original size is checked first, as the buffer
+    /// for it is allocated before the data is decoded.
+    pub fn decompress(self) -> io::Result<Vec<u8>> {
+        if self.codec != Codec::None && self.original_size > DEFAULT_MAX_FRAME_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Payload too large."));
+        }
+        let original_size: usize = self.original_size
+            .try_into()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Payload too large."))?;
+
+        let data = match self.codec {
+            Codec::None => self.data,
+            Codec::Lz4 => lz4_flex::decompress(&self.data, original_size)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?,
+        };
+
+        if data.len() != original_size {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Payload size mismatch."));
+        }
+        Ok(data)
+    }
+}
+
+/// Running totals for file payloads passing through a connection.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct TransferStats {
+    files: u64,
+    original_bytes: u64,
+    wire_bytes: u64,
+}
+
+impl TransferStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, payload: &Payload) {
+        self.files += 1;
+        self.original_bytes += payload.get_original_size();
+        self.wire_bytes += payload.get_wire_size();
+    }
+
+    /// Size on the wire relative to the original size. Lower is better, 1.0 means no savings.
+    pub fn compression_ratio(&self) -> f64 {
+        if self.original_bytes == 0 {
+            return 1.0;
+        }
+        self.wire_bytes as f64 / self.original_bytes as f64
+    }
+}
+
+impl Display for TransferStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} file(s), {} bytes sent as {} bytes (ratio {:.2})",
+            self.files,
+            self.original_bytes,
+            self.wire_bytes,
+            self.compression_ratio()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiation_prefers_local_order() {
+        assert_eq!(negotiate(&SUPPORTED_CODECS, &[Codec::None, Codec::Lz4]), Codec::Lz4);
+        assert_eq!(negotiate(&SUPPORTED_CODECS, &[Codec::None]), Codec::None);
+        assert_eq!(negotiate(&SUPPORTED_CODECS, &[]), Codec::None);
+    }
+
+    #[test]
+    fn payload_round_trips_through_lz4() {
+        let content = b"rdovetail ".repeat(100);
+        let mut payload = Payload::raw(content.clone());
+        payload.compress(Codec::Lz4, Path::new("./notes.txt"));
+
+        assert_eq!(payload.codec, Codec::Lz4);
+        assert!(payload.get_wire_size() < payload.get_original_size());
+
+        let mut stats = TransferStats::new();
+        stats.record(&payload);
+        assert!(stats.compression_ratio() < 1.0);
+        assert_eq!(payload.decompress().unwrap(), content);
+    }
+
+    #[test]
+    fn oversized_payloads_are_rejected_before_decoding() {
+        let mut payload = Payload::raw(b"rdovetail ".repeat(100));
+        payload.compress(Codec::Lz4, Path::new("./notes.txt"));
+        payload.original_size = u32::MAX as u64;
+
+        assert_eq!(payload.decompress().unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn compressed_file_types_are_skipped() {
+        let content = b"rdovetail ".repeat(100);
+        let mut payload = Payload::raw(content.clone());
+        payload.compress(Codec::Lz4, Path::new("./photos/holiday.JPG"));
+
+        assert_eq!(payload.codec, Codec::None);
+        assert_eq!(payload.decompress().unwrap(), content);
+    }
+}
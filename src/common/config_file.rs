@@ -0,0 +1,126 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use serde::{Serialize, Deserialize};
+
+/// Options read from a TOML config file, either `.rdovetail/config` of the folder or the config
+/// of the user. Every option can be left out, and command line arguments take precedence.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ConfigFile {
+    /// Address of the server to connect to, or to listen on like `--ip`.
+    pub ip: Option<String>,
+    /// Addresses to accept connections on.
+    pub listen: Option<Vec<String>>,
+    /// Addresses of the peers to keep in sync with.
+    pub peers: Option<Vec<String>>,
+    /// Patterns of paths that are not synchronized, on top of `.rdovetailignore`.
+    pub ignore: Vec<String>,
+    /// Number of threads hashing files when a folder is indexed.
+    pub threads: Option<usize>,
+    /// Maximum rate at which file contents are sent, in KiB per second.
+    pub upload_limit: Option<u64>,
+    /// Maximum rate at which file contents are received, in KiB per second.
+    pub download_limit: Option<u64>,
+    pub conflict_policy: Option<String>,
+    pub preferred_peer: Option<String>,
+    /// Log level, optionally followed by levels for single modules, e.g. `info,peers=debug`.
+    pub log: Option<String>,
+    /// Whether to also log as JSON to `.rdovetail/logs`.
+    pub json_log: Option<bool>,
+    /// Address to serve Prometheus metrics on, e.g. `127.0.0.1:9464`.
+    pub metrics: Option<String>,
+    /// The synchronized folder when `--root` is not given. Only read from the config of the
+    /// user, the config of a folder is found through the root.
+    pub root: Option<PathBuf>,
+}
+
+impl ConfigFile {
+    /// Reads the file, a missing file sets no options.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => return Err(err),
+        };
+        toml::from_str(&content).map_err(|err| io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid config {}: {}", path.display(), err)
+        ))
+    }
+
+    /// The config of the folder laid over the config of the user.
+    pub fn load_layered(root: &Path) -> io::Result<Self> {
+        let user = match Self::user_path() {
+            Some(path) => Self::load(&path)?,
+            None => Self::default(),
+        };
+        Ok(Self::load(&folder_path(root))?.over(user))
+    }
+
+    /// Takes every option that is not set from `lower`. Ignore patterns are combined.
+    pub fn over(self, lower: ConfigFile) -> ConfigFile {
+        ConfigFile {
+            ip: self.ip.or(lower.ip),
+            listen: self.listen.or(lower.listen),
+            peers: self.peers.or(lower.peers),
+            ignore: [self.ignore, lower.ignore].concat(),
+            threads: self.threads.or(lower.threads),
+            upload_limit: self.upload_limit.or(lower.upload_limit),
+            download_limit: self.download_limit.or(lower.download_limit),
+            conflict_policy: self.conflict_policy.or(lower.conflict_policy),
+            preferred_peer: self.preferred_peer.or(lower.preferred_peer),
+            log: self.log.or(lower.log),
+            json_log: self.json_log.or(lower.json_log),
+            metrics: self.metrics.or(lower.metrics),
+            root: self.root.or(lower.root),
+        }
+    }
+
+    pub fn to_toml(&self) -> String {
+        toml::to_string(self).unwrap_or_default()
+    }
+
+    /// `$XDG_CONFIG_HOME/rdovetail/config`, or `~/.config/rdovetail/config` without it.
+    pub fn user_path() -> Option<PathBuf> {
+        let config_home = env::var_os("XDG_CONFIG_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+        Some(config_home.join("rdovetail").join("config"))
+    }
+}
+
+pub fn folder_path(root: &Path) -> PathBuf {
+    root.join(".rdovetail").join("config")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn folder_options_take_precedence() {
+        let folder: ConfigFile = toml::from_str("ip = \"nas.local\"\nignore = [\"*.tmp\"]\n").unwrap();
+        let user: ConfigFile = toml::from_str(
+            "ip = \"0.0.0.0:7000\"\nthreads = 2\nupload-limit = 512\nignore = [\"target/\"]\n"
+        ).unwrap();
+
+        let merged = folder.over(user);
+        assert_eq!(merged.ip.as_deref(), Some("nas.local"));
+        assert_eq!(merged.threads, Some(2));
+        assert_eq!(merged.upload_limit, Some(512));
+        assert_eq!(merged.download_limit, None);
+        assert_eq!(merged.ignore, vec!["*.tmp".to_string(), "target/".to_string()]);
+
+        let user: ConfigFile = toml::from_str("root = \"/srv/sync\"\n").unwrap();
+        assert_eq!(ConfigFile::default().over(user).root, Some(PathBuf::from("/srv/sync")));
+    }
+
+    #[test]
+    fn invalid_options_are_rejected() {
+        assert!(toml::from_str::<ConfigFile>("listen-address = \"0.0.0.0:6000\"").is_err());
+        assert!(toml::from_str::<ConfigFile>("listen = \"0.0.0.0:6000\"").is_err());
+    }
+}
@@ -0,0 +1,120 @@
+use std::path::{Path, PathBuf};
+use serde::{Serialize, Deserialize};
+
+use super::data::{ChangeId, DeviceId};
+use super::util::format_utc;
+
+/// How concurrent edits of the same file are resolved. Every device applies the same rule to
+/// the same pair of edits, so all of them end up with the same winner.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// The newest edit wins, the other one is kept as a conflict copy next to the file.
+    #[default]
+    KeepBoth,
+    /// The newest edit wins, the other one is discarded.
+    NewestWins,
+    /// Edits made on the given device win, conflicts between other devices go to the newest edit.
+    PreferPeer(DeviceId),
+}
+
+/// The device and time of one side of a conflict.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Edit {
+    pub origin: DeviceId,
+    /// Nanoseconds since the epoch.
+    pub timestamp: u64,
+}
+
+impl ConflictPolicy {
+    pub fn remote_wins(&self, local: &Edit, remote: &Edit) -> bool {
+        if let ConflictPolicy::PreferPeer(preferred) = self {
+            if (local.origin == *preferred) != (remote.origin == *preferred) {
+                return remote.origin == *preferred;
+            }
+        }
+        // The device id breaks ties, so both sides agree even on equal timestamps
+        (remote.timestamp, remote.origin) > (local.timestamp, local.origin)
+    }
+
+    pub fn keeps_copies(&self) -> bool {
+        *self == ConflictPolicy::KeepBoth
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Resolution {
+    KeptLocal,
+    KeptRemote,
+    /// Both edits were merged line by line, with conflict markers where they overlapped.
+    Merged,
+}
+
+/// A conflict detected on this device, recorded in the journal.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Conflict {
+    pub file_path: PathBuf,
+    /// The remote change that was concurrent with the local version of the file.
+    pub change: ChangeId,
+    pub resolution: Resolution,
+    /// Where the losing local version was kept, if the policy keeps both.
+    pub copy: Option<PathBuf>,
+    pub timestamp: u64,
+}
+
+/// Path for the losing version of a file, e.g. `notes.conflict-3f2a91b-20240131-235959.txt`.
+pub fn conflict_copy_path(path: &Path, edit: &Edit) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let mut name = format!(
+        "{}.conflict-{}-{}",
+        stem,
+        short_device_id(edit.origin),
+        format_utc(edit.timestamp / 1_000_000_000)
+    );
+    if let Some(extension) = path.extension() {
+        name.push('.');
+        name.push_str(&extension.to_string_lossy());
+    }
+    path.with_file_name(name)
+}
+
+/// First seven hex digits of the device id, enough to tell the devices of a folder apart.
+pub fn short_device_id(device: DeviceId) -> String {
+    format!("{:016x}", device)[..7].to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_device_picks_the_same_winner() {
+        let older = Edit { origin: 1, timestamp: 10 };
+        let newer = Edit { origin: 2, timestamp: 20 };
+        let tied = Edit { origin: 3, timestamp: 20 };
+
+        for policy in [ConflictPolicy::KeepBoth, ConflictPolicy::NewestWins] {
+            assert!(policy.remote_wins(&older, &newer));
+            assert!(!policy.remote_wins(&newer, &older));
+            assert!(policy.remote_wins(&newer, &tied));
+            assert!(!policy.remote_wins(&tied, &newer));
+        }
+
+        let prefer_first = ConflictPolicy::PreferPeer(1);
+        assert!(!prefer_first.remote_wins(&older, &newer));
+        assert!(prefer_first.remote_wins(&newer, &older));
+        assert!(prefer_first.remote_wins(&newer, &tied));
+    }
+
+    #[test]
+    fn conflict_copies_keep_the_extension() {
+        let edit = Edit { origin: 0x3f2a91bc0d4e77a1, timestamp: 1_706_745_599_000_000_000 };
+        assert_eq!(
+            conflict_copy_path(Path::new("./docs/notes.txt"), &edit),
+            PathBuf::from("./docs/notes.conflict-3f2a91b-20240131-235959.txt")
+        );
+        assert_eq!(
+            conflict_copy_path(Path::new("./Makefile"), &edit),
+            PathBuf::from("./Makefile.conflict-3f2a91b-20240131-235959")
+        );
+    }
+}
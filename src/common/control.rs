@@ -0,0 +1,229 @@
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use std::time::Duration;
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+use log::error;
+
+use super::conflict::Conflict;
+use super::data::DeviceId;
+use super::framing::DEFAULT_MAX_FRAME_SIZE;
+use super::version_control::Inbound;
+
+/// Asked of the process syncing a folder over its control socket.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Request {
+    Status,
+    /// Stops exchanging changes with peers, local changes are still journaled.
+    Pause,
+    /// Catches up with every connected peer after a pause.
+    Resume,
+    /// Journals the changes the watcher missed.
+    Rescan,
+    /// The peers connected to the folder.
+    Peers,
+    Conflicts,
+    /// Exchanges sync states with every connected peer and retransmits unacknowledged changes.
+    Sync,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Response {
+    Status(FolderStatus),
+    Peers(Vec<ConnectedPeer>),
+    Conflicts(Vec<Conflict>),
+    Scanned {
+        changed: usize,
+        removed: usize,
+    },
+    /// Number of peers asked to sync.
+    Syncing(usize),
+    Done,
+    Error(String),
+}
+
+/// The state of a folder kept by the running process.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FolderStatus {
+    pub pid: u32,
+    pub paused: bool,
+    pub tracked_files: usize,
+    pub journaled_changes: usize,
+    pub connected_peers: usize,
+    /// Remote changes waiting for the file content.
+    pub pending_transfers: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConnectedPeer {
+    pub device: DeviceId,
+    pub unacknowledged: usize,
+    /// Whether the peer has sent its sync state since it connected.
+    pub sent_state: bool,
+}
+
+/// `.rdovetail/control` of the folder.
+pub fn socket_path(root: &Path) -> PathBuf {
+    root.join(".rdovetail").join("control")
+}
+
+/// How often the control socket checks whether it was stopped while no requests come in.
+const ACCEPT_POLL: Duration = Duration::from_millis(100);
+
+/// Accepts requests on the control socket of the folder and hands them to its VCS, until the
+/// flag is set. Needs the pid file of the folder, as a socket left behind by a process that died
+/// is replaced.
+pub fn serve(root: &Path, tx_to_vcs: Sender<Inbound>, stop: Arc<AtomicBool>) -> io::Result<()> {
+    let path = socket_path(root);
+    remove_socket(root);
+    let listener = UnixListener::bind(&path)?;
+    // Blocking in accept would never see the flag
+    listener.set_nonblocking(true)?;
+    thread::spawn(move || {
+        while !stop.load(Ordering::SeqCst) {
+            let stream = match listener.accept().and_then(|(stream, _)| stream.set_nonblocking(false).map(|_| stream)) {
+                Ok(stream) => stream,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(ACCEPT_POLL);
+                    continue;
+                },
+                Err(err) => {
+                    error!("{:?}", err);
+                    continue;
+                },
+            };
+            let tx_to_vcs = tx_to_vcs.clone();
+            thread::spawn(move || {
+                if let Err(err) = answer(stream, &tx_to_vcs) {
+                    error!("{:?}", err);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+/// Answers every request on the connection until the client closes it.
+fn answer(mut stream: UnixStream, tx_to_vcs: &Sender<Inbound>) -> io::Result<()> {
+    while let Some(request) = read_frame::<Request, _>(&mut stream)? {
+        write_frame(&mut stream, &ask(tx_to_vcs, request))?;
+    }
+    Ok(())
+}
+
+/// Hands the request to the VCS and waits for its answer.
+pub fn ask(tx_to_vcs: &Sender<Inbound>, request: Request) -> Response {
+    let (reply, rx_reply) = channel();
+    match tx_to_vcs.send(Inbound::Control { request, reply }) {
+        Ok(()) => rx_reply.recv().unwrap_or_else(|_| Response::Error("The folder has stopped".to_string())),
+        Err(_) => Response::Error("The folder has stopped".to_string()),
+    }
+}
+
+pub fn remove_socket(root: &Path) {
+    let _ = fs::remove_file(socket_path(root));
+}
+
+/// Sends the request to the process syncing the folder, `None` if no process is. Errors
+/// reported by the process are returned as errors.
+pub fn request(root: &Path, request: &Request) -> io::Result<Option<Response>> {
+    let mut stream = match UnixStream::connect(socket_path(root)) {
+        Ok(stream) => stream,
+        Err(err) if matches!(err.kind(), io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused) => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    write_frame(&mut stream, request)?;
+    match read_frame(&mut stream)? {
+        Some(Response::Error(message)) => Err(io::Error::other(message)),
+        Some(response) => Ok(Some(response)),
+        None => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "The control socket closed without an answer")),
+    }
+}
+
+/// Like `request`, for requests that need a running process.
+pub fn request_running(root: &Path, request: &Request) -> io::Result<Response> {
+    self::request(root, request)?.ok_or_else(|| io::Error::new(
+            io::ErrorKind::NotConnected,
+            format!("rdovetail is not running on {}", root.display())
+    ))
+}
+
+/// Writes the value bincode encoded, prefixed by its length as a big endian u32.
+fn write_frame<T: Serialize>(stream: &mut UnixStream, value: &T) -> io::Result<()> {
+    let encoded = bincode::serialize(value)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    let length: u32 = encoded.len()
+        .try_into()
+        .ok()
+        .filter(|length| *length as u64 <= DEFAULT_MAX_FRAME_SIZE)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Control message too large."))?;
+    stream.write_all(&length.to_be_bytes())?;
+    stream.write_all(&encoded)
+}
+
+/// Reads a value written by `write_frame`, `None` once the other side has closed the socket.
+/// Frames above the size limit are rejected before any memory is allocated for them.
+fn read_frame<T: DeserializeOwned, R: Read>(stream: &mut R) -> io::Result<Option<T>> {
+    let mut length = [0u8; 4];
+    match stream.read_exact(&mut length) {
+        Ok(()) => {},
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+    let length = u32::from_be_bytes(length);
+    if length as u64 > DEFAULT_MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Control message of {} bytes exceeds the limit of {} bytes", length, DEFAULT_MAX_FRAME_SIZE)
+        ));
+    }
+    let mut encoded = vec![0u8; length as usize];
+    stream.read_exact(&mut encoded)?;
+    bincode::deserialize(&encoded)
+        .map(Some)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn requests_reach_the_vcs_and_answers_come_back() {
+        let root = env::temp_dir().join(format!("rdovetail-control-{}", std::process::id()));
+        fs::create_dir_all(root.join(".rdovetail")).unwrap();
+        assert!(request(&root, &Request::Status).unwrap().is_none());
+
+        let (tx_to_vcs, rx_vcs) = channel::<Inbound>();
+        let stop = Arc::new(AtomicBool::new(false));
+        serve(&root, tx_to_vcs, Arc::clone(&stop)).unwrap();
+        thread::spawn(move || {
+            for inbound in rx_vcs {
+                if let Inbound::Control { request: Request::Rescan, reply } = inbound {
+                    let _ = reply.send(Response::Scanned { changed: 2, removed: 1 });
+                } else if let Inbound::Control { reply, .. } = inbound {
+                    let _ = reply.send(Response::Error("unsupported".to_string()));
+                }
+            }
+        });
+
+        assert!(matches!(request(&root, &Request::Rescan).unwrap(), Some(Response::Scanned { changed: 2, removed: 1 })));
+        assert_eq!(request(&root, &Request::Pause).unwrap_err().to_string(), "unsupported");
+        stop.store(true, Ordering::SeqCst);
+        remove_socket(&root);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn oversized_frames_are_rejected() {
+        let frame = u32::MAX.to_be_bytes();
+        let err = read_frame::<Request, _>(&mut &frame[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
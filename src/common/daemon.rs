@@ -0,0 +1,139 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, Write};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Mutex;
+
+/// Log of a process running in the background, relative to the synchronized folder.
+pub const DAEMON_LOG: &str = ".rdovetail/daemon.log";
+
+/// Holds an exclusive lock on `.rdovetail/pid` of a folder for as long as the process syncs
+/// it, so no two processes write to the same folder. The lock is released by the OS when the
+/// process dies, a pid file left behind by a crash does not get in the way.
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+    file: File,
+}
+
+impl PidFile {
+    pub fn acquire(root: &Path) -> io::Result<Self> {
+        let path = path(root);
+        let file = loop {
+            let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path)?;
+            if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+                let mut pid = String::new();
+                file.read_to_string(&mut pid)?;
+                return Err(io::Error::new(
+                        io::ErrorKind::WouldBlock,
+                        format!("rdovetail is already running on {} with pid {}", root.display(), pid.trim())
+                ));
+            }
+            // The process holding the lock before may have removed the file after it was opened
+            let locked = file.metadata()?.ino();
+            if fs::metadata(&path).is_ok_and(|metadata| metadata.ino() == locked) {
+                break file;
+            }
+        };
+        let mut pid_file = PidFile {
+            path,
+            file,
+        };
+        pid_file.write_pid()?;
+        Ok(pid_file)
+    }
+
+    /// Locks every folder, so no other process syncs any of them while the pid files are held.
+    pub fn acquire_all(roots: &[PathBuf]) -> io::Result<Vec<Self>> {
+        roots.iter().map(|root| Self::acquire(root)).collect()
+    }
+
+    /// Records the current process, called again in the background process after forking.
+    pub fn write_pid(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.rewind()?;
+        writeln!(self.file, "{}", process::id())?;
+        self.file.sync_all()
+    }
+
+    /// Removes the pid file of the folder, for when the process exits without dropping the
+    /// `PidFile`.
+    pub fn remove(root: &Path) {
+        let _ = fs::remove_file(path(root));
+    }
+}
+
+fn path(root: &Path) -> PathBuf {
+    root.join(".rdovetail").join("pid")
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        // Removed while still locked, so a starting process cannot lock the file just before
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Detaches from the terminal and keeps running in the background, with the output appended
+/// to the log. Has to be called before any thread is spawned. The foreground process prints
+/// the pid of the background one and exits.
+pub fn daemonize(log: &Path) -> io::Result<()> {
+    let log = OpenOptions::new().create(true).append(true).open(log)?;
+    let dev_null = File::open("/dev/null")?;
+    match unsafe { libc::fork() } {
+        -1 => return Err(io::Error::last_os_error()),
+        0 => {},
+        pid => {
+            println!("Started in the background with pid {}", pid);
+            // Leaves the pid files to the background process
+            process::exit(0);
+        },
+    }
+    if unsafe { libc::setsid() } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // Every root is absolute, so the process does not need to keep its directory busy
+    std::env::set_current_dir("/")?;
+    for (from, to) in [(dev_null.as_raw_fd(), libc::STDIN_FILENO), (log.as_raw_fd(), libc::STDOUT_FILENO), (log.as_raw_fd(), libc::STDERR_FILENO)] {
+        if unsafe { libc::dup2(from, to) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Runs the handler once on the first SIGINT or SIGTERM, later signals are ignored while it
+/// runs.
+pub fn on_shutdown<F>(handler: F) -> io::Result<()>
+where
+    F: FnOnce() + Send + 'static,
+{
+    let handler = Mutex::new(Some(handler));
+    ctrlc::set_handler(move || {
+        if let Some(handler) = handler.lock().unwrap().take() {
+            handler();
+        }
+    }).map_err(io::Error::other)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn a_folder_is_locked_once() {
+        let root = env::temp_dir().join(format!("rdovetail-pid-{}", process::id()));
+        fs::create_dir_all(root.join(".rdovetail")).unwrap();
+
+        let pid_file = PidFile::acquire(&root).unwrap();
+        assert_eq!(fs::read_to_string(root.join(".rdovetail").join("pid")).unwrap(), format!("{}\n", process::id()));
+        assert_eq!(PidFile::acquire(&root).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+
+        drop(pid_file);
+        assert!(PidFile::acquire(&root).is_ok());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -1,19 +1,35 @@
 use core::panic;
 use std::path::{Path, PathBuf};
 use std::io::{self, Write};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::hash::Hash;
 use sha2::{Digest, Sha256};
+use serde::{Serialize, Deserialize};
+use log::warn;
 use crate::common::error::EntryConflict;
 use super::util::{as_nanos_since_epoch};
 
+/// Marks an index file written in the current format, followed by the format number.
+const INDEX_MAGIC: &[u8; 4] = b"RDVI";
+
+/// Entries carry a version vector.
+const INDEX_FORMAT: u32 = 2;
+
+/// Indexes written before the header existed, without version vectors.
+const LEGACY_INDEX_FORMAT: u32 = 1;
+
+fn invalid_index(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Corrupt index: {}", message))
+}
+
 #[derive(Debug)]
 pub struct FileData {
     hash: [u8; 32],
     path_from_root: Box<Path>,
     timestamp: SystemTime,
+    version: VersionVector,
 }
 
 impl FileData {
@@ -22,6 +38,7 @@ impl FileData {
             hash: [0; 32],
             path_from_root: Path::new(".").into(),
             timestamp: UNIX_EPOCH,
+            version: VersionVector::new(),
         }
     }
 
@@ -49,6 +66,14 @@ impl FileData {
         self.timestamp = timestamp
     }
 
+    pub fn get_version(&self) -> &VersionVector {
+        &self.version
+    }
+
+    pub fn set_version(&mut self, version: VersionVector) {
+        self.version = version
+    }
+
     fn serialize(&self) -> Vec<u8> {
         let mut bytes: Vec<u8> = Vec::new();
         let mut path = String::new();
@@ -56,17 +81,18 @@ impl FileData {
             let dir_str = match dir.to_str() {
                 Some(str) => str,
                 None => {
-                    eprintln!("Filepath is not valid UTF-8.");
+                    warn!("Filepath is not valid UTF-8.");
                     panic!();
                 }
             };
             path.push_str(dir_str);
-            path.push_str("/");
+            path.push('/');
         }
         path.pop();
         let path = path.as_bytes();
 
-        let data_length: u32 = (path.len() + 40).try_into().unwrap_or_else(|_| {
+        let version = self.version.serialize();
+        let data_length: u32 = (path.len() + 40 + version.len()).try_into().unwrap_or_else(|_| {
             panic!("Conversion from usize to u32 failed.");
         });
         let data_length = data_length.to_be_bytes();
@@ -88,18 +114,26 @@ impl FileData {
             bytes.push(byte);
         }
 
+        bytes.extend_from_slice(&version);
         bytes
     }
 
-    fn deserialize(bytes: &[u8]) -> Self {
-        // Last 48 bytes are hash and timestamp, everything up until that point is the path
-        let path = &bytes[0..bytes.len()-40];
-        let path = match std::str::from_utf8(path) {
-            Ok(path) => path,
-            Err(err) => {
-                panic!("Conversion from bytes to UTF-8 failed: {}", err);
-            }
+    /// Reads an entry of an index in the given format, legacy entries get an empty version.
+    fn deserialize(bytes: &[u8], format: u32) -> io::Result<Self> {
+        let (version, bytes) = if format == LEGACY_INDEX_FORMAT {
+            (VersionVector::new(), bytes)
+        } else {
+            // The version vector is at the end, its length is stored in the last 4 bytes
+            let (version, version_length) = VersionVector::deserialize(bytes)?;
+            (version, &bytes[..bytes.len()-version_length])
         };
+        if bytes.len() < 40 {
+            return Err(invalid_index("entry too short"));
+        }
+
+        // Last 40 bytes are hash and timestamp, everything up until that point is the path
+        let path = &bytes[0..bytes.len()-40];
+        let path = std::str::from_utf8(path).map_err(|_| invalid_index("path is not valid UTF-8"))?;
         let mut path_from_root = PathBuf::new();
         for dir in path.split("/") {
             path_from_root.push(dir);
@@ -114,31 +148,14 @@ impl FileData {
         let ms_since_epoch: [u8; 8] = bytes[bytes.len()-8..bytes.len()].try_into().unwrap();
         let ms_since_epoch = u64::from_be_bytes(ms_since_epoch);
         let ms_since_epoch = Duration::from_nanos(ms_since_epoch);
-        let timestamp = UNIX_EPOCH.checked_add(ms_since_epoch).unwrap();
+        let timestamp = UNIX_EPOCH.checked_add(ms_since_epoch).ok_or_else(|| invalid_index("timestamp out of range"))?;
 
-        let file_data = FileData {
+        Ok(FileData {
             hash,
             path_from_root,
             timestamp,
-        };
-        file_data
-    }
-
-    fn display_hash(&self) -> String {
-        let bytes = &self.hash;
-        let mut hex_string = String::new();
-
-        let hex_values = ['0', '1', '2', '3', '4', '5', '6', '7', '8', '9', 'a', 'b', 'c', 'd', 'e', 'f'];
-
-        let left_mask: u8 = 240;
-        let right_mask: u8 = 15;
-        for byte in bytes {
-            let mut left_index = byte&left_mask;
-            left_index >>= 4;
-            hex_string.push(hex_values[usize::from(left_index)]);
-            hex_string.push(hex_values[usize::from(byte&right_mask)]);
-        }
-        hex_string
+            version,
+        })
     }
 }
 
@@ -147,6 +164,13 @@ impl PartialEq for FileData {
         self.hash.eq(&other.hash) 
             && self.path_from_root.eq(&other.path_from_root)
             && self.timestamp.eq(&other.timestamp)
+            && self.version.eq(&other.version)
+    }
+}
+
+impl Default for FileData {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -155,7 +179,8 @@ impl Clone for FileData {
         let mut copy = FileData::new();
         copy.hash.copy_from_slice(&self.hash);
         copy.path_from_root = self.path_from_root.clone();
-        copy.timestamp = self.timestamp.clone();
+        copy.timestamp = self.timestamp;
+        copy.version = self.version.clone();
         copy
     }
 }
@@ -175,6 +200,9 @@ impl Hash for SHA256Hash {
 #[derive(Debug)]
 pub struct Index {
     file_data: HashMap<SHA256Hash, FileData>, 
+    /// Last version of every deleted file, so a file created again at the same path continues
+    /// its history. Stored next to the index in `.rdovetail/tombstones`.
+    tombstones: HashMap<SHA256Hash, VersionVector>,
     path_to_dir: PathBuf,
 }
 
@@ -182,6 +210,7 @@ impl Index {
     pub fn new(path_to_dir: PathBuf) -> Self {
         Index {
             file_data: HashMap::new(),
+            tombstones: HashMap::new(),
             path_to_dir,
         }
     }
@@ -203,7 +232,17 @@ impl Index {
     }
 
     pub fn from_file(path: &Path) -> io::Result<Self> {
-        Self::deserialize(&path)
+        Self::deserialize(path)
+    }
+
+    /// Paths of all tracked files, relative to the directory.
+    pub fn paths(&self) -> Vec<PathBuf> {
+        self.file_data.values().map(|file_data| file_data.get_path_from_root()).collect()
+    }
+
+    /// Number of tracked files, without collecting their paths.
+    pub fn len(&self) -> usize {
+        self.file_data.len()
     }
 
     pub fn get_file_data(&self, key: &[u8; 32]) -> Option<&FileData> {
@@ -217,6 +256,7 @@ impl Index {
         let key = SHA256Hash {
             value: relative_path_hash,
         };
+        self.tombstones.remove(&key);
         let res = self.file_data.insert(key, file_data);
         match res {
             Some(_) => Err(EntryConflict{}),
@@ -228,24 +268,60 @@ impl Index {
         let key = SHA256Hash {
             value: relative_path_hash,
         };
-        let return_value = self.file_data.remove(&key);
-        return_value
+        self.file_data.remove(&key)
     }
 
-    pub fn edit_file_data(&mut self) {
+    /// Inserts the file data, replacing and returning any previous entry for the same path.
+    pub fn edit_file_data(&mut self, relative_path_hash: [u8; 32], file_data: FileData) -> Option<FileData> {
+        let key = SHA256Hash {
+            value: relative_path_hash,
+        };
+        self.tombstones.remove(&key);
+        self.file_data.insert(key, file_data)
     }
 
-    pub fn write_to_file(&self) -> Result<(), io::Error>{
-        let content = Self::serialize(&self);
-        let mut file = fs::File::create(&self.path_to_dir.join(".rdovetail").join("index"))?;
+    /// Remembers the version a file was deleted at.
+    pub fn add_tombstone(&mut self, relative_path_hash: [u8; 32], version: VersionVector) {
+        let key = SHA256Hash {
+            value: relative_path_hash,
+        };
+        self.tombstones.insert(key, version);
+    }
+
+    /// Version of the file at the path, or the version it was deleted at.
+    pub fn get_version(&self, relative_path_hash: &[u8; 32]) -> Option<&VersionVector> {
+        match self.get_file_data(relative_path_hash) {
+            Some(file_data) => Some(file_data.get_version()),
+            None => self.tombstones.get(&SHA256Hash { value: *relative_path_hash }),
+        }
+    }
 
+    pub fn write_to_file(&self) -> Result<(), io::Error>{
+        let content = Self::serialize(self);
+        let dovetail_dir = self.path_to_dir.join(".rdovetail");
+        // Replaced in one step, so a process stopped while writing leaves the old index behind
+        let temp_path = dovetail_dir.join("index.tmp");
+        let mut file = fs::File::create(&temp_path)?;
         file.write_all(&content)?;
+        fs::rename(&temp_path, dovetail_dir.join("index"))?;
+
+        let tombstones: Vec<([u8; 32], &VersionVector)> = self.tombstones
+            .iter()
+            .map(|(key, version)| (key.value, version))
+            .collect();
+        let tombstones = bincode::serialize(&tombstones)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        let temp_path = dovetail_dir.join("tombstones.tmp");
+        fs::write(&temp_path, tombstones)?;
+        fs::rename(&temp_path, dovetail_dir.join("tombstones"))?;
 
         Ok(())
     }
 
     fn serialize(&self) -> Vec<u8> {
         let mut bytes: Vec<u8> = Vec::new();
+        bytes.extend_from_slice(INDEX_MAGIC);
+        bytes.extend_from_slice(&INDEX_FORMAT.to_be_bytes());
 
         for (hash, file_data) in self.file_data.iter() {
             for byte in hash.value {
@@ -268,33 +344,54 @@ impl Index {
 
         let bytes = fs::read(path)?;
 
-        let mut slice_start = 0;
+        // Indexes without the header are read as they are and written in the current format
+        // on the next update
+        let (format, mut slice_start) = match bytes.strip_prefix(INDEX_MAGIC) {
+            Some(rest) => {
+                let format = rest.get(..4).ok_or_else(|| invalid_index("truncated header"))?;
+                (u32::from_be_bytes(format.try_into().unwrap()), INDEX_MAGIC.len() + 4)
+            },
+            None => (LEGACY_INDEX_FORMAT, 0),
+        };
+        if format > INDEX_FORMAT {
+            return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("The index has format {}, this version of rdovetail reads up to {}", format, INDEX_FORMAT)
+            ));
+        }
 
         while slice_start < bytes.len() {
             // Read key hash
             let mut hash: [u8; 32] = [0; 32];
-            hash.copy_from_slice(&bytes[slice_start..slice_start+32]);
+            hash.copy_from_slice(bytes.get(slice_start..slice_start+32).ok_or_else(|| invalid_index("truncated key"))?);
             slice_start += 32;
 
             // Read file data and deserialize FileData struct
-            let mut bytes_to_read: [u8; 4] = [0; 4];
-            bytes_to_read.copy_from_slice(&bytes[slice_start..slice_start+4]);
-            let bytes_to_read: usize = u32::from_be_bytes(bytes_to_read)
-                .try_into()
-                .map_err(|_| io::Error::new(
-                        io::ErrorKind::InvalidData, 
-                        "Failed to convert u32 to usize."
-                        ))?;
+            let bytes_to_read = bytes.get(slice_start..slice_start+4).ok_or_else(|| invalid_index("truncated length"))?;
+            let bytes_to_read = u32::from_be_bytes(bytes_to_read.try_into().unwrap()) as usize;
             slice_start += 4;
-            let file_data = FileData::deserialize(&bytes[slice_start..slice_start+bytes_to_read]);
+            let entry = bytes.get(slice_start..slice_start+bytes_to_read).ok_or_else(|| invalid_index("truncated entry"))?;
+            let file_data = FileData::deserialize(entry, format)?;
 
             let _ = index.add_file_data(hash, file_data);
             slice_start += bytes_to_read;
         }
+
+        let tombstones = match fs::read(path.with_file_name("tombstones")) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(index),
+            Err(err) => return Err(err),
+        };
+        let tombstones: Vec<([u8; 32], VersionVector)> = bincode::deserialize(&tombstones)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        for (hash, version) in tombstones {
+            index.add_tombstone(hash, version);
+        }
         Ok(index)
     } 
 }
 
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum ChangeType {
     Create {
         file_hash: [u8; 32],
@@ -308,63 +405,222 @@ pub enum ChangeType {
     },
 }
 
+/// Identifies the device a change was first made on. Derived from the device's public key.
+pub type DeviceId = u64;
+
+/// Globally unique identifier of a change, used to recognize changes that reach a device
+/// through more than one peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct ChangeId {
+    pub origin: DeviceId,
+    pub sequence: u64,
+}
+
+/// How two versions of a file relate to each other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VersionOrdering {
+    Equal,
+    /// Every edit in this version is also in the other one.
+    Before,
+    /// This version contains every edit of the other one, and more.
+    After,
+    /// Both versions contain edits the other is missing.
+    Concurrent,
+}
+
+/// Number of edits every device has made to a file. Unlike timestamps, comparing two vectors
+/// tells whether one version was derived from the other, regardless of the devices' clocks.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VersionVector {
+    counters: BTreeMap<DeviceId, u64>,
+}
+
+impl VersionVector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, device: DeviceId) -> u64 {
+        self.counters.get(&device).copied().unwrap_or(0)
+    }
+
+    /// Records an edit made on the device.
+    pub fn increment(&mut self, device: DeviceId) {
+        *self.counters.entry(device).or_insert(0) += 1;
+    }
+
+    /// Takes the highest counter of every device, the result includes the edits of both.
+    pub fn merge(&mut self, other: &VersionVector) {
+        for (device, counter) in &other.counters {
+            let entry = self.counters.entry(*device).or_insert(0);
+            *entry = (*entry).max(*counter);
+        }
+    }
+
+    pub fn compare(&self, other: &VersionVector) -> VersionOrdering {
+        let mut ahead = false;
+        let mut behind = false;
+        for device in self.counters.keys().chain(other.counters.keys()) {
+            let (own, theirs) = (self.get(*device), other.get(*device));
+            ahead |= own > theirs;
+            behind |= own < theirs;
+        }
+        match (ahead, behind) {
+            (false, false) => VersionOrdering::Equal,
+            (false, true) => VersionOrdering::Before,
+            (true, false) => VersionOrdering::After,
+            (true, true) => VersionOrdering::Concurrent,
+        }
+    }
+
+    /// Entries of device id and counter, followed by the number of entries.
+    fn serialize(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::new();
+        for (device, counter) in &self.counters {
+            bytes.extend_from_slice(&device.to_be_bytes());
+            bytes.extend_from_slice(&counter.to_be_bytes());
+        }
+        let entries: u32 = self.counters.len().try_into().unwrap_or_else(|_| {
+            panic!("Conversion from usize to u32 failed.");
+        });
+        bytes.extend_from_slice(&entries.to_be_bytes());
+        bytes
+    }
+
+    /// Reads the vector from the end of the bytes, returns it along with its length in bytes.
+    fn deserialize(bytes: &[u8]) -> io::Result<(Self, usize)> {
+        let entries = bytes.len().checked_sub(4)
+            .map(|start| u32::from_be_bytes(bytes[start..].try_into().unwrap()) as usize)
+            .ok_or_else(|| invalid_index("truncated version"))?;
+        let length = entries.checked_mul(16)
+            .and_then(|length| length.checked_add(4))
+            .filter(|length| *length <= bytes.len())
+            .ok_or_else(|| invalid_index("truncated version"))?;
+        let start = bytes.len() - length;
+
+        let mut version = VersionVector::new();
+        for entry in bytes[start..bytes.len()-4].chunks(16) {
+            let device = u64::from_be_bytes(entry[..8].try_into().unwrap());
+            let counter = u64::from_be_bytes(entry[8..].try_into().unwrap());
+            version.counters.insert(device, counter);
+        }
+        Ok((version, length))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Change {
+    pub id: ChangeId,
     pub change_type: ChangeType,
     pub new_state: [u8; 32],
     pub timestamp: u64,
     pub file_path: PathBuf,
+    /// Version of the file after the change.
+    pub version: VersionVector,
+    /// Hash of the file content in the object store, `None` if the change removed the file.
+    pub content_hash: Option<[u8; 32]>,
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use sha2::{Digest, Sha256};
+    use std::env;
     use std::path::Path;
-    use crate::common::util::create_file_data;
+    use crate::common::util::{create_file_data, hash_path};
 
     #[test]
     fn copy_and_clone_works_for_file_data() {
         let mut original = FileData::new();
         original.hash = {
-            let mut array = [0; 32];
-            for i in 0..32 {
-                array[i] = 1;
-            }
-            array
+            [1; 32]
         };
         original.set_timestamp(UNIX_EPOCH.checked_add(Duration::from_millis(5000)).unwrap());
+        original.version.increment(7);
         let clone = original.clone();
         assert_eq!(original, clone);
     }
 
     #[test]
     fn index_is_serialized() -> Result<(), io::Error> {
-        let mut index = Index::new(PathBuf::from("./test"));
-        let _ = fs::create_dir("./test");
-        let _ = fs::create_dir("./test/.rdovetail");
-        let mut file_handle = fs::File::create("./test/test_data.txt")?;
-        let _ = file_handle.write(b"asdf");
-        let mut hasher = Sha256::new();
-        hasher.update(b".testtest_data.txt");
-        let relative_path_hash: [u8; 32] = hasher.finalize().into();
-        let file_data = match create_file_data(
-            PathBuf::from("."),
-            PathBuf::from("./test/test_data.txt")
-            ) {
+        let dir = test_dir("serialized");
+        let mut index = Index::new(dir.clone());
+        fs::write(dir.join("test_data.txt"), b"asdf")?;
+        let relative_path_hash = hash_path(&Path::new(".").join("test_data.txt"));
+        let file_data = match create_file_data(dir.clone(), dir.join("test_data.txt")) {
             Some(data) => data,
-            None => return Err(io::Error::new(io::ErrorKind::Other, "Failed to create FileData")),
+            None => return Err(io::Error::other("Failed to create FileData")),
         };
+        let mut file_data = file_data;
+        file_data.version.increment(3);
+        file_data.version.increment(3);
         let file_data_clone = file_data.clone();
         let res = index.add_file_data(relative_path_hash, file_data);
         assert!(res.is_ok());
-        let _ = index.write_to_file()?;
-        let read_index = Index::from_file(Path::new("./test/.rdovetail/index"))?;
+        index.write_to_file()?;
+        let read_index = Index::from_file(&dir.join(".rdovetail").join("index"))?;
         let relative_path_hash = SHA256Hash {
             value: relative_path_hash,
         };
         let entry = read_index.file_data.get(&relative_path_hash);
         assert!(entry.is_some(), "Entry in read index does not exist.");
         assert_eq!(entry.unwrap(), &file_data_clone, "Read filedata does not match original.");
+        fs::remove_dir_all(&dir)
+    }
+
+    #[test]
+    fn tracked_index_is_read() -> Result<(), io::Error> {
+        let index = Index::from_file(Path::new("./test/.rdovetail/index"))?;
+        assert_eq!(index.paths(), vec![PathBuf::from("./test/test_data.txt")]);
         Ok(())
     }
+
+    #[test]
+    fn legacy_indexes_are_migrated_and_corrupt_ones_rejected() -> Result<(), io::Error> {
+        let dir = test_dir("legacy");
+        let path = dir.join(".rdovetail").join("index");
+        // An entry without a version vector and no header, as written before versions existed
+        let entry = [b"./notes.txt".as_slice(), &[7; 32], &5_000_000u64.to_be_bytes()].concat();
+        let legacy = [[1; 32].as_slice(), &(entry.len() as u32).to_be_bytes(), &entry].concat();
+        fs::write(&path, &legacy)?;
+
+        let index = Index::from_file(&path)?;
+        let file_data = index.get_file_data(&[1; 32]).unwrap();
+        assert_eq!(file_data.get_path_from_root(), PathBuf::from("./notes.txt"));
+        assert_eq!(file_data.get_version(), &VersionVector::new());
+        index.write_to_file()?;
+        assert!(fs::read(&path)?.starts_with(INDEX_MAGIC));
+        assert_eq!(Index::from_file(&path)?.get_file_data(&[1; 32]), Some(file_data));
+
+        fs::write(&path, &legacy[..legacy.len() - 3])?;
+        assert_eq!(Index::from_file(&path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        fs::write(&path, [INDEX_MAGIC.as_slice(), &INDEX_FORMAT.to_be_bytes(), &legacy].concat())?;
+        assert_eq!(Index::from_file(&path).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        fs::remove_dir_all(&dir)
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("rdovetail-index-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join(".rdovetail")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn version_vectors_are_compared() {
+        let mut base = VersionVector::new();
+        base.increment(1);
+
+        let mut ours = base.clone();
+        ours.increment(1);
+        let mut theirs = base.clone();
+        theirs.increment(2);
+
+        assert_eq!(base.compare(&base.clone()), VersionOrdering::Equal);
+        assert_eq!(base.compare(&ours), VersionOrdering::Before);
+        assert_eq!(ours.compare(&base), VersionOrdering::After);
+        assert_eq!(ours.compare(&theirs), VersionOrdering::Concurrent);
+
+        ours.merge(&theirs);
+        assert_eq!(ours.compare(&theirs), VersionOrdering::After);
+    }
 }
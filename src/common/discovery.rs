@@ -0,0 +1,215 @@
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use serde::{Serialize, Deserialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use log::warn;
+
+use super::data::DeviceId;
+use super::util::sleep_unless_stopped;
+
+/// Administratively scoped multicast group, only routed inside the local network.
+pub const DEFAULT_GROUP: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 255, 77, 77), 50011);
+
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5);
+const MAGIC: [u8; 4] = *b"rdvt";
+const MAX_ANNOUNCEMENT: usize = 1024;
+/// How often the listener checks whether discovery was stopped.
+const STOP_CHECK: Duration = Duration::from_millis(200);
+
+/// Broadcast periodically by every node in discovery mode.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Announcement {
+    magic: [u8; 4],
+    pub device: DeviceId,
+    pub public_key: Vec<u8>,
+    /// Port the node accepts connections on, the address is taken from the datagram.
+    pub port: u16,
+    /// Ids of the folders the node syncs.
+    pub folders: Vec<String>,
+}
+
+impl Announcement {
+    pub fn new(device: DeviceId, public_key: Vec<u8>, port: u16, folders: Vec<String>) -> Self {
+        Announcement {
+            magic: MAGIC,
+            device,
+            public_key,
+            port,
+            folders,
+        }
+    }
+
+    /// Whether both nodes sync at least one folder with the same id.
+    pub fn shares_folder(&self, other: &Announcement) -> bool {
+        self.folders.iter().any(|folder| other.folders.contains(folder))
+    }
+
+    pub fn encode(&self) -> io::Result<Vec<u8>> {
+        bincode::serialize(self).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    /// Returns `None` for datagrams that are not announcements from rdovetail.
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        let announcement: Announcement = bincode::deserialize(bytes).ok()?;
+        if announcement.magic != MAGIC {
+            return None;
+        }
+        Some(announcement)
+    }
+}
+
+/// Where and how discovery traffic is sent.
+#[derive(Debug, Clone, Copy)]
+pub struct DiscoveryConfig {
+    pub group: SocketAddrV4,
+    /// Local interface to send and receive multicast on, unspecified lets the OS choose.
+    pub interface: Ipv4Addr,
+    pub interval: Duration,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        DiscoveryConfig {
+            group: DEFAULT_GROUP,
+            interface: Ipv4Addr::UNSPECIFIED,
+            interval: ANNOUNCE_INTERVAL,
+        }
+    }
+}
+
+/// Multicast socket shared by the announcer and the listener. Several nodes on one machine can
+/// bind the same group port, which makes discovery testable on loopback.
+fn open_socket(config: &DiscoveryConfig) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, config.group.port()).into())?;
+    socket.join_multicast_v4(config.group.ip(), &config.interface)?;
+    socket.set_multicast_if_v4(&config.interface)?;
+    socket.set_multicast_loop_v4(true)?;
+    Ok(socket.into())
+}
+
+/// Announces this node every few seconds, and calls `on_peer` with the address of every other
+/// node syncing one of the same folders. Filtering for trusted devices is left to the caller.
+/// Both threads end soon after the flag is set, their handles are returned to wait for that.
+pub fn start<F>(
+    config: DiscoveryConfig,
+    announcement: Announcement,
+    stop: Arc<AtomicBool>,
+    mut on_peer: F,
+) -> io::Result<Vec<JoinHandle<()>>>
+where
+    F: FnMut(Announcement, SocketAddr) + Send + 'static,
+{
+    let socket = open_socket(&config)?;
+    socket.set_read_timeout(Some(STOP_CHECK))?;
+    let announcer = socket.try_clone()?;
+    let encoded = announcement.encode()?;
+
+    let announcer_stop = Arc::clone(&stop);
+    let announcing = thread::spawn(move || {
+        while !announcer_stop.load(Ordering::SeqCst) {
+            if let Err(err) = announcer.send_to(&encoded, config.group) {
+                warn!("Failed to send discovery announcement: {}", err);
+            }
+            sleep_unless_stopped(config.interval, &announcer_stop);
+        }
+    });
+
+    let listening = thread::spawn(move || {
+        let mut buffer = [0u8; MAX_ANNOUNCEMENT];
+        while !stop.load(Ordering::SeqCst) {
+            let (length, sender) = match socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(err) if matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => continue,
+                Err(err) => {
+                    warn!("Discovery stopped: {}", err);
+                    break;
+                },
+            };
+            let remote = match Announcement::decode(&buffer[..length]) {
+                Some(remote) => remote,
+                None => continue,
+            };
+            if remote.device == announcement.device || !remote.shares_folder(&announcement) {
+                continue;
+            }
+            let address = SocketAddr::new(sender.ip(), remote.port);
+            on_peer(remote, address);
+        }
+    });
+    Ok(vec![announcing, listening])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn announcements_round_trip() {
+        let announcement = Announcement::new(7, vec![1; 32], 50010, vec!["docs".to_string()]);
+        let decoded = Announcement::decode(&announcement.encode().unwrap());
+        assert_eq!(decoded, Some(announcement));
+    }
+
+    #[test]
+    fn foreign_datagrams_are_ignored() {
+        assert_eq!(Announcement::decode(b"M-SEARCH * HTTP/1.1"), None);
+        let mut foreign = Announcement::new(7, vec![1; 32], 50010, vec!["docs".to_string()]);
+        foreign.magic = *b"nope";
+        assert_eq!(Announcement::decode(&foreign.encode().unwrap()), None);
+    }
+
+    #[test]
+    fn nodes_match_on_any_shared_folder() {
+        let local = Announcement::new(7, vec![1; 32], 50010, vec!["docs".to_string(), "assets".to_string()]);
+        let remote = Announcement::new(8, vec![2; 32], 50010, vec!["assets".to_string()]);
+        let other = Announcement::new(9, vec![3; 32], 50010, vec!["configs".to_string()]);
+        assert!(local.shares_folder(&remote));
+        assert!(!local.shares_folder(&other));
+    }
+
+    #[test]
+    fn nodes_find_each_other_on_loopback() {
+        let config = DiscoveryConfig {
+            group: SocketAddrV4::new(Ipv4Addr::new(239, 255, 77, 78), 40000 + (std::process::id() % 20000) as u16),
+            interface: Ipv4Addr::LOCALHOST,
+            interval: Duration::from_millis(100),
+        };
+        let stop = Arc::new(AtomicBool::new(false));
+        let (tx, rx) = channel();
+        let mut threads = Vec::new();
+        for (device, port) in [(7, 50010), (8, 50020)] {
+            let announcement = Announcement::new(device, vec![device as u8; 32], port, vec!["docs".to_string()]);
+            let tx = tx.clone();
+            threads.extend(start(config, announcement, Arc::clone(&stop), move |remote, address| {
+                let _ = tx.send((device, remote.device, address.port()));
+            }).unwrap());
+        }
+        // An unrelated node is not reported
+        let other = Announcement::new(9, vec![9; 32], 50030, vec!["photos".to_string()]);
+        threads.extend(start(config, other, Arc::clone(&stop), |_, _| panic!("No folder is shared")).unwrap());
+
+        let mut found = Vec::new();
+        while found.len() < 2 {
+            let seen = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+            if !found.contains(&seen) {
+                found.push(seen);
+            }
+        }
+        found.sort();
+        assert_eq!(found, vec![(7, 8, 50020), (8, 7, 50010)]);
+
+        stop.store(true, Ordering::SeqCst);
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+}
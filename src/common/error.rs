@@ -1,4 +1,6 @@
 use std::error::Error;
+use std::io;
+use std::path::PathBuf;
 use core::fmt::Display;
 
 #[derive(Debug)]
@@ -33,3 +35,71 @@ impl  Display for IllegalState {
 }
 
 impl Error for IllegalState {}
+
+/// The folder has no `.rdovetail` directory with an index yet.
+#[derive(Debug)]
+pub struct NotInitialized {
+    pub dir: PathBuf,
+}
+
+impl Display for NotInitialized {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?} is not an rdovetail folder, run the init command first", self.dir)
+    }
+}
+
+impl Error for NotInitialized {}
+
+/// Errors that end a single connection. The server keeps serving other peers when one of these
+/// occurs.
+#[derive(Debug)]
+pub enum ProtocolError {
+    /// The underlying stream failed or was closed.
+    Io(io::Error),
+    /// The peer announced a frame larger than we are willing to buffer.
+    FrameTooLarge {
+        length: u64,
+        max: u64,
+    },
+    /// The frame could not be decoded into a message.
+    Malformed(String),
+    /// The message is valid, but not allowed at this point in the conversation.
+    UnexpectedMessage(String),
+    /// The peer is not on the list of trusted devices.
+    Untrusted(String),
+    /// The peer reported an error and closed the connection.
+    Remote(String),
+}
+
+impl ProtocolError {
+    /// Whether the peer should be told about the error before the connection is closed. There
+    /// is no point in replying to a broken stream or to an error reported by the peer itself.
+    pub fn should_notify_peer(&self) -> bool {
+        !matches!(self, ProtocolError::Io(_) | ProtocolError::Remote(_))
+    }
+}
+
+impl Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProtocolError::Io(err) => write!(f, "connection error: {}", err),
+            ProtocolError::FrameTooLarge { length, max } => {
+                write!(f, "frame of {} bytes exceeds the limit of {} bytes", length, max)
+            },
+            ProtocolError::Malformed(reason) => write!(f, "malformed message: {}", reason),
+            ProtocolError::UnexpectedMessage(message) => write!(f, "unexpected message: {}", message),
+            ProtocolError::Untrusted(fingerprint) => {
+                write!(f, "untrusted peer {}, run the pair command to trust it", fingerprint)
+            },
+            ProtocolError::Remote(message) => write!(f, "peer reported an error: {}", message),
+        }
+    }
+}
+
+impl Error for ProtocolError {}
+
+impl From<io::Error> for ProtocolError {
+    fn from(err: io::Error) -> Self {
+        ProtocolError::Io(err)
+    }
+}
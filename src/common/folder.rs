@@ -0,0 +1,297 @@
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::thread::JoinHandle;
+use log::{error, info};
+
+use super::control;
+use super::daemon::{self, PidFile};
+use super::data::DeviceId;
+use super::error::IllegalState;
+use super::identity::TrustedPeers;
+use super::peers::{self, Peers};
+use super::version_control::{self, Inbound, Settings};
+
+/// Identifies a synchronized folder across devices, the folders with the same id are kept in
+/// sync with each other wherever they are stored.
+pub type FolderId = String;
+
+/// The id the folder was initialized with, stored in `.rdovetail/folder`. Folders initialized
+/// before ids were stored go by the name of their directory.
+pub fn folder_id(root: &Path) -> io::Result<FolderId> {
+    match fs::read_to_string(root.join(".rdovetail").join("folder")) {
+        Ok(id) => Ok(id.trim().to_string()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(root
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default()),
+        Err(err) => Err(err),
+    }
+}
+
+pub fn set_folder_id(root: &Path, id: &str) -> io::Result<()> {
+    fs::write(root.join(".rdovetail").join("folder"), format!("{}\n", id))
+}
+
+/// Checks that the directory can be synchronized and returns its canonical path: it exists, is
+/// not inside another synchronized folder and, for commands that write, rdovetail can keep its
+/// data there.
+pub fn validate_root(root: &Path, writable: bool) -> Result<PathBuf, IllegalState> {
+    // The watcher reports absolute paths, which are made relative by stripping the root
+    let root = root.canonicalize()
+        .map_err(|err| IllegalState::new(format!("Invalid root {:?}: {}", root, err)))?;
+    if !root.is_dir() {
+        return Err(IllegalState::new(format!("Root {:?} is not a directory", root)));
+    }
+    if let Some(outer) = root.ancestors().skip(1).find(|dir| version_control::is_initialized(dir)) {
+        return Err(IllegalState::new(format!("Root {:?} is inside the synchronized folder {:?}", root, outer)));
+    }
+    if !writable {
+        return Ok(root);
+    }
+
+    // Probes inside .rdovetail where it exists, so a running watcher does not see the probe
+    let dovetail_dir = root.join(".rdovetail");
+    let probe_dir = if dovetail_dir.is_dir() { dovetail_dir } else { root.clone() };
+    let probe = probe_dir.join(format!(".rdovetail-probe-{}", process::id()));
+    fs::OpenOptions::new().write(true).create_new(true).open(&probe)
+        .and_then(|_| fs::remove_file(&probe))
+        .map_err(|err| IllegalState::new(format!("Root {:?} is not writable: {}", root, err)))?;
+    Ok(root)
+}
+
+/// A folder served by this process.
+#[derive(Debug, Clone)]
+pub struct Folder {
+    pub id: FolderId,
+    pub root: PathBuf,
+    /// Queue of the VCS managing the folder.
+    pub tx_to_vcs: Sender<Inbound>,
+}
+
+impl Folder {
+    /// Whether the folder is shared with the device holding the key. Every folder has its own
+    /// trusted peers, reloaded on every call so devices paired while running are picked up.
+    pub fn is_shared_with(&self, public_key: &[u8]) -> io::Result<bool> {
+        Ok(TrustedPeers::from_file(&self.root.join(".rdovetail"))?.is_trusted(public_key))
+    }
+}
+
+/// Every folder served by this process, connections to peers are shared by all of them.
+#[derive(Debug, Clone, Default)]
+pub struct Folders {
+    folders: Vec<Folder>,
+    /// Threads of the VCSs and of the dispatchers forwarding what they emit, joined on shutdown.
+    threads: Arc<Mutex<Vec<JoinHandle<()>>>>,
+    /// Set on shutdown, ends the control sockets.
+    stop: Arc<AtomicBool>,
+}
+
+impl Folders {
+    /// Starts a VCS for every folder, forwarding what each one emits to the peers sharing it and
+    /// answering its control socket. The first root holds the identity of this device, which the other roots adopt.
+    pub fn start(roots: &[PathBuf], device: DeviceId, settings: &Settings, peers: &Arc<Peers>) -> Result<Self, Box<dyn Error>> {
+        let mut folders: Vec<Folder> = Vec::new();
+        let mut threads = Vec::new();
+        let stop = Arc::new(AtomicBool::new(false));
+        for root in roots {
+            if let Some(primary) = roots.first().filter(|primary| *primary != root) {
+                adopt_identity(primary, root)?;
+            }
+            let id = folder_id(root)?;
+            if folders.iter().any(|folder| folder.id == id) {
+                return Err(Box::new(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("Two folders have the id {}", id)
+                )));
+            }
+            let (tx_to_vcs, rx_from_vcs, thread) = version_control::start(root, device, settings, peers.metrics.folder(&id))?;
+            threads.push(thread);
+            control::serve(root, tx_to_vcs.clone(), Arc::clone(&stop))?;
+            threads.push(peers::spawn_dispatcher(Arc::clone(peers), id.clone(), rx_from_vcs));
+            info!("Syncing folder {} at {}", id, root.display());
+            folders.push(Folder {
+                id,
+                root: root.clone(),
+                tx_to_vcs,
+            });
+        }
+        Ok(Folders {
+            folders,
+            threads: Arc::new(Mutex::new(threads)),
+            stop,
+        })
+    }
+
+    /// Stops every VCS once it has written out its state, and waits for them and their
+    /// dispatchers. The control sockets stop accepting requests.
+    pub fn shutdown(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+        for folder in &self.folders {
+            let _ = folder.tx_to_vcs.send(Inbound::Shutdown);
+        }
+        let threads: Vec<JoinHandle<()>> = self.threads.lock().unwrap().drain(..).collect();
+        for thread in threads {
+            if thread.join().is_err() {
+                error!("A folder stopped with a panic");
+            }
+        }
+    }
+
+    /// Shuts down and removes the control sockets of the folders.
+    pub fn close(&self) {
+        self.shutdown();
+        for folder in &self.folders {
+            control::remove_socket(&folder.root);
+        }
+    }
+
+    /// Shuts down on SIGINT or SIGTERM and exits, removing the pid files and control sockets of
+    /// the folders.
+    pub fn stop_on_signal(&self) -> io::Result<()> {
+        let folders = self.clone();
+        daemon::on_shutdown(move || {
+            info!("Shutting down");
+            folders.close();
+            for folder in folders.iter() {
+                PidFile::remove(&folder.root);
+            }
+            process::exit(0);
+        })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Folder> {
+        self.folders.iter()
+    }
+
+    /// The folders shared with the device holding the key.
+    pub fn shared_with(&self, public_key: &[u8]) -> io::Result<Vec<&Folder>> {
+        let mut shared = Vec::new();
+        for folder in &self.folders {
+            if folder.is_shared_with(public_key)? {
+                shared.push(folder);
+            }
+        }
+        Ok(shared)
+    }
+
+    /// Connections are accepted from devices that share at least one folder.
+    pub fn is_trusted(&self, public_key: &[u8]) -> io::Result<bool> {
+        Ok(self.peer_name(public_key)?.is_some())
+    }
+
+    /// The name the device was paired under, in the first folder shared with it.
+    pub fn peer_name(&self, public_key: &[u8]) -> io::Result<Option<String>> {
+        for folder in &self.folders {
+            if let Some(peer) = TrustedPeers::from_file(&folder.root.join(".rdovetail"))?.get(public_key) {
+                return Ok(Some(peer.name.clone()));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Gives the folder the device identity stored in the primary folder, one process presents a
+/// single identity to its peers. A folder that was never paired simply replaces its own identity,
+/// its journal keeps the changes made under the old one.
+pub fn adopt_identity(primary: &Path, root: &Path) -> io::Result<()> {
+    let identity = fs::read(primary.join(".rdovetail").join("identity"))?;
+    let path = root.join(".rdovetail").join("identity");
+    match fs::read(&path) {
+        Ok(existing) if existing == identity => return Ok(()),
+        Ok(_) if TrustedPeers::from_file(&root.join(".rdovetail"))?.iter().next().is_some() => {
+            return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "{} is paired under a different device identity than {}, remove {} and pair the folder again",
+                        root.display(), primary.display(), path.display()
+                    )
+            ));
+        },
+        Ok(_) => {},
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {},
+        Err(err) => return Err(err),
+    }
+    fs::copy(primary.join(".rdovetail").join("identity"), &path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::path::PathBuf;
+
+    #[test]
+    fn unpaired_folders_adopt_the_primary_identity() {
+        let dir = test_dir("adopt");
+        let (primary, extra) = (dir.join("primary"), dir.join("extra"));
+        fs::create_dir_all(primary.join(".rdovetail")).unwrap();
+        fs::create_dir_all(extra.join(".rdovetail")).unwrap();
+        fs::write(primary.join(".rdovetail").join("identity"), b"primary").unwrap();
+        fs::write(extra.join(".rdovetail").join("identity"), b"extra").unwrap();
+
+        adopt_identity(&primary, &extra).unwrap();
+        assert_eq!(fs::read(extra.join(".rdovetail").join("identity")).unwrap(), b"primary");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn ids_are_stored_or_taken_from_the_directory() {
+        let dir = test_dir("folder-id").join("photos");
+        fs::create_dir_all(dir.join(".rdovetail")).unwrap();
+        assert_eq!(folder_id(&dir).unwrap(), "photos");
+        set_folder_id(&dir, "pictures").unwrap();
+        assert_eq!(folder_id(&dir).unwrap(), "pictures");
+        fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn invalid_roots_are_rejected() {
+        let dir = test_dir("roots");
+        let outer = dir.join("outer");
+        fs::create_dir_all(outer.join("inner")).unwrap();
+        fs::write(dir.join("file"), b"").unwrap();
+
+        assert_eq!(validate_root(&outer, true).unwrap(), outer.canonicalize().unwrap());
+        let missing = validate_root(&dir.join("missing"), false).unwrap_err().to_string();
+        assert!(missing.starts_with("Invalid root"), "{}", missing);
+        let file = validate_root(&dir.join("file"), false).unwrap_err().to_string();
+        assert!(file.ends_with("is not a directory"), "{}", file);
+
+        version_control::init(&outer, 1).unwrap();
+        let nested = validate_root(&outer.join("inner"), false).unwrap_err().to_string();
+        assert!(nested.contains("is inside the synchronized folder"), "{}", nested);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn read_only_roots_are_only_rejected_for_writing() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = test_dir("read-only");
+        fs::create_dir_all(&dir).unwrap();
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o555)).unwrap();
+        // Root ignores permissions, there is nothing to check then
+        let writable = fs::write(dir.join("probe"), b"").is_ok();
+
+        assert!(validate_root(&dir, false).is_ok());
+        if !writable {
+            let err = validate_root(&dir, true).unwrap_err().to_string();
+            assert!(err.contains("is not writable"), "{}", err);
+        }
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o755)).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("rdovetail-folder-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+}
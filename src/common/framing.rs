@@ -0,0 +1,132 @@
+use std::io::{Read, Write};
+use bincode::Options;
+
+use super::error::ProtocolError;
+use super::message::Message;
+
+/// Frames larger than this are rejected before any memory is allocated for them.
+pub const DEFAULT_MAX_FRAME_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Splits a stream into messages, each prefixed by its length as a big endian u64.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameCodec {
+    max_frame_size: u64,
+}
+
+impl Default for FrameCodec {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_FRAME_SIZE)
+    }
+}
+
+impl FrameCodec {
+    pub fn new(max_frame_size: u64) -> Self {
+        FrameCodec {
+            max_frame_size,
+        }
+    }
+
+    pub fn write_message<W: Write>(&self, writer: &mut W, message: &Message) -> Result<(), ProtocolError> {
+        let encoded = self.options()
+            .serialize(message)
+            .map_err(|err| ProtocolError::Malformed(err.to_string()))?;
+        let message_length = encoded.len() as u64;
+        self.check_length(message_length)?;
+
+        writer.write_all(&message_length.to_be_bytes())?;
+        writer.write_all(&encoded)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn read_message<R: Read>(&self, reader: &mut R) -> Result<Message, ProtocolError> {
+        let mut message_length = [0u8; 8];
+        reader.read_exact(&mut message_length)?;
+        let message_length = u64::from_be_bytes(message_length);
+        self.check_length(message_length)?;
+
+        // The buffer grows with the data that actually arrives, rather than the announced length
+        let mut buffer: Vec<u8> = Vec::new();
+        reader.take(message_length).read_to_end(&mut buffer)?;
+        if (buffer.len() as u64) < message_length {
+            return Err(ProtocolError::Io(std::io::ErrorKind::UnexpectedEof.into()));
+        }
+
+        self.options()
+            .deserialize(&buffer)
+            .map_err(|err| ProtocolError::Malformed(err.to_string()))
+    }
+
+    fn check_length(&self, length: u64) -> Result<(), ProtocolError> {
+        if length > self.max_frame_size {
+            return Err(ProtocolError::FrameTooLarge {
+                length,
+                max: self.max_frame_size,
+            });
+        }
+        Ok(())
+    }
+
+    /// Same encoding as `bincode::serialize`, but collection lengths inside a frame are bounded
+    /// by the frame limit as well.
+    fn options(&self) -> impl Options {
+        bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes()
+            .with_limit(self.max_frame_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn messages_round_trip() {
+        let codec = FrameCodec::default();
+        let mut buffer = Vec::new();
+        codec.write_message(&mut buffer, &Message::FileRequest { relative_path_hash: [7; 32] }).unwrap();
+
+        match codec.read_message(&mut Cursor::new(buffer)).unwrap() {
+            Message::FileRequest { relative_path_hash } => assert_eq!(relative_path_hash, [7; 32]),
+            other => panic!("Unexpected message {:?}", other),
+        }
+    }
+
+    #[test]
+    fn oversized_frames_are_rejected() {
+        let codec = FrameCodec::new(1024);
+        let mut frame = u64::MAX.to_be_bytes().to_vec();
+        frame.extend_from_slice(&[0; 16]);
+
+        match codec.read_message(&mut Cursor::new(frame)) {
+            Err(ProtocolError::FrameTooLarge { length, max }) => {
+                assert_eq!(length, u64::MAX);
+                assert_eq!(max, 1024);
+            },
+            other => panic!("Expected FrameTooLarge, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn garbage_is_reported_as_malformed() {
+        let codec = FrameCodec::default();
+        let mut frame = 4u64.to_be_bytes().to_vec();
+        frame.extend_from_slice(&[0xff; 4]);
+
+        assert!(matches!(
+            codec.read_message(&mut Cursor::new(frame)),
+            Err(ProtocolError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn truncated_frames_are_io_errors() {
+        let codec = FrameCodec::default();
+        let mut frame = 100u64.to_be_bytes().to_vec();
+        frame.extend_from_slice(&[0; 10]);
+
+        assert!(matches!(codec.read_message(&mut Cursor::new(frame)), Err(ProtocolError::Io(_))));
+    }
+}
@@ -0,0 +1,174 @@
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use super::data::{Change, DeviceId};
+use super::objects::ObjectStore;
+
+const NANOS_PER_DAY: u64 = 86_400 * 1_000_000_000;
+
+/// A stored version of a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileVersion {
+    /// Nanoseconds since the epoch.
+    pub timestamp: u64,
+    pub origin: DeviceId,
+    pub content_hash: [u8; 32],
+}
+
+/// Which previous versions of every file are kept in the object store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retention {
+    /// Number of most recent versions kept, including the current one.
+    pub keep_last: usize,
+    /// Days for which the last version of each day is kept.
+    pub keep_daily_days: u64,
+}
+
+/// Versions of every file kept unless configured otherwise.
+pub const DEFAULT_KEEP_VERSIONS: usize = 10;
+/// Days of daily versions kept unless configured otherwise.
+pub const DEFAULT_KEEP_DAYS: u64 = 30;
+
+impl Default for Retention {
+    fn default() -> Self {
+        Retention {
+            keep_last: DEFAULT_KEEP_VERSIONS,
+            keep_daily_days: DEFAULT_KEEP_DAYS,
+        }
+    }
+}
+
+impl Retention {
+    /// Content hashes of the versions to keep, `versions` is sorted oldest first.
+    pub fn retained(&self, versions: &[FileVersion], now: u64) -> HashSet<[u8; 32]> {
+        // The current version is always kept
+        let keep_last = self.keep_last.max(1);
+        let mut retained: HashSet<[u8; 32]> = versions
+            .iter()
+            .rev()
+            .take(keep_last)
+            .map(|version| version.content_hash)
+            .collect();
+
+        let oldest_day = (now / NANOS_PER_DAY).saturating_sub(self.keep_daily_days);
+        let mut days_seen = HashSet::new();
+        for version in versions.iter().rev() {
+            let day = version.timestamp / NANOS_PER_DAY;
+            if day >= oldest_day && days_seen.insert(day) {
+                retained.insert(version.content_hash);
+            }
+        }
+        retained
+    }
+}
+
+/// Brings a path given on the command line into the form used in the journal, e.g. `./a/b.txt`.
+/// Absolute paths are taken relative to the root of the folder.
+pub fn journal_path(root: &Path, path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::from(".");
+    for component in path.strip_prefix(root).unwrap_or(path).components() {
+        if let Component::Normal(name) = component {
+            normalized.push(name);
+        }
+    }
+    normalized
+}
+
+/// Versions of the file that are in the object store, oldest first.
+pub fn file_versions(changes: &[Change], relative_path: &Path, objects: &ObjectStore) -> Vec<FileVersion> {
+    let mut versions: Vec<FileVersion> = changes
+        .iter()
+        .filter(|change| change.file_path == relative_path)
+        .filter_map(|change| Some(FileVersion {
+            timestamp: change.timestamp,
+            origin: change.id.origin,
+            content_hash: change.content_hash?,
+        }))
+        .filter(|version| objects.contains(&version.content_hash))
+        .collect();
+    versions.sort_by_key(|version| version.timestamp);
+    versions
+}
+
+/// Removes the objects no file needs to keep according to the retention rules, returns how
+/// many were removed. Pinned objects, like the content of snapshots, are always kept.
+pub fn prune(changes: &[Change], objects: &ObjectStore, retention: &Retention, pinned: &HashSet<[u8; 32]>, now: u64) -> io::Result<usize> {
+    let stored: HashSet<[u8; 32]> = objects.hashes()?.into_iter().collect();
+
+    // Versions that were never stored on this device do not count towards the retained ones
+    let mut versions_by_path: HashMap<&Path, Vec<FileVersion>> = HashMap::new();
+    for change in changes {
+        if let Some(content_hash) = change.content_hash.filter(|hash| stored.contains(hash)) {
+            versions_by_path.entry(&change.file_path).or_default().push(FileVersion {
+                timestamp: change.timestamp,
+                origin: change.id.origin,
+                content_hash,
+            });
+        }
+    }
+
+    let mut retained = pinned.clone();
+    for versions in versions_by_path.values_mut() {
+        versions.sort_by_key(|version| version.timestamp);
+        retained.extend(retention.retained(versions, now));
+    }
+
+    let mut removed = 0;
+    for hash in stored.difference(&retained) {
+        objects.remove(hash)?;
+        removed += 1;
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::util::{format_utc, parse_utc};
+
+    fn version(day: u64, hour: u64, id: u8) -> FileVersion {
+        FileVersion {
+            timestamp: day * NANOS_PER_DAY + hour * 3_600 * 1_000_000_000,
+            origin: 1,
+            content_hash: [id; 32],
+        }
+    }
+
+    #[test]
+    fn recent_and_daily_versions_are_retained() {
+        let versions = [
+            version(1, 10, 1),
+            version(8, 10, 2),
+            version(8, 12, 3),
+            version(9, 10, 4),
+            version(9, 11, 5),
+            version(9, 12, 6),
+        ];
+        let retention = Retention {
+            keep_last: 2,
+            keep_daily_days: 3,
+        };
+        let retained = retention.retained(&versions, 10 * NANOS_PER_DAY);
+
+        // The last two versions, and the last version of day 8; day 1 is too old
+        let expected: HashSet<[u8; 32]> = [[3; 32], [5; 32], [6; 32]].into_iter().collect();
+        assert_eq!(retained, expected);
+    }
+
+    #[test]
+    fn command_line_paths_match_the_journal() {
+        let root = Path::new("/home/user/folder");
+        assert_eq!(journal_path(root, Path::new("docs/a.txt")), PathBuf::from("./docs/a.txt"));
+        assert_eq!(journal_path(root, Path::new("./docs/a.txt")), PathBuf::from("./docs/a.txt"));
+        assert_eq!(journal_path(root, Path::new("/home/user/folder/docs/a.txt")), PathBuf::from("./docs/a.txt"));
+    }
+
+    #[test]
+    fn restore_times_round_trip() {
+        assert_eq!(format_utc(1_706_745_599), "20240131-235959");
+        assert_eq!(parse_utc("20240131-235959"), Some(1_706_745_599));
+        assert_eq!(parse_utc("20240229-000000").map(format_utc), Some("20240229-000000".to_string()));
+        assert_eq!(parse_utc("2024-01-31"), None);
+    }
+}
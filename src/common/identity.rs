@@ -0,0 +1,208 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use sha2::{Digest, Sha256};
+
+use super::data::DeviceId;
+use super::transport::Keypair;
+use super::util::{from_hex, to_hex};
+
+const KEY_LENGTH: usize = 32;
+
+/// Loads the keypair identifying this device, `None` if the device has no identity yet.
+pub fn load_keypair(dovetail_dir: &Path) -> io::Result<Option<Keypair>> {
+    let bytes = match fs::read(dovetail_dir.join("identity")) {
+        Ok(bytes) => bytes,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    if bytes.len() != 2 * KEY_LENGTH {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Identity file is corrupted."));
+    }
+    Ok(Some(Keypair {
+        private: bytes[..KEY_LENGTH].to_vec(),
+        public: bytes[KEY_LENGTH..].to_vec(),
+    }))
+}
+
+/// Loads the keypair identifying this device, generating and storing a new one on first use.
+pub fn load_or_create_keypair(dovetail_dir: &Path) -> io::Result<Keypair> {
+    let path = dovetail_dir.join("identity");
+    if let Some(keypair) = load_keypair(dovetail_dir)? {
+        return Ok(keypair);
+    }
+
+    fs::create_dir_all(dovetail_dir)?;
+    let keypair = Keypair::generate()?;
+    let mut file = create_private_file(&path)?;
+    file.write_all(&keypair.private)?;
+    file.write_all(&keypair.public)?;
+    Ok(keypair)
+}
+
+#[cfg(unix)]
+fn create_private_file(path: &Path) -> io::Result<fs::File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)
+}
+
+#[cfg(not(unix))]
+fn create_private_file(path: &Path) -> io::Result<fs::File> {
+    fs::OpenOptions::new().write(true).create_new(true).open(path)
+}
+
+/// Short, human readable digest of a public key, e.g. `3f2a-91bc-0d4e-77a1`.
+pub fn fingerprint(public_key: &[u8]) -> String {
+    let digest = Sha256::digest(public_key);
+    digest[..8]
+        .chunks(2)
+        .map(to_hex)
+        .collect::<Vec<String>>()
+        .join("-")
+}
+
+/// Numeric id of the device owning the key, made from the same digest bytes as the fingerprint.
+pub fn device_id(public_key: &[u8]) -> DeviceId {
+    let digest = Sha256::digest(public_key);
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&digest[..8]);
+    DeviceId::from_be_bytes(bytes)
+}
+
+/// Six digit code derived from the handshake hash. Both ends of a connection compute the same
+/// code, unless someone in between performed a separate handshake with each of them.
+pub fn pairing_code(handshake_hash: &[u8]) -> String {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&handshake_hash[..4]);
+    let code = u32::from_be_bytes(bytes) % 1_000_000;
+    format!("{:03} {:03}", code / 1000, code % 1000)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrustedPeer {
+    pub public_key: Vec<u8>,
+    pub name: String,
+}
+
+/// The devices this machine has been paired with, stored in `.rdovetail/peers` as one
+/// `<hex public key> <name>` entry per line.
+#[derive(Debug)]
+pub struct TrustedPeers {
+    peers: Vec<TrustedPeer>,
+    path: PathBuf,
+}
+
+impl TrustedPeers {
+    pub fn from_file(dovetail_dir: &Path) -> io::Result<Self> {
+        let path = dovetail_dir.join("peers");
+        let mut peers = Vec::new();
+
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err),
+        };
+
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, name) = line.split_once(' ').unwrap_or((line, ""));
+            let public_key = match from_hex(key) {
+                Some(key) if key.len() == KEY_LENGTH => key,
+                _ => return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Invalid key in peers file: {}", key)
+                )),
+            };
+            peers.push(TrustedPeer {
+                public_key,
+                name: name.trim().to_string(),
+            });
+        }
+
+        Ok(TrustedPeers {
+            peers,
+            path,
+        })
+    }
+
+    pub fn is_trusted(&self, public_key: &[u8]) -> bool {
+        self.get(public_key).is_some()
+    }
+
+    pub fn get(&self, public_key: &[u8]) -> Option<&TrustedPeer> {
+        self.peers.iter().find(|peer| peer.public_key == public_key)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &TrustedPeer> {
+        self.peers.iter()
+    }
+
+    /// Adds the key to the list, or renames the existing entry if the key is already trusted.
+    pub fn add(&mut self, public_key: Vec<u8>, name: String) {
+        match self.peers.iter_mut().find(|peer| peer.public_key == public_key) {
+            Some(peer) => peer.name = name,
+            None => self.peers.push(TrustedPeer {
+                public_key,
+                name,
+            }),
+        }
+    }
+
+    pub fn write_to_file(&self) -> io::Result<()> {
+        let mut content = String::new();
+        for peer in &self.peers {
+            content.push_str(&to_hex(&peer.public_key));
+            content.push(' ');
+            content.push_str(&peer.name);
+            content.push('\n');
+        }
+        fs::write(&self.path, content)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("rdovetail-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn keypair_is_persisted() -> io::Result<()> {
+        let dir = temp_dir("identity");
+        let created = load_or_create_keypair(&dir)?;
+        let loaded = load_or_create_keypair(&dir)?;
+        assert_eq!(created.private, loaded.private);
+        assert_eq!(created.public, loaded.public);
+        fs::remove_dir_all(&dir)
+    }
+
+    #[test]
+    fn trusted_peers_round_trip() -> io::Result<()> {
+        let dir = temp_dir("peers");
+        fs::create_dir_all(&dir)?;
+        let mut peers = TrustedPeers::from_file(&dir)?;
+        assert!(!peers.is_trusted(&[7; 32]));
+
+        peers.add(vec![7; 32], "laptop".to_string());
+        peers.write_to_file()?;
+
+        let read = TrustedPeers::from_file(&dir)?;
+        assert!(read.is_trusted(&[7; 32]));
+        assert_eq!(read.get(&[7; 32]).unwrap().name, "laptop");
+        fs::remove_dir_all(&dir)
+    }
+
+    #[test]
+    fn fingerprint_and_code_are_short() {
+        assert_eq!(fingerprint(&[0; 32]).len(), 19);
+        assert_eq!(pairing_code(&[0, 0, 0x30, 0x39]), "012 345");
+    }
+}
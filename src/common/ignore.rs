@@ -0,0 +1,151 @@
+use std::fs;
+use std::io;
+use std::path::{Component, Path};
+
+use super::config_file::ConfigFile;
+
+/// File in the root of a folder listing the paths that are not synchronized.
+pub const IGNORE_FILE: &str = ".rdovetailignore";
+
+/// Paths left out of synchronization, read from `.rdovetailignore`. Every line holds a pattern
+/// where `*` matches any characters and `?` a single one, within one path component. Patterns
+/// without a slash match a file or directory name anywhere in the folder, patterns with a slash
+/// match from the root, and a trailing slash only matches directories. Lines starting with `#`
+/// are comments.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IgnoreRules {
+    patterns: Vec<Pattern>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Pattern {
+    components: Vec<String>,
+    /// Matched from the root rather than against any component.
+    anchored: bool,
+    directory_only: bool,
+}
+
+impl IgnoreRules {
+    /// Reads the rules of the folder from its ignore file, and the patterns in the config of the
+    /// folder and of the user.
+    pub fn load(root: &Path) -> io::Result<Self> {
+        let mut content = match fs::read_to_string(root.join(IGNORE_FILE)) {
+            Ok(content) => content,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err),
+        };
+        for pattern in ConfigFile::load_layered(root)?.ignore {
+            content.push('\n');
+            content.push_str(&pattern);
+        }
+        Ok(Self::parse(&content))
+    }
+
+    pub fn parse(content: &str) -> Self {
+        let patterns = content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| {
+                let directory_only = line.ends_with('/');
+                let line = line.trim_end_matches('/');
+                Pattern {
+                    components: line.split('/').filter(|part| !part.is_empty()).map(str::to_string).collect(),
+                    anchored: line.contains('/'),
+                    directory_only,
+                }
+            })
+            .filter(|pattern| !pattern.components.is_empty())
+            .collect();
+        IgnoreRules {
+            patterns,
+        }
+    }
+
+    /// Whether the file at the path relative to the root, e.g. `./build/out.o`, is ignored
+    /// itself or lies in an ignored directory.
+    pub fn is_ignored(&self, relative_path: &Path) -> bool {
+        let components: Vec<String> = relative_path
+            .components()
+            .filter_map(|component| match component {
+                Component::Normal(name) => Some(name.to_string_lossy().to_string()),
+                _ => None,
+            })
+            .collect();
+        self.patterns.iter().any(|pattern| pattern.matches(&components))
+    }
+}
+
+impl Pattern {
+    fn matches(&self, components: &[String]) -> bool {
+        // Every component but the last is a directory
+        let last = components.len().saturating_sub(1);
+        if self.anchored {
+            let length = self.components.len();
+            length <= components.len()
+                && (length <= last || !self.directory_only)
+                && self.components.iter().zip(components).all(|(pattern, name)| glob_match(pattern, name))
+        } else {
+            components
+                .iter()
+                .enumerate()
+                .any(|(i, name)| (i < last || !self.directory_only) && glob_match(&self.components[0], name))
+        }
+    }
+}
+
+/// Matches a name against a pattern with `*` and `?` wildcards.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Where the last star was seen, and the name position it currently stands for
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = star {
+            // Lets the star take one more character
+            p = star_p + 1;
+            n = star_n + 1;
+            star = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_match_anywhere_and_paths_from_the_root() {
+        let rules = IgnoreRules::parse("# build output\n*.tmp\ntarget/\n/docs/draft-?.md\n");
+        assert!(rules.is_ignored(Path::new("./a.tmp")));
+        assert!(rules.is_ignored(Path::new("./src/deep/b.tmp")));
+        assert!(rules.is_ignored(Path::new("./target/debug/app")));
+        assert!(rules.is_ignored(Path::new("./crate/target/out")));
+        assert!(rules.is_ignored(Path::new("./docs/draft-1.md")));
+
+        assert!(!rules.is_ignored(Path::new("./a.tmp.txt")));
+        // Only directories named target are ignored
+        assert!(!rules.is_ignored(Path::new("./src/target")));
+        assert!(!rules.is_ignored(Path::new("./docs/draft-10.md")));
+        assert!(!rules.is_ignored(Path::new("./old/docs/draft-1.md")));
+    }
+
+    #[test]
+    fn stars_match_any_part_of_a_name() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("a*b*c", "aXXbYYc"));
+        assert!(glob_match("*.rs", "main.rs"));
+        assert!(!glob_match("a*b", "aXXc"));
+        assert!(!glob_match("?", ""));
+    }
+}
@@ -0,0 +1,315 @@
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Serialize, Deserialize};
+
+use super::conflict::Conflict;
+use super::data::{Change, ChangeId, DeviceId};
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Entry {
+    Change(Change),
+    Conflict(Conflict),
+}
+
+/// Append-only log of every change applied to the synchronized directory, whether it was made
+/// locally or received from a peer, and of the conflicts detected between them. Stored in
+/// `.rdovetail/journal` as a sequence of bincode encoded entries, each prefixed by its length
+/// as a big endian u32.
+#[derive(Debug)]
+pub struct Journal {
+    path: PathBuf,
+    device: DeviceId,
+    entries: Vec<Change>,
+    conflicts: Vec<Conflict>,
+    /// Position in `entries` of every journaled change.
+    positions: HashMap<ChangeId, usize>,
+    /// Per origin, the number of changes from the start of its sequence without any gaps.
+    contiguous: HashMap<DeviceId, u64>,
+    next_sequence: u64,
+}
+
+impl Journal {
+    pub fn open(dovetail_dir: &Path, device: DeviceId) -> io::Result<Self> {
+        let path = dovetail_dir.join("journal");
+        let mut journal = Journal {
+            path,
+            device,
+            entries: Vec::new(),
+            conflicts: Vec::new(),
+            positions: HashMap::new(),
+            contiguous: HashMap::new(),
+            next_sequence: 0,
+        };
+
+        let (entries, intact_length) = read_entries(&journal.path)?;
+        // Cuts off a torn write, which new entries would otherwise be appended behind
+        if fs::metadata(&journal.path).is_ok_and(|metadata| metadata.len() > intact_length) {
+            OpenOptions::new().write(true).open(&journal.path)?.set_len(intact_length)?;
+        }
+        for entry in entries {
+            match entry {
+                Entry::Change(change) => journal.remember(change),
+                Entry::Conflict(conflict) => journal.conflicts.push(conflict),
+            }
+        }
+        Ok(journal)
+    }
+
+    /// Reads the changes recorded in the journal, without opening it for writing.
+    pub fn changes_in(dovetail_dir: &Path) -> io::Result<Vec<Change>> {
+        Ok(read_entries(&dovetail_dir.join("journal"))?.0
+            .into_iter()
+            .filter_map(|entry| match entry {
+                Entry::Change(change) => Some(change),
+                Entry::Conflict(_) => None,
+            })
+            .collect())
+    }
+
+    /// Reads the conflicts recorded in the journal, without opening it for writing.
+    pub fn conflicts_in(dovetail_dir: &Path) -> io::Result<Vec<Conflict>> {
+        Ok(read_entries(&dovetail_dir.join("journal"))?.0
+            .into_iter()
+            .filter_map(|entry| match entry {
+                Entry::Conflict(conflict) => Some(conflict),
+                Entry::Change(_) => None,
+            })
+            .collect())
+    }
+
+    /// Creates the id for the next change made on this device.
+    pub fn next_id(&mut self) -> ChangeId {
+        let id = ChangeId {
+            origin: self.device,
+            sequence: self.next_sequence,
+        };
+        self.next_sequence += 1;
+        id
+    }
+
+    pub fn contains(&self, id: &ChangeId) -> bool {
+        self.positions.contains_key(id)
+    }
+
+    pub fn get(&self, id: &ChangeId) -> Option<&Change> {
+        self.positions.get(id).map(|position| &self.entries[*position])
+    }
+
+    pub fn entries(&self) -> &[Change] {
+        &self.entries
+    }
+
+    pub fn conflicts(&self) -> &[Conflict] {
+        &self.conflicts
+    }
+
+    /// How far this device has seen the changes of every origin, sent to a peer on connect.
+    /// All changes of an origin with a lower sequence number than its entry are in the journal.
+    pub fn known_sequences(&self) -> Vec<(DeviceId, u64)> {
+        let mut known: Vec<(DeviceId, u64)> = self.contiguous
+            .iter()
+            .map(|(origin, count)| (*origin, *count))
+            .collect();
+        known.sort();
+        known
+    }
+
+    /// Changes the peer has not seen according to its known sequences, in journal order.
+    /// Some of them may have reached the peer another way, which it detects by their ids.
+    pub fn missing_for(&self, known: &[(DeviceId, u64)]) -> Vec<Change> {
+        let known: HashMap<DeviceId, u64> = known.iter().copied().collect();
+        self.entries
+            .iter()
+            .filter(|change| change.id.sequence >= known.get(&change.id.origin).copied().unwrap_or(0))
+            .cloned()
+            .collect()
+    }
+
+    /// Persists the change and marks its id as seen. Changes that are already in the journal
+    /// are ignored, returns whether the change was added.
+    pub fn append(&mut self, change: Change) -> io::Result<bool> {
+        if self.contains(&change.id) {
+            return Ok(false);
+        }
+
+        let entry = Entry::Change(change);
+        self.write_entry(&entry)?;
+        if let Entry::Change(change) = entry {
+            self.remember(change);
+        }
+        Ok(true)
+    }
+
+    pub fn record_conflict(&mut self, conflict: Conflict) -> io::Result<()> {
+        let entry = Entry::Conflict(conflict);
+        self.write_entry(&entry)?;
+        if let Entry::Conflict(conflict) = entry {
+            self.conflicts.push(conflict);
+        }
+        Ok(())
+    }
+
+    /// Waits until every entry written so far has reached the disk.
+    pub fn sync(&self) -> io::Result<()> {
+        match OpenOptions::new().append(true).open(&self.path) {
+            Ok(file) => file.sync_all(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn write_entry(&self, entry: &Entry) -> io::Result<()> {
+        let encoded = bincode::serialize(entry)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        let entry_length: u32 = encoded.len()
+            .try_into()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Journal entry too large."))?;
+
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        let mut bytes = entry_length.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&encoded);
+        file.write_all(&bytes)
+    }
+
+    fn remember(&mut self, change: Change) {
+        if change.id.origin == self.device {
+            self.next_sequence = self.next_sequence.max(change.id.sequence + 1);
+        }
+        self.positions.insert(change.id, self.entries.len());
+
+        let count = self.contiguous.entry(change.id.origin).or_insert(0);
+        while self.positions.contains_key(&ChangeId { origin: change.id.origin, sequence: *count }) {
+            *count += 1;
+        }
+        self.entries.push(change);
+    }
+}
+
+/// The entries of the journal, along with the length of the file up to the end of the last one.
+fn read_entries(path: &Path) -> io::Result<(Vec<Entry>, u64)> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(err) => return Err(err),
+    };
+
+    let mut entries = Vec::new();
+    let mut slice_start = 0;
+    while slice_start + 4 <= bytes.len() {
+        let mut entry_length: [u8; 4] = [0; 4];
+        entry_length.copy_from_slice(&bytes[slice_start..slice_start+4]);
+        let entry_length = u32::from_be_bytes(entry_length) as usize;
+        let entry_start = slice_start + 4;
+
+        // A torn write at the end of the file is dropped, everything before it is intact
+        if entry_start + entry_length > bytes.len() {
+            break;
+        }
+        let entry: Entry = bincode::deserialize(&bytes[entry_start..entry_start+entry_length])
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        slice_start = entry_start + entry_length;
+        entries.push(entry);
+    }
+    Ok((entries, slice_start as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::conflict::Resolution;
+    use crate::common::data::{ChangeType, VersionVector};
+    use std::env;
+
+    fn change(id: ChangeId) -> Change {
+        Change {
+            id,
+            change_type: ChangeType::Delete,
+            new_state: [0; 32],
+            timestamp: 0,
+            file_path: PathBuf::from("./a.txt"),
+            version: VersionVector::new(),
+            content_hash: None,
+        }
+    }
+
+    #[test]
+    fn journal_is_persisted_and_deduplicated() -> io::Result<()> {
+        let dir = env::temp_dir().join(format!("rdovetail-journal-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir)?;
+
+        let mut journal = Journal::open(&dir, 1)?;
+        let local = journal.next_id();
+        let remote = ChangeId { origin: 2, sequence: 7 };
+        assert!(journal.append(change(local))?);
+        assert!(journal.append(change(remote))?);
+        assert!(!journal.append(change(remote))?);
+
+        let conflict = Conflict {
+            file_path: PathBuf::from("./a.txt"),
+            change: remote,
+            resolution: Resolution::KeptLocal,
+            copy: None,
+            timestamp: 0,
+        };
+        journal.record_conflict(conflict.clone())?;
+
+        let mut reopened = Journal::open(&dir, 1)?;
+        assert_eq!(reopened.entries().len(), 2);
+        assert_eq!(reopened.conflicts().len(), 1);
+        assert_eq!(Journal::conflicts_in(&dir)?, vec![conflict]);
+        assert!(reopened.contains(&remote));
+        assert_eq!(reopened.next_id().sequence, local.sequence + 1);
+        fs::remove_dir_all(&dir)
+    }
+
+    #[test]
+    fn torn_tails_are_cut_off_before_appending() -> io::Result<()> {
+        let dir = env::temp_dir().join(format!("rdovetail-torn-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir)?;
+
+        let mut journal = Journal::open(&dir, 1)?;
+        let first = journal.next_id();
+        journal.append(change(first))?;
+        // A write interrupted after the length prefix and part of the entry
+        let mut file = OpenOptions::new().append(true).open(dir.join("journal"))?;
+        file.write_all(&[0, 0, 0, 90, 1, 2, 3])?;
+        drop(file);
+
+        let mut journal = Journal::open(&dir, 1)?;
+        assert_eq!(journal.entries().len(), 1);
+        let second = journal.next_id();
+        journal.append(change(second))?;
+
+        let reopened = Journal::open(&dir, 1)?;
+        let ids: Vec<ChangeId> = reopened.entries().iter().map(|change| change.id).collect();
+        assert_eq!(ids, vec![first, second]);
+        fs::remove_dir_all(&dir)
+    }
+
+    #[test]
+    fn peers_receive_only_what_they_are_missing() -> io::Result<()> {
+        let dir = env::temp_dir().join(format!("rdovetail-resume-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir)?;
+
+        let mut journal = Journal::open(&dir, 1)?;
+        for _ in 0..3 {
+            let id = journal.next_id();
+            journal.append(change(id))?;
+        }
+        // Sequence 1 of device 2 is still missing, so only sequence 0 counts as known
+        journal.append(change(ChangeId { origin: 2, sequence: 0 }))?;
+        journal.append(change(ChangeId { origin: 2, sequence: 2 }))?;
+        assert_eq!(journal.known_sequences(), vec![(1, 3), (2, 1)]);
+
+        let missing = journal.missing_for(&[(1, 2), (2, 1)]);
+        let ids: Vec<ChangeId> = missing.iter().map(|change| change.id).collect();
+        assert_eq!(ids, vec![ChangeId { origin: 1, sequence: 2 }, ChangeId { origin: 2, sequence: 2 }]);
+        fs::remove_dir_all(&dir)
+    }
+}
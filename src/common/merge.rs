@@ -0,0 +1,161 @@
+/// Largest number of cells in the table used to compare two files, merging larger files is left
+/// to the user.
+const MAX_TABLE_SIZE: usize = 1 << 22;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MergeResult {
+    pub content: String,
+    /// Number of regions both sides changed differently, marked in the content.
+    pub conflicts: usize,
+}
+
+/// Whether the content can be merged line by line.
+pub fn is_text(content: &[u8]) -> bool {
+    !content.contains(&0) && std::str::from_utf8(content).is_ok()
+}
+
+/// Whether a merged file still contains the start of a conflict region.
+pub fn has_conflict_markers(content: &str) -> bool {
+    content.lines().any(|line| line.starts_with("<<<<<<< "))
+}
+
+/// Line based three-way merge of two versions derived from a common base. Regions changed on
+/// one side only take that side's lines, regions both sides changed differently are written
+/// between conflict markers labeled with the given names. Returns `None` if the files are too
+/// large to compare.
+pub fn merge(base: &str, local: &str, remote: &str, local_label: &str, remote_label: &str) -> Option<MergeResult> {
+    let base: Vec<&str> = base.split_inclusive('\n').collect();
+    let local: Vec<&str> = local.split_inclusive('\n').collect();
+    let remote: Vec<&str> = remote.split_inclusive('\n').collect();
+    let local_matches = match_lines(&base, &local)?;
+    let remote_matches = match_lines(&base, &remote)?;
+
+    let mut result = MergeResult {
+        content: String::new(),
+        conflicts: 0,
+    };
+    let (mut b, mut l, mut r) = (0, 0, 0);
+    while b < base.len() || l < local.len() || r < remote.len() {
+        if b < base.len() && local_matches[b] == Some(l) && remote_matches[b] == Some(r) {
+            // Unchanged on both sides
+            result.content.push_str(base[b]);
+            b += 1;
+            l += 1;
+            r += 1;
+            continue;
+        }
+
+        // The region up to the next base line both sides still have
+        let mut next = b;
+        while next < base.len() && (local_matches[next].is_none() || remote_matches[next].is_none()) {
+            next += 1;
+        }
+        let (next_local, next_remote) = match (local_matches.get(next), remote_matches.get(next)) {
+            (Some(Some(next_local)), Some(Some(next_remote))) => (*next_local, *next_remote),
+            _ => (local.len(), remote.len()),
+        };
+
+        let base_region = &base[b..next];
+        let local_region = &local[l..next_local];
+        let remote_region = &remote[r..next_remote];
+        if local_region == base_region || local_region == remote_region {
+            result.content.extend(remote_region.iter().copied());
+        } else if remote_region == base_region {
+            result.content.extend(local_region.iter().copied());
+        } else {
+            push_conflict(&mut result, local_region, remote_region, local_label, remote_label);
+        }
+        b = next;
+        l = next_local;
+        r = next_remote;
+    }
+    Some(result)
+}
+
+fn push_conflict(result: &mut MergeResult, local: &[&str], remote: &[&str], local_label: &str, remote_label: &str) {
+    result.conflicts += 1;
+    result.content.push_str(&format!("<<<<<<< {}\n", local_label));
+    push_lines(&mut result.content, local);
+    result.content.push_str("=======\n");
+    push_lines(&mut result.content, remote);
+    result.content.push_str(&format!(">>>>>>> {}\n", remote_label));
+}
+
+/// Appends the lines, making sure the last one is terminated so the next marker starts a line.
+fn push_lines(content: &mut String, lines: &[&str]) {
+    for line in lines {
+        content.push_str(line);
+    }
+    if !content.ends_with('\n') {
+        content.push('\n');
+    }
+}
+
+/// For every line of `base`, the index of the matching line in `other` according to their
+/// longest common subsequence.
+fn match_lines(base: &[&str], other: &[&str]) -> Option<Vec<Option<usize>>> {
+    let width = other.len() + 1;
+    if (base.len() + 1).checked_mul(width)? > MAX_TABLE_SIZE {
+        return None;
+    }
+
+    // Length of the longest common subsequence of the suffixes starting at i and j
+    let mut table = vec![0u32; (base.len() + 1) * width];
+    for i in (0..base.len()).rev() {
+        for j in (0..other.len()).rev() {
+            table[i * width + j] = if base[i] == other[j] {
+                table[(i + 1) * width + j + 1] + 1
+            } else {
+                table[(i + 1) * width + j].max(table[i * width + j + 1])
+            };
+        }
+    }
+
+    let mut matches = vec![None; base.len()];
+    let (mut i, mut j) = (0, 0);
+    while i < base.len() && j < other.len() {
+        if base[i] == other[j] {
+            matches[i] = Some(j);
+            i += 1;
+            j += 1;
+        } else if table[(i + 1) * width + j] >= table[i * width + j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    Some(matches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn edits_to_different_lines_merge_cleanly() {
+        let base = "a\nb\nc\nd\n";
+        let local = "a\nB\nc\nd\n";
+        let remote = "a\nb\nc\nd\ne\n";
+        let merged = merge(base, local, remote, "local", "remote").unwrap();
+        assert_eq!(merged, MergeResult { content: "a\nB\nc\nd\ne\n".to_string(), conflicts: 0 });
+    }
+
+    #[test]
+    fn edits_to_the_same_line_are_marked() {
+        let base = "a\nb\nc\n";
+        let local = "a\nlocal\nc\n";
+        let remote = "a\nremote\nc\n";
+        let merged = merge(base, local, remote, "one", "two").unwrap();
+        assert_eq!(merged.conflicts, 1);
+        assert_eq!(merged.content, "a\n<<<<<<< one\nlocal\n=======\nremote\n>>>>>>> two\nc\n");
+    }
+
+    #[test]
+    fn identical_edits_and_removals_are_taken_once() {
+        let base = "a\nb\nc\nd\n";
+        let local = "a\nx\nc\nd\n";
+        let remote = "a\nx\nc\n";
+        let merged = merge(base, local, remote, "local", "remote").unwrap();
+        assert_eq!(merged, MergeResult { content: "a\nx\nc\n".to_string(), conflicts: 0 });
+    }
+}
@@ -1,19 +1,55 @@
 use std::path::PathBuf;
 use serde::{Serialize, Deserialize};
 
-#[derive(Debug, Serialize, Deserialize)]
+use super::compression::{Codec, Payload};
+use super::data::{Change, ChangeId, DeviceId};
+use super::folder::FolderId;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Message {
-    FileCreated {
+    /// First message on a new connection, lists the codecs the sender can decode.
+    Hello {
+        codecs: Vec<Codec>,
+    },
+    FileRequest {
+        relative_path_hash: [u8; 32],
+    },
+    FileContents {
         path: PathBuf,
+        payload: Payload,
     },
-    FileRemoved {
+    /// Leading part of a file too large for a single frame. The parts follow each other in
+    /// order, and the last one is sent as `FileContents`.
+    FileChunk {
         path: PathBuf,
+        payload: Payload,
     },
-    FileRequest {
+    /// Reply to a file request when the file has been removed or replaced since it was announced.
+    FileUnavailable {
         relative_path_hash: [u8; 32],
     },
+    /// Sent on every new connection, so the peer can replay the changes this device has missed.
+    /// Each entry is an origin device and the number of its changes seen without gaps.
+    SyncState {
+        known: Vec<(DeviceId, u64)>,
+    },
+    /// A change made on some device in the mesh, forwarded until every device has seen it.
     ExternalChange {
-        
-    }
+        change: Change,
+    },
+    /// Confirms that a change received from the peer has been applied, so the peer can remove
+    /// it from its outbox.
+    Ack {
+        id: ChangeId,
+    },
+    /// A message for the VCS of one folder. Connections are shared by all folders, so every
+    /// message but the handshake and errors travels inside one of these.
+    Folder {
+        id: FolderId,
+        message: Box<Message>,
+    },
+    /// Sent right before the connection is closed because of a protocol error.
+    Error {
+        message: String,
+    },
 }
-
@@ -0,0 +1,278 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use log::{info, warn};
+
+use super::conflict::short_device_id;
+use super::data::DeviceId;
+use super::folder::FolderId;
+use super::net;
+
+/// How long a metrics client may take to send its request or read the answer.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+/// The most bytes read of a request, its line and headers together.
+const MAX_REQUEST: u64 = 8 * 1024;
+
+/// Counters and gauges of the whole process, served in the Prometheus text format.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Bytes of all frames sent to peers.
+    pub bytes_sent: AtomicU64,
+    /// Bytes of all frames received from peers.
+    pub bytes_received: AtomicU64,
+    folders: Mutex<Vec<(FolderId, Arc<FolderMetrics>)>>,
+}
+
+/// Kept up to date by the VCS of a folder.
+#[derive(Debug, Default)]
+pub struct FolderMetrics {
+    pub files_indexed: AtomicU64,
+    /// Changes sent to peers that they have not acknowledged yet.
+    pub pending_outbound: AtomicU64,
+    /// Conflicts recorded in the journal.
+    pub conflicts: AtomicU64,
+    pub watcher_errors: AtomicU64,
+    /// Seconds since the epoch when every device last acknowledged a change or had one applied.
+    last_sync: Mutex<BTreeMap<DeviceId, u64>>,
+}
+
+impl FolderMetrics {
+    pub fn record_sync(&self, device: DeviceId) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        self.last_sync.lock().unwrap().insert(device, now);
+    }
+}
+
+impl Metrics {
+    /// The metrics of the folder, added on first use.
+    pub fn folder(&self, id: &str) -> Arc<FolderMetrics> {
+        let mut folders = self.folders.lock().unwrap();
+        if let Some((_, metrics)) = folders.iter().find(|(folder, _)| folder == id) {
+            return Arc::clone(metrics);
+        }
+        let metrics = Arc::new(FolderMetrics::default());
+        folders.push((id.to_string(), Arc::clone(&metrics)));
+        metrics
+    }
+
+    /// Every metric in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let folders = self.folders.lock().unwrap();
+        let mut text = String::new();
+        let mut family = |name: &str, kind: &str, help: &str, samples: Vec<(String, u64)>| {
+            let _ = writeln!(text, "# HELP {} {}", name, help);
+            let _ = writeln!(text, "# TYPE {} {}", name, kind);
+            for (labels, value) in samples {
+                let _ = writeln!(text, "{}{} {}", name, labels, value);
+            }
+        };
+        let per_folder = |value: fn(&FolderMetrics) -> u64| -> Vec<(String, u64)> {
+            folders.iter()
+                .map(|(id, metrics)| (format!("{{folder=\"{}\"}}", escape(id)), value(metrics)))
+                .collect()
+        };
+
+        family("rdovetail_files_indexed", "gauge", "Files tracked in the index.",
+            per_folder(|metrics| metrics.files_indexed.load(Ordering::Relaxed)));
+        family("rdovetail_pending_outbound_changes", "gauge", "Changes sent to peers and not acknowledged yet.",
+            per_folder(|metrics| metrics.pending_outbound.load(Ordering::Relaxed)));
+        family("rdovetail_conflicts", "gauge", "Conflicts recorded in the journal.",
+            per_folder(|metrics| metrics.conflicts.load(Ordering::Relaxed)));
+        family("rdovetail_watcher_errors_total", "counter", "Errors reported by the file system watcher.",
+            per_folder(|metrics| metrics.watcher_errors.load(Ordering::Relaxed)));
+        family("rdovetail_bytes_sent_total", "counter", "Bytes sent to peers.",
+            vec![(String::new(), self.bytes_sent.load(Ordering::Relaxed))]);
+        family("rdovetail_bytes_received_total", "counter", "Bytes received from peers.",
+            vec![(String::new(), self.bytes_received.load(Ordering::Relaxed))]);
+        let last_sync = folders.iter()
+            .flat_map(|(id, metrics)| {
+                metrics.last_sync.lock().unwrap()
+                    .iter()
+                    .map(|(device, time)| (format!("{{folder=\"{}\",peer=\"{}\"}}", escape(id), short_device_id(*device)), *time))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        family("rdovetail_last_sync_timestamp_seconds", "gauge",
+            "When the peer last acknowledged a change or had one applied, in seconds since the epoch.", last_sync);
+        text
+    }
+}
+
+/// Escapes a label value as the text format requires.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Serves the metrics over HTTP at `/metrics` on the address, meant to be local.
+pub fn serve(address: &str, metrics: Arc<Metrics>) -> io::Result<()> {
+    let listeners = net::bind(&[address.to_string()])?;
+    for listener in listeners {
+        info!("Serving metrics on http://{}/metrics", listener.local_addr()?);
+        let metrics = Arc::clone(&metrics);
+        thread::spawn(move || accept(listener, &metrics));
+    }
+    Ok(())
+}
+
+/// Answers every connection on its own thread, so a slow client does not hold up the others.
+fn accept(listener: TcpListener, metrics: &Arc<Metrics>) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let metrics = Arc::clone(metrics);
+                thread::spawn(move || {
+                    if let Err(err) = answer(stream, &metrics) {
+                        warn!("Failed to serve metrics: {}", err);
+                    }
+                });
+            },
+            Err(err) => warn!("Failed to accept a metrics client: {}", err),
+        }
+    }
+}
+
+/// Answers a single request and closes the connection.
+fn answer(stream: TcpStream, metrics: &Metrics) -> io::Result<()> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?.take(MAX_REQUEST));
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Headers are not needed, but read so the client is not reset while sending them
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", "text/plain; version=0.0.4", metrics.render()),
+        (Some("GET"), _) => ("404 Not Found", "text/plain", "Not found, try /metrics\n".to_string()),
+        _ => ("405 Method Not Allowed", "text/plain", "Only GET is supported\n".to_string()),
+    };
+    let mut stream = stream;
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, content_type, body.len(), body
+    )?;
+    stream.flush()
+}
+
+/// Adds the bytes passing through to a counter.
+pub struct Counted<'a, T> {
+    inner: T,
+    bytes: &'a AtomicU64,
+}
+
+impl<'a, T> Counted<'a, T> {
+    pub fn new(inner: T, bytes: &'a AtomicU64) -> Self {
+        Counted {
+            inner,
+            bytes,
+        }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T: Read> Read for Counted<'_, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.bytes.fetch_add(read as u64, Ordering::Relaxed);
+        Ok(read)
+    }
+}
+
+impl<T: Write> Write for Counted<'_, T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.bytes.fetch_add(written as u64, Ordering::Relaxed);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metrics_are_rendered_per_folder() {
+        let metrics = Metrics::default();
+        let docs = metrics.folder("docs");
+        docs.files_indexed.store(12, Ordering::Relaxed);
+        docs.record_sync(0xabcdef1234567890);
+        metrics.folder("photos").conflicts.store(1, Ordering::Relaxed);
+        metrics.bytes_sent.store(2048, Ordering::Relaxed);
+        assert!(Arc::ptr_eq(&docs, &metrics.folder("docs")));
+
+        let text = metrics.render();
+        assert!(text.contains("# TYPE rdovetail_files_indexed gauge\n"));
+        assert!(text.contains("rdovetail_files_indexed{folder=\"docs\"} 12\n"));
+        assert!(text.contains("rdovetail_conflicts{folder=\"photos\"} 1\n"));
+        assert!(text.contains("rdovetail_bytes_sent_total 2048\n"));
+        assert!(text.contains("rdovetail_last_sync_timestamp_seconds{folder=\"docs\",peer=\"abcdef1\"} "));
+    }
+
+    #[test]
+    fn bytes_are_counted_both_ways() {
+        let counter = AtomicU64::new(0);
+        let mut writer = Counted::new(Vec::new(), &counter);
+        writer.write_all(b"hello").unwrap();
+        let mut reader = Counted::new(&b"world!"[..], &counter);
+        let mut read = String::new();
+        reader.read_to_string(&mut read).unwrap();
+        assert_eq!(counter.load(Ordering::Relaxed), 11);
+    }
+
+    #[test]
+    fn silent_client_does_not_block_others() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let metrics = Arc::new(Metrics::default());
+        metrics.bytes_sent.store(7, Ordering::Relaxed);
+        thread::spawn(move || accept(listener, &metrics));
+
+        let _silent = TcpStream::connect(address).unwrap();
+        let mut client = TcpStream::connect(address).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        client.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut answer = String::new();
+        client.read_to_string(&mut answer).unwrap();
+        assert!(answer.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(answer.contains("rdovetail_bytes_sent_total 7\n"));
+    }
+
+    #[test]
+    fn endless_headers_are_cut_off() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let metrics = Arc::new(Metrics::default());
+        thread::spawn(move || accept(listener, &metrics));
+
+        let mut client = TcpStream::connect(address).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        client.write_all(b"GET /metrics HTTP/1.1\r\n").unwrap();
+        let header = format!("X-Padding: {}\r\n", "a".repeat(1000));
+        for _ in 0..=(MAX_REQUEST / 1000) {
+            client.write_all(header.as_bytes()).unwrap();
+        }
+        // The server answers and closes with headers unread, which may reset the connection
+        let mut answer = String::new();
+        match client.read_to_string(&mut answer) {
+            Ok(_) => assert!(answer.starts_with("HTTP/1.1 200 OK\r\n")),
+            Err(err) => assert_eq!(err.kind(), io::ErrorKind::ConnectionReset),
+        }
+    }
+}
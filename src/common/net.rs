@@ -0,0 +1,122 @@
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use socket2::{Domain, Protocol, Socket, Type};
+
+/// Port used when an address leaves it out.
+pub const DEFAULT_PORT: u16 = 50010;
+
+/// Pending connections queued by the OS for every listener.
+const BACKLOG: i32 = 128;
+
+/// Adds the default port to an address without one. Accepts host names, IPv4 addresses and
+/// IPv6 addresses with or without brackets, e.g. `nas.local`, `10.0.0.2:6000`, `::1` or
+/// `[fe80::1]:6000`.
+pub fn with_default_port(address: &str) -> io::Result<String> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid address {}", address));
+    let (host, port) = if let Some(rest) = address.strip_prefix('[') {
+        let (host, rest) = rest.split_once(']').ok_or_else(invalid)?;
+        match rest {
+            "" => (host, None),
+            _ => (host, Some(rest.strip_prefix(':').ok_or_else(invalid)?)),
+        }
+    } else if address.matches(':').count() > 1 {
+        // Only an IPv6 address without brackets has several colons, and it cannot have a port
+        (address, None)
+    } else {
+        match address.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (address, None),
+        }
+    };
+    if host.is_empty() {
+        return Err(invalid());
+    }
+    let port = match port {
+        Some(port) => port.parse::<u16>().map_err(|_| invalid())?,
+        None => DEFAULT_PORT,
+    };
+    Ok(match host.contains(':') {
+        true => format!("[{}]:{}", host, port),
+        false => format!("{}:{}", host, port),
+    })
+}
+
+/// Every address the name resolves to, a literal address resolves to itself.
+pub fn resolve(address: &str) -> io::Result<Vec<SocketAddr>> {
+    let addresses: Vec<SocketAddr> = with_default_port(address)?.to_socket_addrs()?.collect();
+    if addresses.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} did not resolve to any address", address)));
+    }
+    Ok(addresses)
+}
+
+/// Connects to the first address the name resolves to that accepts the connection. The name is
+/// resolved again on every call, so reconnects follow changes in DNS.
+pub fn connect(address: &str) -> io::Result<TcpStream> {
+    let mut last_err = None;
+    for socket_address in resolve(address)? {
+        match TcpStream::connect(socket_address) {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_err = Some(err),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| io::ErrorKind::NotConnected.into()))
+}
+
+/// Listens on every address the given ones resolve to. Without addresses the default port is
+/// opened on all interfaces, over both IPv6 and IPv4 where the system supports it.
+pub fn bind(addresses: &[String]) -> io::Result<Vec<TcpListener>> {
+    if addresses.is_empty() {
+        return bind_any(DEFAULT_PORT).map(|listener| vec![listener]);
+    }
+    let mut listeners = Vec::new();
+    for address in addresses {
+        for socket_address in resolve(address)? {
+            listeners.push(TcpListener::bind(socket_address)?);
+        }
+    }
+    Ok(listeners)
+}
+
+/// A single dual-stack socket accepting IPv4 connections as mapped addresses, or an IPv4 socket
+/// on systems without IPv6.
+fn bind_any(port: u16) -> io::Result<TcpListener> {
+    let dual_stack = || -> io::Result<TcpListener> {
+        let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
+        socket.set_only_v6(false)?;
+        #[cfg(unix)]
+        socket.set_reuse_address(true)?;
+        socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+        socket.listen(BACKLOG)?;
+        Ok(socket.into())
+    };
+    dual_stack().or_else(|_| TcpListener::bind((Ipv4Addr::UNSPECIFIED, port)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ports_are_added_where_missing() {
+        assert_eq!(with_default_port("nas.local").unwrap(), "nas.local:50010");
+        assert_eq!(with_default_port("nas.local:6000").unwrap(), "nas.local:6000");
+        assert_eq!(with_default_port("10.0.0.2").unwrap(), "10.0.0.2:50010");
+        assert_eq!(with_default_port("::1").unwrap(), "[::1]:50010");
+        assert_eq!(with_default_port("[fe80::1]").unwrap(), "[fe80::1]:50010");
+        assert_eq!(with_default_port("[fe80::1]:6000").unwrap(), "[fe80::1]:6000");
+    }
+
+    #[test]
+    fn malformed_addresses_are_rejected() {
+        for address in ["", ":6000", "nas.local:port", "[::1", "[::1]6000", "10.0.0.2:70000"] {
+            assert!(with_default_port(address).is_err(), "{} was accepted", address);
+        }
+    }
+
+    #[test]
+    fn literal_addresses_resolve_to_themselves() {
+        assert_eq!(resolve("127.0.0.1").unwrap(), vec![SocketAddr::from(([127, 0, 0, 1], DEFAULT_PORT))]);
+        assert_eq!(resolve("[::1]:6000").unwrap(), vec![SocketAddr::from((Ipv6Addr::LOCALHOST, 6000))]);
+    }
+}
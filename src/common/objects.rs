@@ -0,0 +1,109 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use sha2::{Digest, Sha256};
+
+use super::util::{from_hex, to_hex};
+
+/// Content addressed store of every version of the synchronized files, kept in
+/// `.rdovetail/objects`. Each object is named after the SHA256 hash of its content, so
+/// identical versions are stored once.
+#[derive(Debug, Clone)]
+pub struct ObjectStore {
+    dir: PathBuf,
+}
+
+impl ObjectStore {
+    pub fn open(dovetail_dir: &Path) -> io::Result<Self> {
+        let dir = dovetail_dir.join("objects");
+        fs::create_dir_all(&dir)?;
+        Ok(ObjectStore {
+            dir,
+        })
+    }
+
+    pub fn hash(content: &[u8]) -> [u8; 32] {
+        Sha256::digest(content).into()
+    }
+
+    /// Stores the content unless it is already present, returns its hash.
+    pub fn put(&self, content: &[u8]) -> io::Result<[u8; 32]> {
+        let hash = Self::hash(content);
+        let path = self.path(&hash);
+        if path.try_exists()? {
+            return Ok(hash);
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // Written under a temporary name, so a crash never leaves a truncated object
+        let temp_path = path.with_extension("tmp");
+        fs::write(&temp_path, content)?;
+        fs::rename(&temp_path, &path)?;
+        Ok(hash)
+    }
+
+    pub fn get(&self, hash: &[u8; 32]) -> io::Result<Vec<u8>> {
+        fs::read(self.path(hash))
+    }
+
+    pub fn contains(&self, hash: &[u8; 32]) -> bool {
+        self.path(hash).exists()
+    }
+
+    pub fn remove(&self, hash: &[u8; 32]) -> io::Result<()> {
+        fs::remove_file(self.path(hash))
+    }
+
+    /// Hashes of all stored objects.
+    pub fn hashes(&self) -> io::Result<Vec<[u8; 32]>> {
+        let mut hashes = Vec::new();
+        for subdir in fs::read_dir(&self.dir)? {
+            let subdir = subdir?;
+            if !subdir.file_type()?.is_dir() {
+                continue;
+            }
+            let prefix = subdir.file_name().to_string_lossy().to_string();
+            for object in fs::read_dir(subdir.path())? {
+                let name = object?.file_name().to_string_lossy().to_string();
+                // Skips temporary files of interrupted writes
+                let hash = from_hex(&format!("{}{}", prefix, name))
+                    .and_then(|hash| <[u8; 32]>::try_from(hash).ok());
+                if let Some(hash) = hash {
+                    hashes.push(hash);
+                }
+            }
+        }
+        Ok(hashes)
+    }
+
+    /// Objects are spread over subdirectories named after the first byte of the hash.
+    fn path(&self, hash: &[u8; 32]) -> PathBuf {
+        let hex = to_hex(hash);
+        self.dir.join(&hex[..2]).join(&hex[2..])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn objects_are_stored_by_content() -> io::Result<()> {
+        let dir = env::temp_dir().join(format!("rdovetail-objects-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let store = ObjectStore::open(&dir)?;
+        let hash = store.put(b"version one")?;
+        assert_eq!(store.put(b"version one")?, hash);
+        assert!(store.contains(&hash));
+        assert!(!store.contains(&ObjectStore::hash(b"version two")));
+        assert_eq!(store.get(&hash)?, b"version one");
+        assert_eq!(store.hashes()?, vec![hash]);
+
+        store.remove(&hash)?;
+        assert!(store.hashes()?.is_empty());
+        fs::remove_dir_all(&dir)
+    }
+}
@@ -0,0 +1,97 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::data::{ChangeId, DeviceId};
+
+/// Changes sent to a peer that it has not acknowledged yet, per device. Stored in
+/// `.rdovetail/outbox` so they are retransmitted after a reconnect, even across restarts.
+#[derive(Debug)]
+pub struct Outbox {
+    path: PathBuf,
+    unacknowledged: HashMap<DeviceId, BTreeSet<ChangeId>>,
+}
+
+impl Outbox {
+    pub fn open(dovetail_dir: &Path) -> io::Result<Self> {
+        let path = dovetail_dir.join("outbox");
+        let unacknowledged = match fs::read(&path) {
+            Ok(bytes) => bincode::deserialize(&bytes)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(err) => return Err(err),
+        };
+        Ok(Outbox {
+            path,
+            unacknowledged,
+        })
+    }
+
+    /// Records that the change was sent to the device.
+    pub fn add(&mut self, device: DeviceId, id: ChangeId) {
+        self.unacknowledged.entry(device).or_default().insert(id);
+    }
+
+    /// Removes the change once the device has applied it, returns whether it was outstanding.
+    pub fn acknowledge(&mut self, device: DeviceId, id: &ChangeId) -> bool {
+        let removed = match self.unacknowledged.get_mut(&device) {
+            Some(ids) => ids.remove(id),
+            None => false,
+        };
+        if self.unacknowledged.get(&device).is_some_and(|ids| ids.is_empty()) {
+            self.unacknowledged.remove(&device);
+        }
+        removed
+    }
+
+    /// Changes the device has not acknowledged, oldest first for every origin.
+    pub fn unacknowledged(&self, device: DeviceId) -> Vec<ChangeId> {
+        match self.unacknowledged.get(&device) {
+            Some(ids) => ids.iter().copied().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Number of changes not acknowledged, counted once for every device.
+    pub fn len(&self) -> usize {
+        self.unacknowledged.values().map(BTreeSet::len).sum()
+    }
+
+    /// Replaces the file as a whole, so a crash leaves either the old or the new outbox.
+    pub fn write_to_file(&self) -> io::Result<()> {
+        let encoded = bincode::serialize(&self.unacknowledged)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        let temp_path = self.path.with_extension("tmp");
+        fs::write(&temp_path, encoded)?;
+        fs::rename(&temp_path, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn unacknowledged_changes_survive_a_restart() -> io::Result<()> {
+        let dir = env::temp_dir().join(format!("rdovetail-outbox-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir)?;
+
+        let first = ChangeId { origin: 1, sequence: 0 };
+        let second = ChangeId { origin: 1, sequence: 1 };
+        let mut outbox = Outbox::open(&dir)?;
+        outbox.add(2, second);
+        outbox.add(2, first);
+        outbox.add(3, first);
+        assert!(outbox.acknowledge(3, &first));
+        assert!(!outbox.acknowledge(3, &first));
+        outbox.write_to_file()?;
+
+        let reopened = Outbox::open(&dir)?;
+        assert_eq!(reopened.unacknowledged(2), vec![first, second]);
+        assert!(reopened.unacknowledged(3).is_empty());
+        fs::remove_dir_all(&dir)
+    }
+}
@@ -0,0 +1,445 @@
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::{Shutdown, TcpStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use log::{debug, info, warn};
+
+use super::compression::{Codec, Payload, TransferStats};
+use super::error::ProtocolError;
+use super::folder::{Folder, FolderId, Folders};
+use super::framing::FrameCodec;
+use super::identity::{device_id, fingerprint};
+use super::message::Message;
+use super::metrics::{Counted, Metrics};
+use super::throttle::Throttle;
+use super::transport::{SecureReader, SecureWriter};
+use super::version_control::{Inbound, Outbound};
+
+/// Files are sent in parts of at most this many bytes, well below the frame limit even when a
+/// part does not compress.
+const CHUNK_SIZE: usize = 16 * 1024 * 1024;
+
+/// Identifies a connection for as long as it is open.
+pub type PeerId = u64;
+
+/// Where a message handed to the VCS came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Origin {
+    /// The file system watcher of this machine.
+    Local,
+    Peer(PeerId),
+}
+
+/// Which connections a message leaving the VCS should be sent to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Recipients {
+    All,
+    AllExcept(PeerId),
+    Only(PeerId),
+}
+
+impl Recipients {
+    /// Everyone but the peer a change came from, who already has it.
+    pub fn excluding(origin: Origin) -> Self {
+        match origin {
+            Origin::Local => Recipients::All,
+            Origin::Peer(peer) => Recipients::AllExcept(peer),
+        }
+    }
+
+    pub fn includes(&self, peer: PeerId) -> bool {
+        match self {
+            Recipients::All => true,
+            Recipients::AllExcept(excluded) => *excluded != peer,
+            Recipients::Only(recipient) => *recipient == peer,
+        }
+    }
+}
+
+/// Outbound queue of a connection, along with the folders shared with the peer.
+#[derive(Debug)]
+struct Queue {
+    sender: Sender<Message>,
+    folders: HashSet<FolderId>,
+    /// Socket of the connection, shut down to end the session.
+    socket: Option<TcpStream>,
+}
+
+/// Outbound queues of all open connections. Every connection has a writer thread draining its
+/// queue, so messages can be handed to any peer without blocking on the network.
+#[derive(Debug, Default)]
+pub struct Peers {
+    next_id: AtomicU64,
+    queues: Mutex<HashMap<PeerId, Queue>>,
+    /// Set once every connection has been closed, connections registered later are closed at once.
+    closed: AtomicBool,
+    /// When a message was last sent to or received from any peer.
+    last_activity: Mutex<Option<Instant>>,
+    /// Limits the file contents sent to all peers together.
+    upload: Arc<Throttle>,
+    /// Limits the file contents received from all peers together.
+    download: Throttle,
+    /// Metrics of the whole process, fed by the connections and the VCS of every folder.
+    pub metrics: Arc<Metrics>,
+}
+
+impl Peers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Limits file transfers to the given rates in bytes per second, `None` for no limit.
+    pub fn with_limits(upload: Option<u64>, download: Option<u64>) -> Self {
+        Peers {
+            upload: Arc::new(Throttle::new(upload)),
+            download: Throttle::new(download),
+            ..Self::default()
+        }
+    }
+
+    /// Adds a connection that receives the messages of the given folders. `close` shuts down
+    /// its socket, if it has one.
+    pub fn register(&self, sender: Sender<Message>, folders: HashSet<FolderId>, socket: Option<TcpStream>) -> PeerId {
+        let peer = self.next_id.fetch_add(1, Ordering::Relaxed);
+        // Checked under the lock, so a connection registered while closing is not missed
+        let mut queues = self.queues.lock().unwrap();
+        if let Some(socket) = socket.as_ref().filter(|_| self.closed.load(Ordering::SeqCst)) {
+            let _ = socket.shutdown(Shutdown::Both);
+        }
+        queues.insert(peer, Queue { sender, folders, socket });
+        drop(queues);
+        self.record_activity();
+        peer
+    }
+
+    pub fn unregister(&self, peer: PeerId) {
+        self.queues.lock().unwrap().remove(&peer);
+    }
+
+    /// Shuts down the socket of every connection, now and from now on. Each session ends once
+    /// its read fails, taking its writer with it.
+    pub fn close(&self) {
+        let queues = self.queues.lock().unwrap();
+        self.closed.store(true, Ordering::SeqCst);
+        for socket in queues.values().filter_map(|queue| queue.socket.as_ref()) {
+            let _ = socket.shutdown(Shutdown::Both);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.queues.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Time since a message was last exchanged with any peer, `None` if there never was one.
+    pub fn idle_for(&self) -> Option<Duration> {
+        self.last_activity.lock().unwrap().map(|instant| instant.elapsed())
+    }
+
+    fn record_activity(&self) {
+        *self.last_activity.lock().unwrap() = Some(Instant::now());
+    }
+
+    /// Queues the message of the folder for every matching peer sharing it. Peers whose writer
+    /// has stopped are dropped.
+    pub fn dispatch(&self, folder: &str, recipients: Recipients, message: &Message) {
+        self.record_activity();
+        let message = Message::Folder {
+            id: folder.to_string(),
+            message: Box::new(message.clone()),
+        };
+        let mut queues = self.queues.lock().unwrap();
+        queues.retain(|peer, queue| {
+            !recipients.includes(*peer)
+                || !queue.folders.contains(folder)
+                || queue.sender.send(message.clone()).is_ok()
+        });
+    }
+}
+
+/// Forwards everything the VCS of the folder emits to the connected peers.
+pub fn spawn_dispatcher(peers: Arc<Peers>, folder: FolderId, rx_from_vcs: Receiver<Outbound>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        for (recipients, message) in rx_from_vcs {
+            peers.dispatch(&folder, recipients, &message);
+        }
+    })
+}
+
+/// Runs an authenticated connection until it fails: the peer gets an outbound queue drained by
+/// a writer thread, and everything it sends is handed to the VCS of the folder it is meant for.
+/// `remote_static` is the authenticated key of the peer, which decides the folders it shares.
+pub fn run_session(
+    frame_codec: FrameCodec,
+    reader: &mut SecureReader,
+    writer: SecureWriter,
+    codec: Codec,
+    remote_static: &[u8],
+    peers: &Peers,
+    folders: &Folders,
+) -> Result<(), ProtocolError> {
+    let shared = folders.shared_with(remote_static)?;
+    if shared.is_empty() {
+        return Err(ProtocolError::Untrusted(fingerprint(remote_static)));
+    }
+    let ids: Vec<&str> = shared.iter().map(|folder| folder.id.as_str()).collect();
+    info!("Sharing {} with {}", ids.join(", "), fingerprint(remote_static));
+
+    let (tx_queue, rx_queue) = channel();
+    let socket = writer.try_clone_socket()?;
+    let peer = peers.register(tx_queue.clone(), shared.iter().map(|folder| folder.id.clone()).collect(), Some(socket));
+    let writer_handle = spawn_writer(writer, frame_codec, codec, Arc::clone(&peers.upload), Arc::clone(&peers.metrics), rx_queue);
+
+    // Starts the exchange of sync states and the retransmission of unacknowledged changes
+    let device = device_id(remote_static);
+    for folder in &shared {
+        let _ = folder.tx_to_vcs.send(Inbound::PeerConnected { peer, device });
+    }
+    let result = serve_peer(&frame_codec, reader, peer, peers, &shared);
+    peers.unregister(peer);
+    for folder in &shared {
+        let _ = folder.tx_to_vcs.send(Inbound::PeerDisconnected { peer });
+    }
+    if let Err(err) = &result {
+        if err.should_notify_peer() {
+            let _ = tx_queue.send(Message::Error { message: err.to_string() });
+        }
+    }
+    // The writer stops once the last sender of its queue is gone
+    drop(tx_queue);
+    let _ = writer_handle.join();
+    result
+}
+
+/// Hands every message from the peer to the VCS of its folder until the connection fails.
+/// Messages for folders not shared with the peer are dropped.
+fn serve_peer(
+    frame_codec: &FrameCodec,
+    reader: &mut SecureReader,
+    peer: PeerId,
+    peers: &Peers,
+    shared: &[&Folder],
+) -> Result<(), ProtocolError> {
+    let mut stats = TransferStats::new();
+    let mut reader = Counted::new(reader, &peers.metrics.bytes_received);
+    // Leading parts of files still being received, by folder and path
+    let mut partial: HashMap<(FolderId, PathBuf), Vec<u8>> = HashMap::new();
+    loop {
+        let (id, message) = match frame_codec.read_message(&mut reader)? {
+            Message::Folder { id, message } => (id, *message),
+            Message::Error { message } => return Err(ProtocolError::Remote(message)),
+            Message::Hello { .. } => {
+                return Err(ProtocolError::UnexpectedMessage("Hello after handshake".to_string()));
+            },
+            other => return Err(ProtocolError::UnexpectedMessage(format!("{:?} outside of a folder", other))),
+        };
+        peers.record_activity();
+        match &message {
+            Message::FileContents { payload, .. } | Message::FileChunk { payload, .. } => {
+                stats.record(payload);
+                debug!("Received stats: {}", stats);
+                // Holding off the next read slows the peer down through flow control
+                peers.download.consume(payload.get_wire_size());
+            },
+            Message::Hello { .. }
+                | Message::Error { .. }
+                | Message::Folder { .. } => {
+                return Err(ProtocolError::UnexpectedMessage(format!("{:?}", message)));
+            },
+            _ => {},
+        }
+
+        let folder = match shared.iter().find(|folder| folder.id == id) {
+            Some(folder) => folder,
+            None => {
+                warn!("Peer sent a message for folder {:?}, which is not shared with it", id);
+                continue;
+            },
+        };
+        let message = match message {
+            Message::FileChunk { path, payload } => {
+                partial.entry((id, path)).or_default().extend(decompress(payload)?);
+                continue;
+            },
+            Message::FileContents { path, payload } => match partial.remove(&(id, path.clone())) {
+                Some(mut content) => {
+                    content.extend(decompress(payload)?);
+                    Message::FileContents { path, payload: Payload::raw(content) }
+                },
+                None => Message::FileContents { path, payload },
+            },
+            message => message,
+        };
+        if folder.tx_to_vcs.send(Inbound::Message(peer, message)).is_err() {
+            return Err(ProtocolError::Io(io::Error::other("version control has stopped")));
+        }
+    }
+}
+
+fn decompress(payload: Payload) -> Result<Vec<u8>, ProtocolError> {
+    payload.decompress().map_err(|err| ProtocolError::Malformed(err.to_string()))
+}
+
+/// Splits a file too large for one frame into `FileChunk`s followed by the `FileContents` with
+/// its last part. Every other message is sent as it is.
+fn into_frames(message: Message, chunk_size: usize) -> Vec<Message> {
+    let (id, path, payload) = match message {
+        Message::Folder { id, message } => match *message {
+            Message::FileContents { path, payload } => (id, path, payload),
+            message => return vec![Message::Folder { id, message: Box::new(message) }],
+        },
+        message => return vec![message],
+    };
+    let mut parts = payload.split(chunk_size);
+    let last = parts.pop().unwrap_or_else(|| Payload::raw(Vec::new()));
+    let mut frames: Vec<Message> = parts.into_iter()
+        .map(|payload| Message::FileChunk { path: path.clone(), payload })
+        .collect();
+    frames.push(Message::FileContents { path, payload: last });
+    frames.into_iter()
+        .map(|message| Message::Folder { id: id.clone(), message: Box::new(message) })
+        .collect()
+}
+
+/// Drains the outbound queue of a connection, compressing file payloads with the negotiated
+/// codec and keeping them within the upload limit. Stops when the queue is closed. When the
+/// connection fails, it is shut down, which ends the session reading from it as well.
+fn spawn_writer(
+    writer: SecureWriter,
+    frame_codec: FrameCodec,
+    codec: Codec,
+    upload: Arc<Throttle>,
+    metrics: Arc<Metrics>,
+    queue: Receiver<Message>,
+) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let mut writer = Counted::new(writer, &metrics.bytes_sent);
+        let mut stats = TransferStats::new();
+        for mut message in queue.iter().flat_map(|message| into_frames(message, CHUNK_SIZE)) {
+            let mut file = None;
+            if let Message::Folder { message, .. } = &mut message {
+                if let Message::FileContents { path, payload } | Message::FileChunk { path, payload } = message.as_mut() {
+                    payload.compress(codec, path);
+                    stats.record(payload);
+                    upload.consume(payload.get_wire_size());
+                    file = Some(path.clone());
+                }
+            }
+            if let Err(err) = frame_codec.write_message(&mut writer, &message) {
+                match file {
+                    Some(path) => warn!("Failed to send {:?}, closing the connection: {}", path, err),
+                    None => warn!("Failed to send message, closing the connection: {}", err),
+                }
+                writer.get_ref().shutdown();
+                break;
+            }
+            if file.is_some() {
+                debug!("Transfer stats: {}", stats);
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn dispatch_skips_the_origin() {
+        let peers = Peers::new();
+        let (tx_a, rx_a) = channel();
+        let (tx_b, rx_b) = channel();
+        let a = peers.register(tx_a, folders(&["docs"]), None);
+        let _b = peers.register(tx_b, folders(&["docs"]), None);
+
+        let message = Message::FileRequest { relative_path_hash: [1; 32] };
+        peers.dispatch("docs", Recipients::excluding(Origin::Peer(a)), &message);
+
+        assert!(rx_a.try_recv().is_err());
+        match rx_b.try_recv() {
+            Ok(Message::Folder { id, message }) => {
+                assert_eq!(id, "docs");
+                assert!(matches!(*message, Message::FileRequest { .. }));
+            },
+            other => panic!("expected a folder message, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn dispatch_only_reaches_peers_sharing_the_folder() {
+        let peers = Peers::new();
+        let (tx_a, rx_a) = channel();
+        let (tx_b, rx_b) = channel();
+        peers.register(tx_a, folders(&["docs"]), None);
+        peers.register(tx_b, folders(&["docs", "assets"]), None);
+
+        peers.dispatch("assets", Recipients::All, &Message::FileRequest { relative_path_hash: [2; 32] });
+
+        assert!(rx_a.try_recv().is_err());
+        assert!(rx_b.try_recv().is_ok());
+    }
+
+    #[test]
+    fn activity_is_tracked() {
+        let peers = Peers::new();
+        assert_eq!(peers.idle_for(), None);
+        let (tx, _rx) = channel();
+        peers.register(tx, folders(&["docs"]), None);
+        assert!(peers.idle_for().is_some_and(|idle| idle < Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn closed_queues_are_dropped() {
+        let peers = Peers::new();
+        let (tx, rx) = channel();
+        peers.register(tx, folders(&["docs"]), None);
+        drop(rx);
+
+        peers.dispatch("docs", Recipients::All, &Message::FileRequest { relative_path_hash: [1; 32] });
+        assert!(peers.is_empty());
+    }
+
+    #[test]
+    fn large_files_are_split_into_chunks() {
+        let content: Vec<u8> = (0..10).collect();
+        let message = Message::Folder {
+            id: "docs".to_string(),
+            message: Box::new(Message::FileContents { path: PathBuf::from("./a.bin"), payload: Payload::raw(content.clone()) }),
+        };
+
+        let mut received = Vec::new();
+        let frames = into_frames(message, 4);
+        assert_eq!(frames.len(), 3);
+        for (index, frame) in frames.into_iter().enumerate() {
+            let Message::Folder { id, message } = frame else { panic!("expected a folder message") };
+            assert_eq!(id, "docs");
+            match (*message, index) {
+                (Message::FileChunk { payload, .. }, 0 | 1) | (Message::FileContents { payload, .. }, 2) => {
+                    received.extend(payload.decompress().unwrap());
+                },
+                (other, _) => panic!("unexpected frame {:?}", other),
+            }
+        }
+        assert_eq!(received, content);
+
+        let small = Message::Folder {
+            id: "docs".to_string(),
+            message: Box::new(Message::FileContents { path: PathBuf::from("./b.bin"), payload: Payload::raw(vec![1, 2]) }),
+        };
+        assert_eq!(into_frames(small, 4).len(), 1);
+        assert_eq!(into_frames(Message::FileRequest { relative_path_hash: [1; 32] }, 4).len(), 1);
+    }
+
+    fn folders(ids: &[&str]) -> HashSet<FolderId> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+}
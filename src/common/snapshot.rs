@@ -0,0 +1,229 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use serde::{Serialize, Deserialize};
+
+use super::ignore::IgnoreRules;
+use super::objects::ObjectStore;
+use super::util::find_relative_path;
+
+/// Name prefix of the snapshots taken on a schedule, only those are removed automatically.
+pub const SCHEDULED_PREFIX: &str = "auto-";
+
+/// The content of every file in the folder at one point in time. The content itself is kept in
+/// the object store, the snapshot only references it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub name: String,
+    /// Nanoseconds since the epoch.
+    pub created: u64,
+    /// Content hash of every file, by path relative to the folder like in the journal.
+    pub files: BTreeMap<PathBuf, [u8; 32]>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Difference {
+    Added(PathBuf),
+    Modified(PathBuf),
+    Removed(PathBuf),
+}
+
+impl Difference {
+    pub fn path(&self) -> &Path {
+        match self {
+            Difference::Added(path) | Difference::Modified(path) | Difference::Removed(path) => path,
+        }
+    }
+}
+
+/// Snapshots kept in `.rdovetail/snapshots`, one bincode encoded file per snapshot named after
+/// it. Snapshots are never changed once written.
+#[derive(Debug, Clone)]
+pub struct SnapshotStore {
+    dir: PathBuf,
+}
+
+impl SnapshotStore {
+    pub fn open(dovetail_dir: &Path) -> io::Result<Self> {
+        let dir = dovetail_dir.join("snapshots");
+        fs::create_dir_all(&dir)?;
+        Ok(SnapshotStore {
+            dir,
+        })
+    }
+
+    /// Writes a new snapshot, fails if one with the same name exists. The snapshot is written to
+    /// a temporary file first, so a process stopped while writing leaves no partial snapshot.
+    pub fn save(&self, snapshot: &Snapshot) -> io::Result<()> {
+        let path = self.path(&snapshot.name)?;
+        let content = bincode::serialize(snapshot)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        // Not a valid name, so never listed as a snapshot
+        let temp_path = self.dir.join(format!(".{}.tmp", snapshot.name));
+        let mut file = fs::File::create(&temp_path)?;
+        file.write_all(&content)?;
+        file.sync_all()?;
+        // Unlike a rename, linking fails if the name is already taken
+        let result = fs::hard_link(&temp_path, path);
+        fs::remove_file(&temp_path)?;
+        result
+    }
+
+    pub fn load(&self, name: &str) -> io::Result<Snapshot> {
+        let content = fs::read(self.path(name)?)?;
+        bincode::deserialize(&content).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    pub fn remove(&self, name: &str) -> io::Result<()> {
+        fs::remove_file(self.path(name)?)
+    }
+
+    /// All snapshots, oldest first.
+    pub fn list(&self) -> io::Result<Vec<Snapshot>> {
+        let mut snapshots = Vec::new();
+        for file in fs::read_dir(&self.dir)? {
+            let name = file?.file_name().to_string_lossy().to_string();
+            if is_valid_name(&name) {
+                snapshots.push(self.load(&name)?);
+            }
+        }
+        snapshots.sort_by_key(|snapshot| snapshot.created);
+        Ok(snapshots)
+    }
+
+    /// Content referenced by any snapshot, which the history pruning has to keep.
+    pub fn hashes(&self) -> io::Result<HashSet<[u8; 32]>> {
+        Ok(self.list()?
+            .into_iter()
+            .flat_map(|snapshot| snapshot.files.into_values())
+            .collect())
+    }
+
+    fn path(&self, name: &str) -> io::Result<PathBuf> {
+        if !is_valid_name(name) {
+            return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Invalid snapshot name {}, use letters, digits, '-', '_' and '.'", name)
+            ));
+        }
+        Ok(self.dir.join(name))
+    }
+}
+
+/// Names are used as file names, so they may not contain separators or start with a dot.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// Content hashes of the files currently in the folder, leaving out the rdovetail metadata and
+/// the ignored files. With an object store the content is also stored, so a snapshot of the
+/// result can be checked out later.
+pub fn scan(root: &Path, objects: Option<&ObjectStore>) -> io::Result<BTreeMap<PathBuf, [u8; 32]>> {
+    let mut files = BTreeMap::new();
+    let ignore = IgnoreRules::load(root)?;
+    scan_dir(root, root, &ignore, objects, &mut files)?;
+    Ok(files)
+}
+
+fn scan_dir(
+    root: &Path,
+    dir: &Path,
+    ignore: &IgnoreRules,
+    objects: Option<&ObjectStore>,
+    files: &mut BTreeMap<PathBuf, [u8; 32]>,
+) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            if dir != root || entry.file_name() != ".rdovetail" {
+                scan_dir(root, &path, ignore, objects, files)?;
+            }
+        } else if file_type.is_file() {
+            let relative_path = find_relative_path(root.iter(), path.iter());
+            if ignore.is_ignored(&relative_path) {
+                continue;
+            }
+            let content = fs::read(&path)?;
+            let hash = match objects {
+                Some(objects) => objects.put(&content)?,
+                None => ObjectStore::hash(&content),
+            };
+            files.insert(relative_path, hash);
+        }
+    }
+    Ok(())
+}
+
+/// What changed going from the `old` to the `new` files, ordered by path.
+pub fn diff(old: &BTreeMap<PathBuf, [u8; 32]>, new: &BTreeMap<PathBuf, [u8; 32]>) -> Vec<Difference> {
+    let mut differences = Vec::new();
+    for (path, hash) in old {
+        match new.get(path) {
+            None => differences.push(Difference::Removed(path.clone())),
+            Some(new_hash) if new_hash != hash => differences.push(Difference::Modified(path.clone())),
+            Some(_) => {},
+        }
+    }
+    for path in new.keys().filter(|path| !old.contains_key(*path)) {
+        differences.push(Difference::Added(path.clone()));
+    }
+    differences.sort_by(|a, b| a.path().cmp(b.path()));
+    differences
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn snapshots_are_immutable_and_pin_their_content() -> io::Result<()> {
+        let root = env::temp_dir().join(format!("rdovetail-snapshot-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("docs"))?;
+        fs::create_dir_all(root.join(".rdovetail"))?;
+        fs::write(root.join("docs/a.txt"), b"a")?;
+        fs::write(root.join("docs/a.tmp"), b"scratch")?;
+        fs::write(root.join(".rdovetailignore"), b"*.tmp\n.rdovetailignore\n")?;
+
+        let objects = ObjectStore::open(&root.join(".rdovetail"))?;
+        let store = SnapshotStore::open(&root.join(".rdovetail"))?;
+        let files = scan(&root, Some(&objects))?;
+        assert_eq!(files.keys().collect::<Vec<_>>(), vec![Path::new("./docs/a.txt")]);
+
+        let snapshot = Snapshot { name: "release-1.0".to_string(), created: 1, files };
+        store.save(&snapshot)?;
+        assert_eq!(store.save(&snapshot).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        assert!(store.load("../index").is_err());
+        // Left behind by a process stopped while saving
+        fs::write(root.join(".rdovetail/snapshots/.partial.tmp"), b"")?;
+        assert_eq!(store.list()?, vec![snapshot]);
+        assert_eq!(store.hashes()?, [ObjectStore::hash(b"a")].into_iter().collect());
+        assert!(objects.contains(&ObjectStore::hash(b"a")));
+        fs::remove_dir_all(&root)
+    }
+
+    #[test]
+    fn differences_are_ordered_by_path() {
+        let old: BTreeMap<PathBuf, [u8; 32]> = [
+            (PathBuf::from("./a"), [1; 32]),
+            (PathBuf::from("./b"), [2; 32]),
+            (PathBuf::from("./c"), [3; 32]),
+        ].into_iter().collect();
+        let new: BTreeMap<PathBuf, [u8; 32]> = [
+            (PathBuf::from("./a"), [1; 32]),
+            (PathBuf::from("./b"), [4; 32]),
+            (PathBuf::from("./ab"), [5; 32]),
+        ].into_iter().collect();
+        assert_eq!(diff(&old, &new), vec![
+            Difference::Added(PathBuf::from("./ab")),
+            Difference::Modified(PathBuf::from("./b")),
+            Difference::Removed(PathBuf::from("./c")),
+        ]);
+    }
+}
@@ -0,0 +1,73 @@
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Limits the rate of transfers shared by all connections. Every transfer reserves the time it
+/// takes at the limit after the transfers reserved before it, and is held back until that time
+/// has passed.
+#[derive(Debug)]
+pub struct Throttle {
+    /// `None` for no limit.
+    bytes_per_second: Option<u64>,
+    /// When the transfers reserved so far are done.
+    next_free: Mutex<Instant>,
+}
+
+impl Throttle {
+    pub fn new(bytes_per_second: Option<u64>) -> Self {
+        Throttle {
+            bytes_per_second: bytes_per_second.filter(|rate| *rate > 0),
+            next_free: Mutex::new(Instant::now()),
+        }
+    }
+
+    pub fn unlimited() -> Self {
+        Self::new(None)
+    }
+
+    /// Blocks until the transfer of the given number of bytes fits within the limit.
+    pub fn consume(&self, bytes: u64) {
+        let wait = self.reserve(bytes, Instant::now());
+        if !wait.is_zero() {
+            thread::sleep(wait);
+        }
+    }
+
+    /// Reserves the time for the transfer, returns how long to hold it back.
+    fn reserve(&self, bytes: u64, now: Instant) -> Duration {
+        let rate = match self.bytes_per_second {
+            Some(rate) => rate,
+            None => return Duration::ZERO,
+        };
+        let mut next_free = self.next_free.lock().unwrap();
+        let start = (*next_free).max(now);
+        *next_free = start + Duration::from_secs_f64(bytes as f64 / rate as f64);
+        *next_free - now
+    }
+}
+
+impl Default for Throttle {
+    fn default() -> Self {
+        Self::unlimited()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transfers_queue_behind_each_other() {
+        let throttle = Throttle::new(Some(1000));
+        let now = Instant::now();
+        assert_eq!(throttle.reserve(100, now), Duration::from_millis(100));
+        assert_eq!(throttle.reserve(100, now), Duration::from_millis(200));
+        assert_eq!(throttle.reserve(100, now + Duration::from_millis(50)), Duration::from_millis(250));
+    }
+
+    #[test]
+    fn no_limit_never_waits() {
+        let throttle = Throttle::unlimited();
+        assert_eq!(throttle.reserve(u64::MAX, Instant::now()), Duration::ZERO);
+    }
+}
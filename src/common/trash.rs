@@ -0,0 +1,152 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use serde::{Serialize, Deserialize};
+
+use super::data::DeviceId;
+use super::util::{hash_path, to_hex};
+
+/// Where and when a trashed file was deleted.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TrashEntry {
+    pub original_path: PathBuf,
+    /// The device the deletion was made on.
+    pub origin: DeviceId,
+    /// Nanoseconds since the epoch.
+    pub deleted_at: u64,
+}
+
+/// Files deleted because of a change from another device, kept in `.rdovetail/trash` until
+/// they expire. Every entry is stored as `<id>` with the content and `<id>.meta` with the
+/// bincode encoded `TrashEntry`.
+#[derive(Debug, Clone)]
+pub struct Trash {
+    dir: PathBuf,
+}
+
+impl Trash {
+    pub fn open(dovetail_dir: &Path) -> io::Result<Self> {
+        let dir = dovetail_dir.join("trash");
+        fs::create_dir_all(&dir)?;
+        Ok(Trash {
+            dir,
+        })
+    }
+
+    /// Moves the file at `root/relative_path` into the trash, returns the id of the entry. The
+    /// file is moved first, so a file that is already gone leaves no entry behind.
+    pub fn put(&self, root: &Path, relative_path: &Path, origin: DeviceId, now: u64) -> io::Result<String> {
+        let id = format!("{:016x}-{}", now, &to_hex(&hash_path(relative_path))[..8]);
+        let entry = TrashEntry {
+            original_path: relative_path.to_path_buf(),
+            origin,
+            deleted_at: now,
+        };
+        let meta = bincode::serialize(&entry)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        fs::rename(root.join(relative_path), self.dir.join(&id))?;
+        if let Err(err) = fs::write(self.dir.join(format!("{}.meta", id)), meta) {
+            let _ = fs::remove_file(self.dir.join(format!("{}.meta", id)));
+            let _ = fs::rename(self.dir.join(&id), root.join(relative_path));
+            return Err(err);
+        }
+        Ok(id)
+    }
+
+    /// All entries, oldest first.
+    pub fn list(&self) -> io::Result<Vec<(String, TrashEntry)>> {
+        let mut entries = Vec::new();
+        for file in fs::read_dir(&self.dir)? {
+            let name = file?.file_name().to_string_lossy().to_string();
+            if let Some(id) = name.strip_suffix(".meta") {
+                entries.push((id.to_string(), self.entry(id)?));
+            }
+        }
+        entries.sort_by_key(|(_, entry)| entry.deleted_at);
+        Ok(entries)
+    }
+
+    /// Copies the file back to its original path and removes it from the trash. Copying
+    /// rather than moving makes the file system watcher report it as a new file.
+    pub fn restore(&self, id: &str, root: &Path) -> io::Result<PathBuf> {
+        let entry = self.entry(id)?;
+        let target = root.join(&entry.original_path);
+        if target.try_exists()? {
+            return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{:?} exists, move it away before restoring", entry.original_path)
+            ));
+        }
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::copy(self.dir.join(id), &target)?;
+        self.remove(id)?;
+        Ok(entry.original_path)
+    }
+
+    /// Removes the entries deleted before `cutoff`, returns how many were removed.
+    pub fn expire(&self, cutoff: u64) -> io::Result<usize> {
+        let mut removed = 0;
+        for (id, entry) in self.list()? {
+            if entry.deleted_at < cutoff {
+                self.remove(&id)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    pub fn empty(&self) -> io::Result<usize> {
+        self.expire(u64::MAX)
+    }
+
+    fn entry(&self, id: &str) -> io::Result<TrashEntry> {
+        // Ids are file names in the trash, anything else could point outside of it
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_hexdigit() || c == '-') {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid trash id {}", id)));
+        }
+        let meta = fs::read(self.dir.join(format!("{}.meta", id)))?;
+        bincode::deserialize(&meta).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    fn remove(&self, id: &str) -> io::Result<()> {
+        match fs::remove_file(self.dir.join(id)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => {},
+        }
+        fs::remove_file(self.dir.join(format!("{}.meta", id)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn trashed_files_are_restored_and_expired() -> io::Result<()> {
+        let root = env::temp_dir().join(format!("rdovetail-trash-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("docs"))?;
+        fs::write(root.join("docs/a.txt"), b"a")?;
+        fs::write(root.join("b.txt"), b"b")?;
+
+        let trash = Trash::open(&root.join(".rdovetail"))?;
+        let first = trash.put(&root, Path::new("./docs/a.txt"), 7, 100)?;
+        trash.put(&root, Path::new("./b.txt"), 7, 200)?;
+        assert!(!root.join("docs/a.txt").exists());
+        assert_eq!(trash.list()?.len(), 2);
+
+        assert_eq!(trash.restore(&first, &root)?, PathBuf::from("./docs/a.txt"));
+        assert_eq!(fs::read(root.join("docs/a.txt"))?, b"a");
+        assert!(trash.restore("../../etc", &root).is_err());
+        assert!(trash.put(&root, Path::new("./gone.txt"), 7, 300).is_err());
+        assert_eq!(trash.list()?.len(), 1);
+
+        assert_eq!(trash.expire(150)?, 0);
+        assert_eq!(trash.expire(250)?, 1);
+        assert!(trash.list()?.is_empty());
+        fs::remove_dir_all(&root)
+    }
+}
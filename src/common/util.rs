@@ -1,17 +1,18 @@
 use sha2::{Sha256, Digest};
 use core::panic;
-use std::path::{Path, PathBuf, Iter};
+use std::path::{Component, Path, PathBuf, Iter};
 use std::sync::mpsc::{channel, Receiver, Sender};
 use std::sync::{Arc, Mutex};
 use std::{fs, io, thread};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::fs::File;
 use std::thread::JoinHandle;
-use memmap2::{Mmap, MmapOptions};
+use log::{debug, error};
 
 use super::data::{FileData, Index};
 
-pub fn hash_path(path: &PathBuf) -> [u8; 32] {
+pub fn hash_path(path: &Path) -> [u8; 32] {
     let mut hasher = Sha256::new();
     for dir_name in path.iter() {
         Digest::update(&mut hasher, dir_name.as_encoded_bytes());
@@ -21,42 +22,20 @@ pub fn hash_path(path: &PathBuf) -> [u8; 32] {
     relative_path_hash
 }
 
+/// SHA-256 of the whole content of the file, the same hash the object store keeps it under.
+/// Read in blocks, so large files are never held in memory at once.
 pub fn hash_file(path: &Path) -> Option<[u8; 32]> {
-    let file = match File::open(path) {
-        Ok(file) => file,
-        Err(_) => return None,
-    };
-    let buffer_size: usize = 512;
-    
-    let mmap = unsafe {
-        let opt = MmapOptions::new().len(buffer_size).map(&file);
-        match opt {
-            Ok(m) => m,
-            Err(_) => match Mmap::map(&file) {
-                Ok(map) => map,
-                Err(err) => panic!("File could not be read: {:?}", err),
-            },
-        }
-    };
-
+    let mut file = File::open(path).ok()?;
     let mut hasher = Sha256::new();
-    hasher.update(&mmap);
-    let result = hasher.finalize();
-
-    let mut returned: [u8; 32] = [0; 32];
-    let mut index = 0;
-    for byte in result.iter() {
-        returned[index] = *byte;
-        index += 1;
-    }
-    Some(returned)
+    io::copy(&mut file, &mut hasher).ok()?;
+    Some(hasher.finalize().into())
 }
 
 pub fn create_file_data(path_to_dir: PathBuf, path: PathBuf) -> Option<FileData> {
     let hash = match hash_file(&path) {
         Some(hash) => hash,
         None => {
-            println!("Hash failed!");
+            error!("Hash failed!");
             return None;
         },
     };
@@ -116,14 +95,38 @@ fn find_all_files(path_to_dir: &PathBuf, filepaths: &mut Vec<PathBuf>) {
     }
 }
 
-pub fn index_from_dir(path_to_dir: &PathBuf, index: Arc<Mutex<Index>>) -> io::Result<()> {
+/// Paths of all files in the directory and below, leaving out the rdovetail metadata.
+pub fn list_files(path_to_dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![path_to_dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                if dir != path_to_dir || entry.file_name() != ".rdovetail" {
+                    dirs.push(entry.path());
+                }
+            } else if file_type.is_file() {
+                files.push(entry.path());
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// A relative path hash paired with the data of the file it points to.
+type IndexEntry = ([u8; 32], FileData);
+
+/// Hashes every file of the directory into the index, spread over the given number of threads.
+pub fn index_from_dir(path_to_dir: &PathBuf, index: Arc<Mutex<Index>>, thread_amount: usize) -> io::Result<()> {
     let mut filepaths = Vec::new();
     let start = SystemTime::now();
     find_all_files(path_to_dir,&mut filepaths);
     let checkpoint = SystemTime::now();
-    let (tx, rx): (Sender<([u8; 32], FileData)>, Receiver<([u8; 32], FileData)>) = channel();
+    let (tx, rx): (Sender<IndexEntry>, Receiver<IndexEntry>) = channel();
 
-    let thread_amount = 4;
+    let thread_amount = thread_amount.max(1);
     let mut thread_handles: Vec<JoinHandle<()>> = Vec::new();
     let slice_size = filepaths.len()/thread_amount;
     for i in 0..thread_amount {
@@ -165,8 +168,8 @@ pub fn index_from_dir(path_to_dir: &PathBuf, index: Arc<Mutex<Index>>) -> io::Re
 
     let finished_processing = SystemTime::now();
 
-    println!("Time to read files: {:?} ms", checkpoint.duration_since(start).unwrap().as_millis());
-    println!("Time to process files: {:?} ms", finished_processing.duration_since(checkpoint).unwrap().as_millis());
+    debug!("Time to read files: {:?} ms", checkpoint.duration_since(start).unwrap().as_millis());
+    debug!("Time to process files: {:?} ms", finished_processing.duration_since(checkpoint).unwrap().as_millis());
     Ok(())
 }
 
@@ -179,3 +182,116 @@ pub fn as_nanos_since_epoch(system_time: &SystemTime) -> u64 {
         Err(err) => panic!("Failed to get duration since epoch: {:?}", err),
     }
 }
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    let mut hex_string = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        hex_string.push_str(&format!("{:02x}", byte));
+    }
+    hex_string
+}
+
+pub fn from_hex(hex_string: &str) -> Option<Vec<u8>> {
+    if !hex_string.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex_string.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex_string.get(i..i+2)?, 16).ok())
+        .collect()
+}
+
+/// Checks that a path received from a peer stays inside the synchronized directory and does not
+/// touch the rdovetail metadata.
+pub fn is_safe_relative_path(path: &Path) -> bool {
+    path.components().all(|component| match component {
+        Component::CurDir => true,
+        Component::Normal(name) => name != ".rdovetail",
+        _ => false,
+    })
+}
+
+/// Sleeps in short steps, so a stopped thread does not linger for the whole delay.
+pub fn sleep_unless_stopped(delay: Duration, stop: &AtomicBool) {
+    let step = Duration::from_millis(100);
+    let mut remaining = delay;
+    while !remaining.is_zero() && !stop.load(Ordering::SeqCst) {
+        let pause = remaining.min(step);
+        thread::sleep(pause);
+        remaining -= pause;
+    }
+}
+
+/// Formats seconds since the epoch as a UTC date and time, e.g. `20240131-235959`.
+pub fn format_utc(secs_since_epoch: u64) -> String {
+    let days = (secs_since_epoch / 86400) as i64;
+    let secs_of_day = secs_since_epoch % 86400;
+
+    // Converts days since the epoch to a civil date in the proleptic Gregorian calendar
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}",
+        year, month, day, secs_of_day / 3600, secs_of_day % 3600 / 60, secs_of_day % 60
+    )
+}
+
+/// Parses a UTC date and time in the format of `format_utc` into seconds since the epoch.
+pub fn parse_utc(time: &str) -> Option<u64> {
+    let (date, clock) = time.split_once('-')?;
+    if date.len() != 8 || clock.len() != 6 || !time.chars().all(|c| c.is_ascii_digit() || c == '-') {
+        return None;
+    }
+    let number = |digits: &str| digits.parse::<i64>().ok();
+    let (year, month, day) = (number(&date[..4])?, number(&date[4..6])?, number(&date[6..])?);
+    let (hour, minute, second) = (number(&clock[..2])?, number(&clock[2..4])?, number(&clock[4..])?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 59 {
+        return None;
+    }
+
+    // Converts the civil date to days since the epoch, the inverse of format_utc
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let shifted_month = if month > 2 { month - 3 } else { month + 9 };
+    let day_of_year = (153 * shifted_month + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+
+    u64::try_from(days * 86400 + hour * 3600 + minute * 60 + second).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn whole_files_are_hashed() -> io::Result<()> {
+        let dir = env::temp_dir().join(format!("rdovetail-hash-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir)?;
+        let path = dir.join("large.bin");
+
+        let mut content = vec![7u8; 4096];
+        fs::write(&path, &content)?;
+        let before = hash_file(&path);
+        content[4000] = 8;
+        fs::write(&path, &content)?;
+        assert_ne!(hash_file(&path), before);
+        assert_eq!(hash_file(&path), Some(Sha256::digest(&content).into()));
+
+        fs::write(&path, [])?;
+        assert_eq!(hash_file(&path), Some(Sha256::digest([]).into()));
+        assert_eq!(hash_file(&dir.join("missing")), None);
+        fs::remove_dir_all(&dir)
+    }
+}
@@ -1,211 +1,1198 @@
 use notify::{EventHandler, EventKind, RecursiveMode, Watcher};
-use std::{env, thread};
-use std::path::Path;
+use notify::event::{AccessKind, AccessMode};
+use std::{fs, io, thread};
+use std::path::{Path, PathBuf};
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
-use std::fs::create_dir;
-use std::sync::mpsc::{Sender, Receiver, SendError, channel};
+use std::fs::create_dir_all;
+use std::sync::mpsc::{Sender, Receiver, RecvError, RecvTimeoutError, SendError, channel};
 use std::sync::{Arc, Mutex};
-use std::time::SystemTime;
+use std::sync::atomic::Ordering;
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime};
+use log::{debug, error, info, trace, warn};
 
 use crate::common::{
+    compression::Payload,
+    metrics::FolderMetrics,
+    control::{ConnectedPeer, FolderStatus, Request, Response},
     message::Message, 
-    data::{Index, FileData, Change, ChangeType}, 
-    util::{hash_path, create_file_data, find_relative_path, as_nanos_since_epoch}
+    data::{Index, FileData, Change, ChangeId, ChangeType, DeviceId, VersionOrdering, VersionVector}, 
+    conflict::{conflict_copy_path, short_device_id, Conflict, ConflictPolicy, Edit, Resolution},
+    history::{self, Retention},
+    ignore::{IgnoreRules, IGNORE_FILE},
+    journal::Journal,
+    merge::{self, is_text},
+    objects::ObjectStore,
+    outbox::Outbox,
+    peers::{Origin, PeerId, Recipients},
+    snapshot::{self, Snapshot, SnapshotStore, SCHEDULED_PREFIX},
+    trash::Trash,
+    util::{hash_path, hash_file, create_file_data, find_relative_path, as_nanos_since_epoch, is_safe_relative_path, format_utc, list_files}
 };
 
-use super::error::IllegalState;
+use super::error::{IllegalState, NotInitialized};
 use super::util::index_from_dir;
 
-fn init_dovetail(dir: &Path) -> Result<Index, Box<dyn Error>> {
-    let dovetail_dir = &dir.join(".rdovetail");
-    let dovetail_initialized = dovetail_dir.try_exists().unwrap_or_else(|_| {
-        false
-    });
-    let index = {
-        if !dovetail_initialized {
-            create_dir(dovetail_dir)?;  
-            let temp = Index::new(dir.to_path_buf());
-            let arc_temp = Arc::new(Mutex::new(temp));
-            index_from_dir(&dir.to_path_buf(), Arc::clone(&arc_temp))?;
-            let mtx = Arc::try_unwrap(arc_temp).unwrap();
-            let index = mtx.into_inner().unwrap();
-            index.write_to_file()?;
-            index
-        } else {
-            Index::from_file(&dovetail_dir.join("index"))?
+/// Whether the folder has been set up with `init`.
+pub fn is_initialized(dir: &Path) -> bool {
+    // The directory may already hold the device identity, so check for the index itself
+    dir.join(".rdovetail").join("index").try_exists().unwrap_or(false)
+}
+
+/// Sets up the `.rdovetail` directory and indexes the folder with the given number of threads,
+/// returns false if that had already been done.
+pub fn init(dir: &Path, threads: usize) -> Result<bool, Box<dyn Error>> {
+    if is_initialized(dir) {
+        return Ok(false);
+    }
+    create_dir_all(dir.join(".rdovetail"))?;
+    let temp = Index::new(dir.to_path_buf());
+    let arc_temp = Arc::new(Mutex::new(temp));
+    index_from_dir(&dir.to_path_buf(), Arc::clone(&arc_temp), threads)?;
+    let mtx = Arc::try_unwrap(arc_temp).unwrap();
+    let index = mtx.into_inner().unwrap();
+    index.write_to_file()?;
+    Ok(true)
+}
+
+/// How the VCS resolves conflicts and how long it keeps old data around.
+#[derive(Debug, Clone, Copy)]
+pub struct Settings {
+    pub conflict_policy: ConflictPolicy,
+    pub retention: Retention,
+    /// Days before files deleted by other devices are removed from the trash.
+    pub trash_days: u64,
+    /// Time between scheduled snapshots, if they are taken at all.
+    pub snapshot_interval: Option<Duration>,
+}
+
+/// Days files deleted by other devices are kept in the trash unless configured otherwise.
+pub const DEFAULT_TRASH_DAYS: u64 = 30;
+
+/// Keeps both sides of a conflict, the default retention of old versions and no scheduled
+/// snapshots.
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            conflict_policy: ConflictPolicy::default(),
+            retention: Retention::default(),
+            trash_days: DEFAULT_TRASH_DAYS,
+            snapshot_interval: None,
         }
-    };
-    Ok(index)
+    }
+}
+
+/// Number of journaled changes between two runs of the history pruning.
+const PRUNE_INTERVAL: usize = 100;
+
+const NANOS_PER_DAY: u64 = 86_400 * 1_000_000_000;
+
+/// How long the VCS waits for a message before checking whether a scheduled snapshot is due.
+const SCHEDULE_CHECK: Duration = Duration::from_secs(60);
+
+/// Time between two removals of expired files from the trash, so quiet folders expire them too.
+const TRASH_EXPIRY_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Number of scheduled snapshots kept, snapshots created by hand are kept until removed.
+const KEEP_SCHEDULED_SNAPSHOTS: usize = 10;
+
+/// What the VCS is handed on its queue. Only messages travel between devices, the rest stays
+/// within the process.
+#[derive(Debug)]
+pub enum Inbound {
+    /// A message received from the peer on the connection.
+    Message(PeerId, Message),
+    /// A file was created or written, reported by the watcher.
+    FileCreated {
+        path: PathBuf,
+    },
+    /// A file was removed, reported by the watcher.
+    FileRemoved {
+        path: PathBuf,
+    },
+    /// A session with the device started on the connection.
+    PeerConnected {
+        peer: PeerId,
+        device: DeviceId,
+    },
+    /// The session on the connection ended.
+    PeerDisconnected {
+        peer: PeerId,
+    },
+    /// A request from the control socket, answered on the channel.
+    Control {
+        request: Request,
+        reply: Sender<Response>,
+    },
+    /// Asks the VCS to send every change it journals from now on to the channel.
+    Subscribe {
+        events: Sender<ChangeEvent>,
+    },
+    /// Asks the VCS to write out its state and stop.
+    Shutdown,
 }
 
-pub fn start() -> Result<(Sender<Message>, Receiver<Message>), Box<dyn Error>> { 
-    let path = env::current_dir()?;
+/// A message emitted by the VCS, tagged with who should receive it.
+pub type Outbound = (Recipients, Message);
+/// A change journaled by the VCS, tagged with where it came from.
+pub type ChangeEvent = (Origin, Change);
+/// The queue into a started VCS, the queue out of it and the thread running it.
+pub type Started = (Sender<Inbound>, Receiver<Outbound>, JoinHandle<()>);
+
+/// Starts the VCS of the folder on its own thread, which runs until it is sent `Shutdown` or
+/// every sender has been dropped. Keeps the metrics of the folder up to date.
+pub fn start(path: &Path, device: DeviceId, settings: &Settings, metrics: Arc<FolderMetrics>) -> Result<Started, Box<dyn Error>> { 
 
     // VCS -> func caller
-    let (tx_alpha, rx_alpha): (Sender<Message>, Receiver<Message>) = channel();
+    let (tx_alpha, rx_alpha): (Sender<Outbound>, Receiver<Outbound>) = channel();
 
     // ChangeNotifier AND func caller -> VCS
-    let (tx_beta, rx_beta): (Sender<Message>, Receiver<Message>) = channel();
+    let (tx_beta, rx_beta): (Sender<Inbound>, Receiver<Inbound>) = channel();
 
-    let index = init_dovetail(&path)?;
-    // Check entire directory, and ensure that index is up to date
+    let mut vcs = VersionControl::open(path, device, settings, rx_beta, tx_alpha, Arc::clone(&metrics))?;
 
     let mut watcher = notify::recommended_watcher(
         ChangeNotifier {
             tx: tx_beta.clone(),
+            metrics: Arc::clone(&metrics),
     })?;
 
-    let mut vcs = VersionControl {
-        index,
-        rx_updates: rx_beta,
-        tx_to_client: tx_alpha,
-        changes: Vec::new(),
-    };
-
     // Add a path to be watched. All files and directories at that path and
     // below will be monitored for changes.
-    watcher.watch(&path, RecursiveMode::Recursive)?;
+    watcher.watch(path, RecursiveMode::Recursive)?;
 
-    println!("Started listening.");
-    thread::spawn(move || {
+    // Picks up what changed while not running, the watcher already catches anything after this
+    let (changed, removed) = vcs.scan()?;
+    info!("Scanned folder: {} changed, {} removed", changed, removed);
+    vcs.prune_history();
+    vcs.update_metrics();
+    debug!("Started listening.");
+    let handle = thread::spawn(move || {
         let _watcher = watcher;
-        loop {
-            vcs.listen();
-        }
+        while vcs.listen().is_ok() {}
+        vcs.flush();
     });
-    Ok((tx_beta, rx_alpha))
+    Ok((tx_beta, rx_alpha, handle))
+}
+
+/// Journals the changes made to the folder since the index was last updated, without
+/// connecting to anyone. Peers receive them on the next connection. Returns the number of
+/// changed and removed files.
+pub fn scan(path: &Path, device: DeviceId, settings: &Settings) -> Result<(usize, usize), Box<dyn Error>> {
+    let (_tx_beta, rx_beta) = channel();
+    // Kept open so announcing the changes to nobody does not fail
+    let (tx_alpha, _rx_alpha) = channel();
+    let mut vcs = VersionControl::open(path, device, settings, rx_beta, tx_alpha, Arc::default())?;
+    Ok(vcs.scan()?)
 }
 
 // VCS should have some sort of data structure that can aid in determining if a file has been
 // deleted.
 
+/// A remote change waiting for the file content.
+struct PendingChange {
+    /// The peer the content was requested from.
+    peer: PeerId,
+    change: Change,
+    /// Version to give the file once written, includes the local edits of resolved conflicts.
+    version: VersionVector,
+    /// Content of the common ancestor, if the content is to be merged with the local file.
+    merge_base: Option<[u8; 32]>,
+}
+
 struct VersionControl {
+    device: DeviceId,
+    conflict_policy: ConflictPolicy,
+    retention: Retention,
+    /// Days before files deleted by other devices are removed from the trash.
+    trash_days: u64,
+    /// Changes journaled since old file versions were last pruned.
+    changes_since_prune: usize,
     index: Index,
-    rx_updates: Receiver<Message>,
-    tx_to_client: Sender<Message>,
-    changes: Vec<Change>,
+    /// Paths of the folder that are neither journaled nor written.
+    ignore: IgnoreRules,
+    rx_updates: Receiver<Inbound>,
+    tx_to_client: Sender<Outbound>,
+    journal: Journal,
+    outbox: Outbox,
+    objects: ObjectStore,
+    trash: Trash,
+    snapshots: SnapshotStore,
+    /// Time between scheduled snapshots, if they are taken at all.
+    snapshot_interval: Option<Duration>,
+    /// When the last scheduled snapshot was taken, in nanoseconds since the epoch.
+    last_snapshot: u64,
+    /// When expired files were last removed from the trash, in nanoseconds since the epoch.
+    last_trash_expiry: u64,
+    /// Device behind every open connection.
+    devices: HashMap<PeerId, DeviceId>,
+    /// Connections whose peer has sent its sync state since connecting.
+    states_received: HashSet<PeerId>,
+    /// Remote changes waiting for the file content, keyed by relative path hash.
+    pending: HashMap<[u8; 32], PendingChange>,
+    /// Whether exchanging changes with peers is paused through the control socket.
+    paused: bool,
+    /// Number of journaled changes when syncing was paused, the later ones are sent on resume.
+    journaled_before_pause: usize,
+    /// Sync states peers sent while paused, replayed on resume.
+    held_states: HashMap<PeerId, Vec<(DeviceId, u64)>>,
+    metrics: Arc<FolderMetrics>,
+    /// Receive every change once it is journaled, dropped when they hang up.
+    subscribers: Vec<Sender<ChangeEvent>>,
 }
 
 impl VersionControl {
-    fn send_update(&self, message: Message) -> Result<(), SendError<Message>> {
-        self.tx_to_client.send(message)
-    }
-
-    fn listen(&mut self) {
-        match self.rx_updates.recv() {
-            Ok(message) => {
-                match message {
-                    Message::FileCreated { path } => {
-                        println!("Created: {:?}", path);
-                        
-                        match self.add_file_data(&path) {
-                            Ok(key) => {
-                            let file_hash = self.index.get_file_data(&key).unwrap().get_hash();
-                            self.changes.push(Change {
-                                change_type: ChangeType::Create {
-                                    file_hash: *file_hash
-                                },
-                                new_state: self.index.get_current_state(),
-                                timestamp: as_nanos_since_epoch(&SystemTime::now()),
-                                file_path: path.clone(),
-                            });
-                            if let Err(err) = self.send_update(Message::FileCreated { path }) {
-                                println!("Error: {:?}", err)
-                                //check_health
-                            }
-                            },
-                            Err(err) => println!("Error: {:?}", err),
-                        };
-                    },
-                    Message::FileRemoved { path } => {
-                        if let Some(_) = self.remove_file_data(&path) {
-                            self.changes.push(Change {
-                                change_type: ChangeType::Delete,
-                                new_state: self.index.get_current_state(),
-                                timestamp: as_nanos_since_epoch(&SystemTime::now()),
-                                file_path: path.clone(),
-                            });
-
-                            // The relative path is passed up in the system
-                            let relative_path = find_relative_path(self.index.get_path_to_dir().iter(), path.iter());
-                            if let Err(err) = self.send_update(Message::FileRemoved { path: relative_path }) {
-                                println!("Error: {:?}", err)
-                                //check_health
-                            }
-                        }
-                    },
-                    Message::FileRequest { relative_path_hash } => {
-                        // Check index for hash
-                        // Send compressed file from store
+    /// Loads the state of an initialized folder.
+    fn open(
+        path: &Path,
+        device: DeviceId,
+        settings: &Settings,
+        rx_updates: Receiver<Inbound>,
+        tx_to_client: Sender<Outbound>,
+        metrics: Arc<FolderMetrics>,
+    ) -> Result<Self, Box<dyn Error>> {
+        if !is_initialized(path) {
+            return Err(Box::new(NotInitialized { dir: path.to_path_buf() }));
+        }
+        let index = Index::from_file(&path.join(".rdovetail").join("index"))?;
+        let ignore = IgnoreRules::load(path)?;
+        let journal = Journal::open(&path.join(".rdovetail"), device)?;
+        let outbox = Outbox::open(&path.join(".rdovetail"))?;
+        let objects = ObjectStore::open(&path.join(".rdovetail"))?;
+        let trash = Trash::open(&path.join(".rdovetail"))?;
+        let snapshots = SnapshotStore::open(&path.join(".rdovetail"))?;
+        let last_snapshot = snapshots.list()?
+            .iter()
+            .filter(|snapshot| snapshot.name.starts_with(SCHEDULED_PREFIX))
+            .map(|snapshot| snapshot.created)
+            .max()
+            .unwrap_or(0);
+
+        Ok(VersionControl {
+            device,
+            conflict_policy: settings.conflict_policy,
+            retention: settings.retention,
+            trash_days: settings.trash_days,
+            changes_since_prune: 0,
+            index,
+            ignore,
+            rx_updates,
+            tx_to_client,
+            journal,
+            outbox,
+            objects,
+            trash,
+            snapshots,
+            snapshot_interval: settings.snapshot_interval,
+            last_snapshot,
+            last_trash_expiry: 0,
+            devices: HashMap::new(),
+            states_received: HashSet::new(),
+            pending: HashMap::new(),
+            paused: false,
+            journaled_before_pause: 0,
+            held_states: HashMap::new(),
+            metrics,
+            subscribers: Vec::new(),
+        })
+    }
+
+    /// Compares the folder with the index and handles every difference like a change reported
+    /// by the watcher. Returns the number of changed and removed files.
+    fn scan(&mut self) -> io::Result<(usize, usize)> {
+        let root = self.index.get_path_to_dir().to_path_buf();
+        let mut changed = 0;
+        for path in list_files(&root)? {
+            let relative_path = find_relative_path(root.iter(), path.iter());
+            if !self.ignore.is_ignored(&relative_path) && !self.is_up_to_date(&relative_path, &path) {
+                self.file_created(path);
+                changed += 1;
+            }
+        }
+        let mut removed = 0;
+        for relative_path in self.index.paths() {
+            // Older indexes also hold the metadata files
+            if is_safe_relative_path(&relative_path)
+                && !self.ignore.is_ignored(&relative_path)
+                && !root.join(&relative_path).try_exists()? {
+                self.file_removed(root.join(&relative_path));
+                removed += 1;
+            }
+        }
+        Ok((changed, removed))
+    }
+    /// Fails when the receiving end is gone, the undelivered message is dropped. Nothing is sent
+    /// while paused, peers catch up on resume.
+    fn send_update(&self, recipients: Recipients, message: Message) -> Result<(), SendError<()>> {
+        if self.paused {
+            return Ok(());
+        }
+        self.tx_to_client.send((recipients, message)).map_err(|_| SendError(()))
+    }
+
+    /// Sends the change and keeps it in the outbox of every connected recipient until the
+    /// recipient acknowledges it. Recipients that are not connected catch up through sync states.
+    /// Nothing is sent or kept while paused, the changes journaled meanwhile are sent on resume.
+    fn send_change(&mut self, recipients: Recipients, change: Change) {
+        if self.paused {
+            return;
+        }
+        let mut added = false;
+        for (peer, device) in &self.devices {
+            if recipients.includes(*peer) && *device != change.id.origin {
+                self.outbox.add(*device, change.id);
+                added = true;
+            }
+        }
+        if added {
+            if let Err(err) = self.outbox.write_to_file() {
+                error!("{:?}", err);
+            }
+        }
+        if let Err(err) = self.send_update(recipients, Message::ExternalChange { change }) {
+            error!("{:?}", err)
+        }
+    }
+
+    /// Tells the peer that the change it sent has been applied.
+    fn acknowledge(&self, peer: PeerId, id: ChangeId) {
+        self.record_sync(peer);
+        if let Err(err) = self.send_update(Recipients::Only(peer), Message::Ack { id }) {
+            error!("{:?}", err)
+        }
+    }
+
+    /// Removes the file versions the retention rules no longer keep, and the expired files in
+    /// the trash.
+    fn prune_history(&mut self) {
+        self.changes_since_prune = 0;
+        let now = as_nanos_since_epoch(&SystemTime::now());
+        // Without knowing what the snapshots reference nothing can be pruned safely
+        let pinned = match self.snapshots.hashes() {
+            Ok(pinned) => pinned,
+            Err(err) => {
+                error!("{:?}", err);
+                return;
+            },
+        };
+        match history::prune(self.journal.entries(), &self.objects, &self.retention, &pinned, now) {
+            Ok(0) => {},
+            Ok(removed) => info!("Pruned {} old file version(s)", removed),
+            Err(err) => error!("{:?}", err),
+        }
+        self.expire_trash(now);
+    }
+
+    fn expire_trash(&mut self, now: u64) {
+        self.last_trash_expiry = now;
+        let cutoff = now.saturating_sub(self.trash_days.saturating_mul(NANOS_PER_DAY));
+        match self.trash.expire(cutoff) {
+            Ok(0) => {},
+            Ok(removed) => info!("Removed {} expired file(s) from the trash", removed),
+            Err(err) => error!("{:?}", err),
+        }
+    }
+
+    /// Expires the trash if that has not happened for a while, whether or not changes come in.
+    fn expire_trash_when_due(&mut self) {
+        let now = as_nanos_since_epoch(&SystemTime::now());
+        if now.saturating_sub(self.last_trash_expiry) >= TRASH_EXPIRY_INTERVAL.as_nanos() as u64 {
+            self.expire_trash(now);
+        }
+    }
+
+    /// Called for every journaled change, prunes old file versions every so often.
+    fn count_change(&mut self) {
+        self.changes_since_prune += 1;
+        if self.changes_since_prune >= PRUNE_INTERVAL {
+            self.prune_history();
+        }
+    }
+
+    /// Handles the next message, takes a scheduled snapshot and expires the trash when due. Fails
+    /// once every sender has been dropped or the VCS is shut down.
+    fn listen(&mut self) -> Result<(), RecvError> {
+        match self.rx_updates.recv_timeout(SCHEDULE_CHECK) {
+            Ok(Inbound::Shutdown) => return Err(RecvError),
+            Ok(Inbound::Control { request, reply }) => {
+                let _ = reply.send(self.handle_control(request));
+            },
+            Ok(Inbound::Subscribe { events }) => self.subscribers.push(events),
+            Ok(Inbound::FileCreated { path }) => self.file_created(path),
+            Ok(Inbound::FileRemoved { path }) => self.file_removed(path),
+            Ok(Inbound::PeerConnected { peer, device }) => self.peer_connected(peer, device),
+            Ok(Inbound::PeerDisconnected { peer }) => self.peer_disconnected(peer),
+            Ok(Inbound::Message(peer, message)) if self.paused => self.hold(peer, message),
+            Ok(Inbound::Message(peer, message)) => self.handle_remote(peer, message),
+            Err(RecvTimeoutError::Timeout) => {},
+            Err(RecvTimeoutError::Disconnected) => return Err(RecvError),
+        }
+        self.update_metrics();
+        self.take_scheduled_snapshot();
+        self.expire_trash_when_due();
+        Ok(())
+    }
+
+    fn update_metrics(&self) {
+        self.metrics.files_indexed.store(self.index.len() as u64, Ordering::Relaxed);
+        self.metrics.pending_outbound.store(self.outbox.len() as u64, Ordering::Relaxed);
+        self.metrics.conflicts.store(self.journal.conflicts().len() as u64, Ordering::Relaxed);
+    }
+
+    /// Records that the device behind the connection is in sync up to a change.
+    fn record_sync(&self, peer: PeerId) {
+        if let Some(device) = self.devices.get(&peer) {
+            self.metrics.record_sync(*device);
+        }
+    }
+
+    /// Answers a request from the control socket.
+    fn handle_control(&mut self, request: Request) -> Response {
+        match request {
+            Request::Status => Response::Status(FolderStatus {
+                pid: std::process::id(),
+                paused: self.paused,
+                tracked_files: self.index.paths().iter().filter(|path| is_safe_relative_path(path)).count(),
+                journaled_changes: self.journal.entries().len(),
+                connected_peers: self.devices.len(),
+                pending_transfers: self.pending.len(),
+            }),
+            Request::Pause => {
+                if !self.paused {
+                    self.paused = true;
+                    self.journaled_before_pause = self.journal.entries().len();
+                    info!("Paused");
+                }
+                Response::Done
+            },
+            Request::Resume => {
+                if self.paused {
+                    self.paused = false;
+                    info!("Resumed");
+                    self.resync_all();
+                    let journaled_while_paused = self.journal.entries()[self.journaled_before_pause..].to_vec();
+                    for change in journaled_while_paused {
+                        self.send_change(Recipients::All, change);
+                    }
+                }
+                Response::Done
+            },
+            Request::Rescan => match self.scan() {
+                Ok((changed, removed)) => Response::Scanned { changed, removed },
+                Err(err) => Response::Error(err.to_string()),
+            },
+            Request::Peers => Response::Peers(self.devices
+                .iter()
+                .map(|(peer, device)| ConnectedPeer {
+                    device: *device,
+                    unacknowledged: self.outbox.unacknowledged(*device).len(),
+                    sent_state: self.states_received.contains(peer),
+                })
+                .collect()),
+            Request::Conflicts => Response::Conflicts(self.journal.conflicts().to_vec()),
+            Request::Sync if self.paused => Response::Error("Syncing is paused, resume it first".to_string()),
+            Request::Sync => Response::Syncing(self.resync_all()),
+        }
+    }
+
+    /// Handles a message from a peer while paused. Only the sync state is kept, the changes the
+    /// peer sends are dropped and replayed once the sync state is exchanged on resume.
+    fn hold(&mut self, peer: PeerId, message: Message) {
+        if let Message::SyncState { known } = message {
+            self.held_states.insert(peer, known);
+        }
+    }
+
+    fn peer_connected(&mut self, peer: PeerId, device: DeviceId) {
+        self.devices.insert(peer, device);
+        self.resync(peer);
+    }
+
+    fn peer_disconnected(&mut self, peer: PeerId) {
+        // Requests that will never be answered, the changes are replayed on reconnect
+        self.pending.retain(|_, pending| pending.peer != peer);
+        self.devices.remove(&peer);
+        self.states_received.remove(&peer);
+        self.held_states.remove(&peer);
+    }
+
+    /// Exchanges sync states with every connected peer, returns their number.
+    fn resync_all(&mut self) -> usize {
+        let peers: Vec<PeerId> = self.devices.keys().copied().collect();
+        for peer in &peers {
+            self.resync(*peer);
+        }
+        peers.len()
+    }
+
+    /// Sends the peer the sync state of this device, so it replays the changes missing here, and
+    /// retransmits the changes it has not acknowledged.
+    fn resync(&mut self, peer: PeerId) {
+        let device = match self.devices.get(&peer) {
+            Some(device) => *device,
+            None => return,
+        };
+        let known = self.journal.known_sequences();
+        if let Err(err) = self.send_update(Recipients::Only(peer), Message::SyncState { known }) {
+            error!("{:?}", err)
+        }
+
+        let unacknowledged: Vec<Change> = self.outbox.unacknowledged(device)
+            .iter()
+            .filter_map(|id| self.journal.get(id).cloned())
+            .collect();
+        if !unacknowledged.is_empty() {
+            info!("Retransmitting {} unacknowledged change(s) to peer", unacknowledged.len());
+        }
+        for change in unacknowledged {
+            self.send_change(Recipients::Only(peer), change);
+        }
+        if let Some(known) = self.held_states.remove(&peer) {
+            self.replay(peer, &known);
+        }
+    }
+
+    /// Sends the peer the changes it has not seen according to its sync state.
+    fn replay(&mut self, peer: PeerId, known: &[(DeviceId, u64)]) {
+        let missing = self.journal.missing_for(known);
+        if !missing.is_empty() {
+            info!("Replaying {} change(s) to peer", missing.len());
+        }
+        for change in missing {
+            self.send_change(Recipients::Only(peer), change);
+        }
+    }
+
+    /// Writes the index and the outbox and makes sure the journal has reached the disk, called
+    /// before the VCS stops.
+    fn flush(&mut self) {
+        let results = [self.index.write_to_file(), self.outbox.write_to_file(), self.journal.sync()];
+        for err in results.into_iter().filter_map(Result::err) {
+            error!("{:?}", err);
+        }
+    }
+
+    /// Snapshots the folder if the interval has passed since the last scheduled snapshot, and
+    /// removes the scheduled snapshots beyond the number kept.
+    fn take_scheduled_snapshot(&mut self) {
+        let interval = match self.snapshot_interval {
+            Some(interval) => interval.as_nanos() as u64,
+            None => return,
+        };
+        let now = as_nanos_since_epoch(&SystemTime::now());
+        if now.saturating_sub(self.last_snapshot) < interval {
+            return;
+        }
+        self.last_snapshot = now;
+
+        let snapshot = snapshot::scan(self.index.get_path_to_dir(), Some(&self.objects)).map(|files| Snapshot {
+            name: format!("{}{}", SCHEDULED_PREFIX, format_utc(now / 1_000_000_000)),
+            created: now,
+            files,
+        });
+        match snapshot.and_then(|snapshot| self.snapshots.save(&snapshot).map(|_| snapshot)) {
+            Ok(snapshot) => info!("Took snapshot {}", snapshot.name),
+            Err(err) => error!("{:?}", err),
+        }
+
+        let scheduled: Vec<Snapshot> = match self.snapshots.list() {
+            Ok(snapshots) => snapshots.into_iter().filter(|snapshot| snapshot.name.starts_with(SCHEDULED_PREFIX)).collect(),
+            Err(err) => {
+                error!("{:?}", err);
+                return;
+            },
+        };
+        for snapshot in scheduled.iter().rev().skip(KEEP_SCHEDULED_SNAPSHOTS) {
+            if let Err(err) = self.snapshots.remove(&snapshot.name) {
+                error!("{:?}", err);
+            }
+        }
+    }
 
+    /// Records a file created or written on this device and announces it to every peer.
+    fn file_created(&mut self, path: PathBuf) {
+        // Directories are created implicitly along with the files inside them
+        if path.is_dir() {
+            return;
+        }
+        let relative_path = find_relative_path(self.index.get_path_to_dir().iter(), path.iter());
+        self.reload_ignore_rules(&relative_path);
+        if self.ignore.is_ignored(&relative_path) || self.is_up_to_date(&relative_path, &path) {
+            // Written by implement_change, or a duplicate event
+            return;
+        }
+        info!("Created: {:?}", path);
+
+        // Replacing or recreating a file is an edit of the version this device has
+        let mut version = self.index.get_version(&hash_path(&relative_path))
+            .cloned()
+            .unwrap_or_default();
+        version.increment(self.device);
+        let content_hash = match fs::read(&path).and_then(|content| self.objects.put(&content)) {
+            Ok(content_hash) => content_hash,
+            Err(err) => {
+                error!("{:?}", err);
+                return;
+            },
+        };
+        match self.add_file_data(&path, version.clone()) {
+            Ok(key) => {
+                let file_hash = *self.index.get_file_data(&key).unwrap().get_hash();
+                self.record_local_change(ChangeType::Create { file_hash }, relative_path, version, Some(content_hash));
+            },
+            Err(err) => error!("{:?}", err),
+        };
+    }
+
+    /// Records a file removed on this device and announces it to every peer.
+    fn file_removed(&mut self, path: PathBuf) {
+        // The relative path is passed up in the system
+        let relative_path = find_relative_path(self.index.get_path_to_dir().iter(), path.iter());
+        self.reload_ignore_rules(&relative_path);
+        if self.ignore.is_ignored(&relative_path) {
+            return;
+        }
+        let mut version = match self.index.get_file_data(&hash_path(&relative_path)) {
+            Some(file_data) => file_data.get_version().clone(),
+            None => return,
+        };
+        version.increment(self.device);
+        self.remove_file_data(&relative_path, version.clone());
+        self.record_local_change(ChangeType::Delete, relative_path, version, None);
+    }
+
+    /// Picks up edits of the ignore file, which is synchronized like any other file.
+    fn reload_ignore_rules(&mut self, relative_path: &Path) {
+        if relative_path != Path::new(".").join(IGNORE_FILE) {
+            return;
+        }
+        match IgnoreRules::load(self.index.get_path_to_dir()) {
+            Ok(ignore) => self.ignore = ignore,
+            Err(err) => error!("{:?}", err),
+        }
+    }
+
+    /// Journals a change made on this device and announces it to every peer.
+    fn record_local_change(
+        &mut self,
+        change_type: ChangeType,
+        file_path: PathBuf,
+        version: VersionVector,
+        content_hash: Option<[u8; 32]>,
+    ) {
+        let change = Change {
+            id: self.journal.next_id(),
+            change_type,
+            new_state: self.index.get_current_state(),
+            timestamp: as_nanos_since_epoch(&SystemTime::now()),
+            file_path,
+            version,
+            content_hash,
+        };
+        match self.journal.append(change.clone()) {
+            Ok(_) => self.publish(Origin::Local, &change),
+            Err(err) => error!("{:?}", err),
+        }
+        self.send_change(Recipients::All, change);
+        self.count_change();
+    }
+
+    /// Hands a journaled change to every subscriber.
+    fn publish(&mut self, origin: Origin, change: &Change) {
+        self.subscribers.retain(|events| events.send((origin, change.clone())).is_ok());
+    }
+
+    /// Journals a change received from a peer, acknowledges it and passes it on to every other
+    /// peer. Peers that already have the change recognize its id and drop it.
+    fn record_remote_change(&mut self, origin: Origin, change: Change) {
+        let id = change.id;
+        match self.journal.append(change.clone()) {
+            Ok(true) => {
+                self.publish(origin, &change);
+                self.send_change(Recipients::excluding(origin), change);
+                self.count_change();
+            },
+            Ok(false) => {},
+            // Left unacknowledged, so the peer sends the change again after a reconnect
+            Err(err) => {
+                error!("{:?}", err);
+                return;
+            },
+        }
+        if let Origin::Peer(peer) = origin {
+            self.acknowledge(peer, id);
+        }
+    }
 
+    /// Answers requests from a peer and applies the changes it announces.
+    fn handle_remote(&mut self, peer: PeerId, message: Message) {
+        if let Message::ExternalChange { change: Change { file_path: path, .. } }
+            | Message::FileContents { path, .. } = &message {
+            if !is_safe_relative_path(path) {
+                error!("peer sent an unsafe path {:?}", path);
+                return;
+            }
+        }
+
+        match message {
+            Message::ExternalChange { change } => {
+                self.implement_change(Origin::Peer(peer), change);
+            },
+            Message::FileRequest { relative_path_hash } => {
+                // Payloads are sent raw, compression is applied by the connection
+                // according to the codec negotiated with the peer.
+                let message = match self.read_file(&relative_path_hash) {
+                    Ok((path, content)) => Message::FileContents {
+                        path,
+                        payload: Payload::raw(content),
+                    },
+                    Err(err) => {
+                        error!("{:?}", err);
+                        Message::FileUnavailable { relative_path_hash }
                     },
-                    Message::ExternalChange {  } => {
-                        // Implement change
+                };
+                if let Err(err) = self.send_update(Recipients::Only(peer), message) {
+                    error!("{:?}", err)
+                }
+            },
+            Message::FileUnavailable { relative_path_hash } => {
+                // The file was changed again after the announced change, which will be
+                // announced separately. Journaling the change keeps it from being replayed.
+                if let Some(PendingChange { change, .. }) = self.pending.remove(&relative_path_hash) {
+                    let id = change.id;
+                    match self.journal.append(change) {
+                        Ok(_) => self.acknowledge(peer, id),
+                        Err(err) => error!("{:?}", err),
                     }
                 }
             },
-            Err(err) => println!("Error: {:?}", err),
+            Message::SyncState { known } => {
+                self.states_received.insert(peer);
+                self.replay(peer, &known);
+            },
+            Message::Ack { id } => {
+                self.record_sync(peer);
+                if let Some(device) = self.devices.get(&peer) {
+                    if self.outbox.acknowledge(*device, &id) {
+                        if let Err(err) = self.outbox.write_to_file() {
+                            error!("{:?}", err);
+                        }
+                    }
+                }
+            },
+            Message::FileContents { path, payload } => {
+                let pending = match self.pending.remove(&hash_path(&path)) {
+                    Some(pending) => pending,
+                    None => {
+                        error!("received {:?} without requesting it", path);
+                        return;
+                    },
+                };
+                let content = match payload.decompress() {
+                    Ok(content) => content,
+                    Err(err) => {
+                        error!("{:?}", err);
+                        return;
+                    },
+                };
+                if let Some(merge_base) = pending.merge_base {
+                    self.merge_file(peer, &path, content, merge_base, pending.change, pending.version);
+                    return;
+                }
+                match self.write_file(&path, &content, pending.version) {
+                    Ok(_) => {
+                        info!("Received: {:?}", path);
+                        self.record_remote_change(Origin::Peer(peer), pending.change);
+                    },
+                    Err(err) => error!("{:?}", err),
+                }
+            },
+            // Unwrapped or reassembled by the connection, which handles the rest itself
+            Message::Hello { .. } | Message::Error { .. } | Message::Folder { .. } | Message::FileChunk { .. } => {},
         }
     }
 
-    fn add_file_data(&mut self, path: &Path) -> Result<[u8; 32], Box<dyn Error>> {
+    /// Whether the index already holds the current content of the file.
+    fn is_up_to_date(&self, relative_path: &Path, path: &Path) -> bool {
+        match (self.index.get_file_data(&hash_path(relative_path)), hash_file(path)) {
+            (Some(file_data), Some(hash)) => *file_data.get_hash() == hash,
+            _ => false,
+        }
+    }
+
+    fn add_file_data(&mut self, path: &Path, version: VersionVector) -> Result<[u8; 32], Box<dyn Error>> {
         // Hashes content with filename
-        let file_data = match create_file_data(
+        let mut file_data = match create_file_data(
             self.index.get_path_to_dir().to_path_buf(), 
             path.to_path_buf()
         ) {
             Some(file_data) => file_data,
             None => return Err(Box::new(IllegalState::new("filedata could not be created".to_string()))),
         };
+        file_data.set_version(version);
 
         // Uses the relative path to the file as a key to avoid collisions for
         // files with the same name.
         let relative_path_hash = hash_path(&file_data.get_path_from_root());
 
-        self.index.add_file_data(relative_path_hash, file_data)?;
+        self.index.edit_file_data(relative_path_hash, file_data);
         self.index.write_to_file()?;
         Ok(relative_path_hash)
     }
 
-    fn remove_file_data(&mut self, path: &Path) -> Option<FileData> {
-        let relative_path = find_relative_path(
-            self.index.get_path_to_dir().iter(), 
-            path.iter()
-        );
+    fn read_file(&self, relative_path_hash: &[u8; 32]) -> Result<(PathBuf, Vec<u8>), Box<dyn Error>> {
+        let file_data = match self.index.get_file_data(relative_path_hash) {
+            Some(file_data) => file_data,
+            None => return Err(Box::new(IllegalState::new("requested file is not indexed".to_string()))),
+        };
+        let relative_path = file_data.get_path_from_root();
+        let content = fs::read(self.index.get_path_to_dir().join(&relative_path))?;
+        Ok((relative_path, content))
+    }
+
+    /// Writes file content received from a peer and indexes it before the watcher reports it.
+    fn write_file(&mut self, relative_path: &Path, content: &[u8], version: VersionVector) -> Result<[u8; 32], Box<dyn Error>> {
+        self.objects.put(content)?;
+        let path = self.index.get_path_to_dir().join(relative_path);
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent)?;
+        }
+        fs::write(&path, content)?;
 
-        let relative_path_hash = hash_path(&relative_path);
-        self.index.remove_file_data(relative_path_hash)
+        let key = self.add_file_data(&path, version)?;
+        Ok(*self.index.get_file_data(&key).unwrap().get_hash())
     }
 
-    fn implement_change(&mut self, change: Change) {
-        match change.change_type {
-            ChangeType::Create { file_hash } => {
-                // Request file from original source
-                //
+    /// When the file already has the announced content, only its version is updated and the
+    /// content does not have to be transferred. Returns whether that was the case.
+    fn adopt_version(&mut self, relative_path_hash: &[u8; 32], file_hash: &[u8; 32], version: &VersionVector) -> bool {
+        match self.index.get_file_data(relative_path_hash) {
+            Some(file_data) if file_data.get_hash() == file_hash => {},
+            _ => return false,
+        };
+        self.set_version(relative_path_hash, version.clone());
+        true
+    }
 
+    /// Updates the version of the file, or of its tombstone if it has been deleted.
+    fn set_version(&mut self, relative_path_hash: &[u8; 32], version: VersionVector) {
+        match self.index.get_file_data(relative_path_hash) {
+            Some(file_data) => {
+                let mut file_data = file_data.clone();
+                file_data.set_version(version);
+                self.index.edit_file_data(*relative_path_hash, file_data);
             },
-            ChangeType::Delete => {
+            None => self.index.add_tombstone(*relative_path_hash, version),
+        }
+        if let Err(err) = self.index.write_to_file() {
+            error!("{:?}", err);
+        }
+    }
 
-                self.remove_file_data(&change.file_path);
+    /// The device and time of the local edit that produced the version of the file. Edits made
+    /// before the journal existed are attributed to this device at the file's modification time.
+    fn local_edit(&self, relative_path: &Path, version: &VersionVector) -> Edit {
+        let journaled = self.journal.entries()
+            .iter()
+            .rev()
+            .find(|change| change.file_path == relative_path && change.version == *version);
+        match journaled {
+            Some(change) => Edit { origin: change.id.origin, timestamp: change.timestamp },
+            None => Edit {
+                origin: self.device,
+                timestamp: self.index.get_file_data(&hash_path(relative_path))
+                    .map(|file_data| as_nanos_since_epoch(file_data.get_timestamp()))
+                    .unwrap_or(0),
             },
-            ChangeType::Modify { file_hash } => {
+        }
+    }
+
+    /// Decides which of two concurrent edits of a file wins according to the conflict policy.
+    fn remote_wins(&self, change: &Change) -> bool {
+        let local = self.current_edit(&change.file_path);
+        let remote = Edit { origin: change.id.origin, timestamp: change.timestamp };
+        self.conflict_policy.remote_wins(&local, &remote)
+    }
+
+    /// The local edit that produced the current version of the file.
+    fn current_edit(&self, relative_path: &Path) -> Edit {
+        let local_version = self.index.get_version(&hash_path(relative_path)).cloned().unwrap_or_default();
+        self.local_edit(relative_path, &local_version)
+    }
+
+    /// Records the conflict in the journal. Keeps a copy of the local file if it loses and the
+    /// policy keeps both. Returns whether the remote change should be applied, which is not
+    /// the case if the copy could not be made.
+    fn resolve_conflict(&mut self, change: &Change, remote_wins: bool) -> bool {
+        let relative_path_hash = hash_path(&change.file_path);
+        let local = self.current_edit(&change.file_path);
+
+        let mut copy = None;
+        let file_exists = self.index.get_file_data(&relative_path_hash).is_some();
+        if remote_wins && file_exists && self.conflict_policy.keeps_copies() {
+            // Announced to the peers like any other new file once the watcher reports it
+            let copy_path = conflict_copy_path(&change.file_path, &local);
+            let dir = self.index.get_path_to_dir();
+            match fs::copy(dir.join(&change.file_path), dir.join(&copy_path)) {
+                Ok(_) => copy = Some(copy_path),
+                Err(err) => {
+                    // Keeps the local version rather than losing it
+                    error!("failed to keep a conflict copy of {:?}: {:?}", change.file_path, err);
+                    return false;
+                },
+            }
+        }
+
+        let (resolution, kept) = if remote_wins {
+            (Resolution::KeptRemote, "remote")
+        } else {
+            (Resolution::KeptLocal, "local")
+        };
+        warn!("Conflict: {:?} was changed on another device, keeping the {} version", change.file_path, kept);
+        if let Some(copy) = &copy {
+            warn!("Kept the local version as {:?}", copy);
+        }
+
+        let conflict = Conflict {
+            file_path: change.file_path.clone(),
+            change: change.id,
+            resolution,
+            copy,
+            timestamp: as_nanos_since_epoch(&SystemTime::now()),
+        };
+        if let Err(err) = self.journal.record_conflict(conflict) {
+            error!("{:?}", err);
+        }
+        remote_wins
+    }
+
+    /// Content of the last common ancestor of the local file and the remote change, if both are
+    /// text that can be merged line by line. The ancestor is the newest journaled version both
+    /// versions were derived from.
+    fn merge_base(&self, change: &Change) -> Option<[u8; 32]> {
+        if !matches!(change.change_type, ChangeType::Create { .. } | ChangeType::Modify { .. }) {
+            return None;
+        }
+        let relative_path_hash = hash_path(&change.file_path);
+        let local_version = self.index.get_file_data(&relative_path_hash)?.get_version();
+        let local_content = fs::read(self.index.get_path_to_dir().join(&change.file_path)).ok()?;
+        if !is_text(&local_content) {
+            return None;
+        }
+
+        let derived_from = |ancestor: &VersionVector, version: &VersionVector| {
+            matches!(ancestor.compare(version), VersionOrdering::Before | VersionOrdering::Equal)
+        };
+        let ancestor = self.journal.entries()
+            .iter()
+            .rev()
+            .filter(|entry| entry.file_path == change.file_path)
+            .find(|entry| derived_from(&entry.version, local_version) && derived_from(&entry.version, &change.version))?;
+        // A deleted ancestor means both sides created the file independently
+        let base = ancestor.content_hash?;
+        if self.objects.contains(&base) { Some(base) } else { None }
+    }
 
+    /// Merges the content of a concurrent remote change into the local file and announces the
+    /// result as a new edit. Falls back to the conflict policy if the files cannot be merged.
+    fn merge_file(
+        &mut self,
+        peer: PeerId,
+        relative_path: &Path,
+        remote_content: Vec<u8>,
+        merge_base: [u8; 32],
+        change: Change,
+        mut version: VersionVector,
+    ) {
+        let local_content = fs::read(self.index.get_path_to_dir().join(relative_path)).unwrap_or_default();
+        let base_content = self.objects.get(&merge_base).unwrap_or_default();
+        let local = self.current_edit(relative_path);
+        let merged = match (
+            std::str::from_utf8(&base_content),
+            std::str::from_utf8(&local_content),
+            std::str::from_utf8(&remote_content),
+        ) {
+            (Ok(base), Ok(local_text), Ok(remote)) if is_text(&remote_content) => merge::merge(
+                base,
+                local_text,
+                remote,
+                &short_device_id(local.origin),
+                &short_device_id(change.id.origin),
+            ),
+            _ => None,
+        };
+
+        let merged = match merged {
+            Some(merged) if merged.content.as_bytes() != remote_content.as_slice() => merged,
+            // The remote content already includes the local edits
+            Some(_) => {
+                if let Err(err) = self.write_file(relative_path, &remote_content, version) {
+                    error!("{:?}", err);
+                    return;
+                }
+                info!("Received: {:?}", relative_path);
+                self.record_remote_change(Origin::Peer(peer), change);
+                return;
             },
-            ChangeType::Rename { new_name } => {
+            None => {
+                error!("{:?} cannot be merged", relative_path);
+                if self.resolve_conflict(&change, true) {
+                    if let Err(err) = self.write_file(relative_path, &remote_content, version) {
+                        error!("{:?}", err);
+                        return;
+                    }
+                }
+                self.record_remote_change(Origin::Peer(peer), change);
+                return;
+            },
+        };
 
+        let content = merged.content.into_bytes();
+        version.increment(self.device);
+        let file_hash = match self.write_file(relative_path, &content, version.clone()) {
+            Ok(file_hash) => file_hash,
+            Err(err) => {
+                error!("{:?}", err);
+                return;
+            },
+        };
+        if merged.conflicts > 0 {
+            warn!("Conflict: merged concurrent edits of {:?} with {} conflicting region(s)", relative_path, merged.conflicts);
+            let conflict = Conflict {
+                file_path: relative_path.to_path_buf(),
+                change: change.id,
+                resolution: Resolution::Merged,
+                copy: None,
+                timestamp: as_nanos_since_epoch(&SystemTime::now()),
+            };
+            if let Err(err) = self.journal.record_conflict(conflict) {
+                error!("{:?}", err);
             }
+        } else {
+            info!("Merged concurrent edits of {:?}", relative_path);
+        }
+
+        self.record_remote_change(Origin::Peer(peer), change);
+        let content_hash = ObjectStore::hash(&content);
+        self.record_local_change(ChangeType::Create { file_hash }, relative_path.to_path_buf(), version, Some(content_hash));
+    }
+
+    /// Removes the file from the index, leaving a tombstone with the version of the deletion.
+    fn remove_file_data(&mut self, relative_path: &Path, version: VersionVector) -> Option<FileData> {
+        let relative_path_hash = hash_path(relative_path);
+        let file_data = self.index.remove_file_data(relative_path_hash);
+        self.index.add_tombstone(relative_path_hash, version);
+        if let Err(err) = self.index.write_to_file() {
+            error!("{:?}", err);
+        }
+        file_data
+    }
+
+    /// Applies a change announced by a peer to the local directory.
+    fn implement_change(&mut self, origin: Origin, change: Change) {
+        // Changes reach this device once through every path in the mesh
+        let relative_path_hash = hash_path(&change.file_path);
+        if self.journal.contains(&change.id) {
+            // Already applied, the peer may have missed the earlier acknowledgement
+            if let Origin::Peer(peer) = origin {
+                self.acknowledge(peer, change.id);
+            }
+            return;
+        }
+        // Acknowledged to the peer that delivers the content, others send it again later
+        if self.pending.values().any(|pending| pending.change.id == change.id) {
+            return;
+        }
+        // No device produces renames yet, applying one needs the content under the new name.
+        // Left unacknowledged, so the peer sends it again once this device can apply it.
+        if let ChangeType::Rename { new_name } = &change.change_type {
+            error!("Cannot apply the rename of {:?} to {:?}, renames are not supported", change.file_path, new_name);
+            return;
+        }
+        // Still journaled and passed on, so peers that do not ignore the path receive it
+        if self.ignore.is_ignored(&change.file_path) {
+            self.record_remote_change(origin, change);
+            return;
+        }
+
+        // Files this device has never seen are always fast-forwarded
+        let ordering = match self.index.get_version(&relative_path_hash) {
+            Some(version) => change.version.compare(version),
+            None => VersionOrdering::After,
+        };
+        let mut version = change.version.clone();
+        let mut merge_base = None;
+        match ordering {
+            VersionOrdering::After => {},
+            VersionOrdering::Equal | VersionOrdering::Before => {
+                // This device already has the change or a newer edit based on it
+                debug!("Ignoring outdated change to {:?}", change.file_path);
+                self.record_remote_change(origin, change);
+                return;
+            },
+            VersionOrdering::Concurrent => {
+                // The resolved file includes both edits, so neither is reported as a conflict again
+                version.merge(self.index.get_version(&relative_path_hash).unwrap());
+                let remote_wins = self.remote_wins(&change);
+                merge_base = self.merge_base(&change);
+                if merge_base.is_some() {
+                    // Text files are merged by the device whose edit loses, the other device
+                    // keeps its version and receives the merged result as a newer edit
+                    if !remote_wins {
+                        warn!("Conflict: {:?} was changed on another device, which merges both edits", change.file_path);
+                        self.set_version(&relative_path_hash, version);
+                        self.record_remote_change(origin, change);
+                        return;
+                    }
+                } else if !self.resolve_conflict(&change, remote_wins) {
+                    self.set_version(&relative_path_hash, version);
+                    self.record_remote_change(origin, change);
+                    return;
+                }
+            },
+        }
+
+        match change.change_type {
+            // Both carry the new content, which is fetched unless the file already has it
+            ChangeType::Create { file_hash } | ChangeType::Modify { file_hash } => {
+                if merge_base.is_none() && self.adopt_version(&relative_path_hash, &file_hash, &version) {
+                    self.record_remote_change(origin, change);
+                    return;
+                }
+                // Request file from original source
+                if let Origin::Peer(peer) = origin {
+                    self.pending.insert(relative_path_hash, PendingChange { peer, change, version, merge_base });
+                    if let Err(err) = self.send_update(Recipients::Only(peer), Message::FileRequest { relative_path_hash }) {
+                        error!("{:?}", err)
+                    }
+                }
+            },
+            ChangeType::Delete => {
+                if self.remove_file_data(&change.file_path, version).is_some() {
+                    // Kept in the trash, so a deletion made by mistake on another device can be undone
+                    let now = as_nanos_since_epoch(&SystemTime::now());
+                    let root = self.index.get_path_to_dir().to_path_buf();
+                    match self.trash.put(&root, &change.file_path, change.id.origin, now) {
+                        Ok(id) => info!("Removed: {:?}, kept in the trash as {}", change.file_path, id),
+                        Err(err) => error!("{:?}", err),
+                    }
+                }
+                self.record_remote_change(origin, change);
+            },
+            ChangeType::Rename { .. } => unreachable!("renames are rejected before any state changes"),
         }
     }
 }
 
 struct ChangeNotifier {
-    tx: Sender<Message>,
+    tx: Sender<Inbound>,
+    metrics: Arc<FolderMetrics>,
 }
 
 impl ChangeNotifier {
-    fn notify_vcs(&self, message: Message) {
-        let _ = self.tx.send(message);
+    fn notify_vcs(&self, inbound: Inbound) {
+        let _ = self.tx.send(inbound);
     }
 }
 
@@ -221,29 +1208,331 @@ impl EventHandler for ChangeNotifier {
                         }
                     }
                 }
-                let path = &event.paths[0];
+                let path = match event.paths.first() {
+                    Some(path) => path,
+                    None => return,
+                };
 
                 match event.kind {
                     EventKind::Remove(_) => {
-                        let message = Message::FileRemoved{
-                            path: path.clone(),
-                        };
-                        self.notify_vcs(message);
+                        self.notify_vcs(Inbound::FileRemoved { path: path.clone() });
                     },
-                    EventKind::Create(_) => {
-                        let message = Message::FileCreated { 
-                            path: path.clone(),
-                        };
-                        self.notify_vcs(message);
+                    // A file written in place is announced like a new file once it is closed
+                    EventKind::Create(_) | EventKind::Access(AccessKind::Close(AccessMode::Write)) => {
+                        self.notify_vcs(Inbound::FileCreated { path: path.clone() });
                     }, 
-                    _ => println!("Event type: {:?}", &event.kind),
+                    _ => trace!("Event type: {:?}", &event.kind),
                 };
             },
             Err(err) => {
-                eprintln!("Error occured in version_control.rs: {}", err);
+                error!("Watcher failed: {}", err);
+                self.metrics.watcher_errors.fetch_add(1, Ordering::Relaxed);
                 // check_health()?
             },
         }
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    /// A VCS driven by hand instead of by a watcher and connections. Every node of a test knows
+    /// the connection to node `j` as peer `j`.
+    struct Node {
+        vcs: VersionControl,
+        root: PathBuf,
+        device: DeviceId,
+        rx: Receiver<Outbound>,
+    }
+
+    impl Node {
+        fn new(name: &str, device: DeviceId) -> Self {
+            let root = env::temp_dir().join(format!("rdovetail-vcs-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&root);
+            fs::create_dir_all(&root).unwrap();
+            let root = root.canonicalize().unwrap();
+            init(&root, 1).unwrap();
+            let (_tx_beta, rx_beta) = channel();
+            let (tx_alpha, rx) = channel();
+            let vcs = VersionControl::open(&root, device, &Settings::default(), rx_beta, tx_alpha, Arc::default()).unwrap();
+            Node { vcs, root, device, rx }
+        }
+
+        /// Writes the file and reports it like the watcher would.
+        fn write(&mut self, name: &str, content: &str) {
+            let path = self.root.join(name);
+            fs::write(&path, content).unwrap();
+            self.vcs.file_created(path);
+        }
+
+        fn read(&self, name: &str) -> Option<String> {
+            fs::read_to_string(self.root.join(name)).ok()
+        }
+
+        /// Names of the files in the root, leaving out the rdovetail metadata.
+        fn files(&self) -> Vec<String> {
+            let mut names: Vec<String> = fs::read_dir(&self.root).unwrap()
+                .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+                .filter(|name| name != ".rdovetail")
+                .collect();
+            names.sort();
+            names
+        }
+
+        /// Handles a message like `listen` does.
+        fn receive(&mut self, peer: PeerId, message: Message) {
+            if self.vcs.paused {
+                self.vcs.hold(peer, message);
+            } else {
+                self.vcs.handle_remote(peer, message);
+            }
+        }
+
+        fn outgoing(&self) -> Vec<Outbound> {
+            self.rx.try_iter().collect()
+        }
+    }
+
+    impl Drop for Node {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.root);
+        }
+    }
+
+    /// Starts a session on both ends of every link.
+    fn connect(nodes: &mut [Node], links: &[(usize, usize)]) {
+        for &(a, b) in links {
+            let (device_a, device_b) = (nodes[a].device, nodes[b].device);
+            nodes[a].vcs.peer_connected(b as PeerId, device_b);
+            nodes[b].vcs.peer_connected(a as PeerId, device_a);
+        }
+    }
+
+    /// Delivers the messages of every node over the links until none are left, returns what was
+    /// delivered as (from, to, message).
+    fn settle(nodes: &mut [Node], links: &[(usize, usize)]) -> Vec<(usize, usize, Message)> {
+        let mut delivered = Vec::new();
+        for _ in 0..100 {
+            let mut quiet = true;
+            for from in 0..nodes.len() {
+                for (recipients, message) in nodes[from].outgoing() {
+                    let linked = links.iter().filter_map(|&(a, b)| match (a == from, b == from) {
+                        (true, _) => Some(b),
+                        (_, true) => Some(a),
+                        _ => None,
+                    });
+                    for to in linked.filter(|to| recipients.includes(*to as PeerId)).collect::<Vec<_>>() {
+                        nodes[to].receive(from as PeerId, message.clone());
+                        delivered.push((from, to, message.clone()));
+                        quiet = false;
+                    }
+                }
+            }
+            if quiet {
+                return delivered;
+            }
+        }
+        panic!("messages kept going back and forth");
+    }
+
+    #[test]
+    fn modifications_are_fetched_and_renames_rejected() {
+        let mut nodes = [Node::new("modify-a", 1), Node::new("modify-b", 2)];
+        let links = [(0, 1)];
+        connect(&mut nodes, &links);
+        settle(&mut nodes, &links);
+        nodes[0].write("a.txt", "first");
+        settle(&mut nodes, &links);
+        assert_eq!(nodes[1].read("a.txt").as_deref(), Some("first"));
+
+        // Announced as a modification instead of a new version of the file
+        nodes[0].write("a.txt", "second");
+        for (_, message) in nodes[0].outgoing() {
+            let message = match message {
+                Message::ExternalChange { mut change } => {
+                    if let ChangeType::Create { file_hash } = change.change_type {
+                        change.change_type = ChangeType::Modify { file_hash };
+                    }
+                    Message::ExternalChange { change }
+                },
+                message => message,
+            };
+            nodes[1].receive(0, message);
+        }
+        settle(&mut nodes, &links);
+        assert_eq!(nodes[1].read("a.txt").as_deref(), Some("second"));
+
+        let mut version = nodes[1].vcs.index.get_version(&hash_path(Path::new("./a.txt"))).cloned().unwrap();
+        version.increment(nodes[0].device);
+        let id = ChangeId { origin: nodes[0].device, sequence: 100 };
+        let rename = Change {
+            id,
+            change_type: ChangeType::Rename { new_name: "b.txt".to_string() },
+            new_state: [0; 32],
+            timestamp: 0,
+            file_path: PathBuf::from("./a.txt"),
+            version,
+            content_hash: None,
+        };
+        nodes[1].receive(0, Message::ExternalChange { change: rename });
+        assert!(nodes[1].outgoing().is_empty(), "renames are neither acknowledged nor passed on");
+        assert!(!nodes[1].vcs.journal.contains(&id));
+        assert_eq!(nodes[1].read("a.txt").as_deref(), Some("second"));
+        assert_eq!(nodes[1].read("b.txt"), None);
+    }
+
+    #[test]
+    fn changes_travel_a_mesh_loop_once() {
+        let mut nodes = [Node::new("mesh-a", 1), Node::new("mesh-b", 2), Node::new("mesh-c", 3)];
+        let links = [(0, 1), (1, 2), (2, 0)];
+        connect(&mut nodes, &links);
+        settle(&mut nodes, &links);
+
+        nodes[0].write("a.txt", "from a");
+        let id = nodes[0].vcs.journal.entries().last().unwrap().id;
+        let delivered = settle(&mut nodes, &links);
+        for node in &nodes {
+            assert_eq!(node.read("a.txt").as_deref(), Some("from a"));
+            assert_eq!(node.vcs.journal.entries().iter().filter(|change| change.id == id).count(), 1);
+            assert!(node.vcs.journal.conflicts().is_empty());
+        }
+        let announced_to: Vec<usize> = delivered.iter()
+            .filter_map(|(_, to, message)| match message {
+                Message::ExternalChange { change } if change.id == id => Some(*to),
+                _ => None,
+            })
+            .collect();
+        assert!(!announced_to.contains(&0), "the change was echoed back to its origin");
+        // Both other nodes hear of it from the origin and once more from each other, after which
+        // neither passes it on again
+        assert_eq!(announced_to.len(), 4);
+    }
+
+    /// Ids of the changes in the messages, in order.
+    fn announced(outbound: &[Outbound]) -> Vec<ChangeId> {
+        outbound.iter()
+            .filter_map(|(_, message)| match message {
+                Message::ExternalChange { change } => Some(change.id),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn unacknowledged_changes_are_resent_after_reconnecting() {
+        let mut nodes = [Node::new("outbox-a", 1), Node::new("outbox-b", 2)];
+        let links = [(0, 1)];
+        connect(&mut nodes, &links);
+        settle(&mut nodes, &links);
+
+        // The change is sent, but the connection drops before it arrives
+        nodes[0].write("a.txt", "lost");
+        let id = nodes[0].vcs.journal.entries().last().unwrap().id;
+        assert_eq!(announced(&nodes[0].outgoing()), vec![id]);
+        assert_eq!(nodes[0].vcs.outbox.unacknowledged(2), vec![id]);
+        nodes[0].vcs.peer_disconnected(1);
+        nodes[1].vcs.peer_disconnected(0);
+
+        // Retransmitted right away, before the sync state of the peer arrives
+        nodes[0].vcs.peer_connected(1, 2);
+        let resent = nodes[0].outgoing();
+        assert_eq!(announced(&resent), vec![id]);
+        nodes[1].vcs.peer_connected(0, 1);
+        for (_, message) in resent {
+            nodes[1].receive(0, message);
+        }
+        settle(&mut nodes, &links);
+        assert_eq!(nodes[1].read("a.txt").as_deref(), Some("lost"));
+        assert!(nodes[0].vcs.outbox.unacknowledged(2).is_empty(), "the ack clears the outbox");
+    }
+
+    #[test]
+    fn changes_made_while_paused_are_sent_on_resume() {
+        let mut nodes = [Node::new("pause-a", 1), Node::new("pause-b", 2)];
+        let links = [(0, 1)];
+        connect(&mut nodes, &links);
+        settle(&mut nodes, &links);
+
+        nodes[0].vcs.handle_control(Request::Pause);
+        nodes[0].write("a.txt", "paused");
+        assert!(nodes[0].outgoing().is_empty());
+        assert!(nodes[0].vcs.outbox.unacknowledged(2).is_empty());
+
+        nodes[0].vcs.handle_control(Request::Resume);
+        settle(&mut nodes, &links);
+        assert_eq!(nodes[1].read("a.txt").as_deref(), Some("paused"));
+        assert!(nodes[0].vcs.outbox.unacknowledged(2).is_empty());
+    }
+
+    #[test]
+    fn concurrent_edits_keep_both_versions() {
+        let mut nodes = [Node::new("keep-a", 1), Node::new("keep-b", 2)];
+        let links = [(0, 1)];
+        connect(&mut nodes, &links);
+        settle(&mut nodes, &links);
+
+        // Binary content cannot be merged, the newer edit wins
+        nodes[0].write("photo.bin", "from a\0");
+        nodes[1].write("photo.bin", "from b\0");
+        settle(&mut nodes, &links);
+        for node in &nodes {
+            assert_eq!(node.read("photo.bin").as_deref(), Some("from b\0"));
+            assert_eq!(node.vcs.journal.conflicts().len(), 1);
+        }
+        assert_eq!(nodes[0].vcs.journal.conflicts()[0].resolution, Resolution::KeptRemote);
+        assert_eq!(nodes[1].vcs.journal.conflicts()[0].resolution, Resolution::KeptLocal);
+
+        let copies: Vec<String> = nodes[0].files().into_iter().filter(|name| name != "photo.bin").collect();
+        assert_eq!(copies.len(), 1);
+        let prefix = format!("photo.conflict-{}-", short_device_id(nodes[0].device));
+        assert!(copies[0].starts_with(&prefix) && copies[0].ends_with(".bin"), "unexpected copy {}", copies[0]);
+        assert_eq!(nodes[0].read(&copies[0]).as_deref(), Some("from a\0"));
+        assert_eq!(nodes[0].vcs.journal.conflicts()[0].copy, Some(Path::new(".").join(&copies[0])));
+        assert_eq!(nodes[1].files(), vec!["photo.bin"]);
+    }
+
+    #[test]
+    fn concurrent_edits_of_text_are_merged() {
+        let mut nodes = [Node::new("merge-a", 1), Node::new("merge-b", 2)];
+        let links = [(0, 1)];
+        connect(&mut nodes, &links);
+        settle(&mut nodes, &links);
+        nodes[0].write("notes.txt", "one\ntwo\nthree\n");
+        settle(&mut nodes, &links);
+
+        nodes[0].write("notes.txt", "ONE\ntwo\nthree\n");
+        nodes[1].write("notes.txt", "one\ntwo\nTHREE\n");
+        settle(&mut nodes, &links);
+        for node in &nodes {
+            assert_eq!(node.read("notes.txt").as_deref(), Some("ONE\ntwo\nTHREE\n"));
+            assert_eq!(node.files(), vec!["notes.txt"]);
+            assert!(node.vcs.journal.conflicts().is_empty());
+        }
+        let versions: Vec<_> = nodes.iter()
+            .map(|node| node.vcs.index.get_version(&hash_path(Path::new("./notes.txt"))).cloned().unwrap())
+            .collect();
+        assert_eq!(versions[0].compare(&versions[1]), VersionOrdering::Equal);
+    }
+
+    #[test]
+    fn overlapping_edits_are_merged_with_markers() {
+        let mut nodes = [Node::new("markers-a", 1), Node::new("markers-b", 2)];
+        let links = [(0, 1)];
+        connect(&mut nodes, &links);
+        settle(&mut nodes, &links);
+        nodes[0].write("notes.txt", "one\ntwo\nthree\n");
+        settle(&mut nodes, &links);
+
+        nodes[0].write("notes.txt", "one\nTWO\nthree\n");
+        nodes[1].write("notes.txt", "one\n2\nthree\n");
+        settle(&mut nodes, &links);
+        let merged = nodes[0].read("notes.txt").unwrap();
+        assert!(merge::has_conflict_markers(&merged));
+        assert!(merged.contains("TWO\n") && merged.contains("2\n"));
+        assert_eq!(nodes[1].read("notes.txt").as_deref(), Some(merged.as_str()));
+        assert!(nodes.iter().any(|node| node.vcs.journal.conflicts().iter().any(|conflict| conflict.resolution == Resolution::Merged)));
+    }
+}
@@ -0,0 +1,29 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use crate::common::control::{self, Request, Response};
+use crate::common::merge::has_conflict_markers;
+use crate::common::journal::Journal;
+
+pub use crate::common::conflict::{Conflict, Resolution};
+
+/// The conflicts recorded in the journal, oldest first. A conflict is open for as long as its
+/// conflict copy exists, deleting the copy once both versions have been merged resolves it.
+/// Merges with conflict markers are open until the markers are gone.
+pub fn list(dir: &Path) -> io::Result<Vec<Conflict>> {
+    // Asks the running process, which holds the journal open, and reads the journal otherwise
+    match control::request(dir, &Request::Conflicts)? {
+        Some(Response::Conflicts(conflicts)) => Ok(conflicts),
+        _ => Journal::conflicts_in(&dir.join(".rdovetail")),
+    }
+}
+
+/// Whether the conflict still has to be looked at.
+pub fn is_open(dir: &Path, conflict: &Conflict) -> bool {
+    if conflict.resolution == Resolution::Merged {
+        // Open until the conflict markers have been edited out
+        let content = fs::read_to_string(dir.join(&conflict.file_path)).unwrap_or_default();
+        return has_conflict_markers(&content);
+    }
+    conflict.copy.as_ref().is_some_and(|copy| dir.join(copy).exists())
+}
@@ -0,0 +1,45 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use crate::common::history::{file_versions, journal_path};
+use crate::common::journal::Journal;
+use crate::common::objects::ObjectStore;
+use crate::common::util::parse_utc;
+
+pub use crate::common::history::FileVersion;
+
+/// The stored versions of the file, oldest first.
+pub fn list(root: &Path, path: &Path) -> io::Result<Vec<FileVersion>> {
+    let dovetail_dir = root.join(".rdovetail");
+    let changes = Journal::changes_in(&dovetail_dir)?;
+    let objects = ObjectStore::open(&dovetail_dir)?;
+    Ok(file_versions(&changes, &journal_path(root, path), &objects))
+}
+
+/// Writes the version the file had at the given time, as YYYYMMDD-HHMMSS in UTC, back into
+/// the directory and returns it. A running rdovetail announces the restored file to the peers
+/// like any other edit.
+pub fn restore(root: &Path, path: &Path, at: &str) -> io::Result<FileVersion> {
+    let at = match parse_utc(at) {
+        Some(secs) => secs.saturating_mul(1_000_000_000),
+        None => return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid time {}, expected YYYYMMDD-HHMMSS in UTC", at)
+        )),
+    };
+    let version = match list(root, path)?.into_iter().rev().find(|version| version.timestamp <= at) {
+        Some(version) => version,
+        None => return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No stored version of {:?} at that time", path)
+        )),
+    };
+
+    let content = ObjectStore::open(&root.join(".rdovetail"))?.get(&version.content_hash)?;
+    let target = root.join(journal_path(root, path));
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(&target, content)?;
+    Ok(version)
+}
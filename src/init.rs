@@ -0,0 +1,47 @@
+use std::path::Path;
+use std::error::Error;
+use crate::common::error::IllegalState;
+use crate::common::folder::{folder_id, set_folder_id, FolderId};
+use crate::common::identity::{self, fingerprint};
+use crate::common::version_control;
+
+/// What `init` did with the directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Initialized {
+    /// Set up as a new folder, with the id and the fingerprint of the device.
+    Created {
+        id: FolderId,
+        fingerprint: String,
+    },
+    /// Already a folder, which was given the new id.
+    Renamed {
+        id: FolderId,
+    },
+    /// Already a folder, left as it was.
+    Existing,
+}
+
+/// Sets up the directory as a synchronized folder: creates the device identity, stores the
+/// folder id and indexes the files already there. The id of an existing folder can be changed
+/// by running it again with a new one.
+pub fn init(dir: &Path, id: Option<&str>, threads: usize) -> Result<Initialized, Box<dyn Error>> {
+    if id.is_some_and(|id| id.trim().is_empty()) {
+        return Err(Box::new(IllegalState::new("The folder id cannot be empty".to_string())));
+    }
+    if !version_control::init(dir, threads)? {
+        return Ok(match id {
+            Some(id) => {
+                set_folder_id(dir, id)?;
+                Initialized::Renamed { id: id.to_string() }
+            },
+            None => Initialized::Existing,
+        });
+    }
+    // Stored either way, so renaming the directory does not change the id
+    set_folder_id(dir, &id.map(str::to_string).unwrap_or(folder_id(dir)?))?;
+    let keypair = identity::load_or_create_keypair(&dir.join(".rdovetail"))?;
+    Ok(Initialized::Created {
+        id: folder_id(dir)?,
+        fingerprint: fingerprint(&keypair.public),
+    })
+}
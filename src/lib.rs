@@ -0,0 +1,145 @@
+//! Keeps folders in sync between devices over authenticated, encrypted connections.
+//!
+//! The `rdovetail` binary is a thin command line wrapper around this crate. Services embedding
+//! synchronization use the API in [`api`]: open a [`SyncFolder`], scan it, start syncing it,
+//! subscribe to the changes journaled there, connect to peers and apply changes of their own.
+//! The other modules inspect and maintain folders, returning what they find.
+
+use std::error::Error;
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use log::info;
+use common::daemon::{self, PidFile, DAEMON_LOG};
+use common::net;
+
+pub mod api;
+pub mod conflicts;
+pub mod history;
+pub mod init;
+pub mod pair;
+pub mod snapshot;
+pub mod status;
+pub mod sync;
+pub mod trash;
+mod client;
+mod peer;
+mod server;
+mod common {
+    pub mod version_control;
+    pub mod backoff;
+    pub mod compression;
+    pub mod config_file;
+    pub mod conflict;
+    pub mod control;
+    pub mod daemon;
+    pub mod discovery;
+    pub mod folder;
+    pub mod history;
+    pub mod identity;
+    pub mod ignore;
+    pub mod journal;
+    pub mod merge;
+    pub mod metrics;
+    pub mod message;
+    pub mod net;
+    pub mod objects;
+    pub mod outbox;
+    pub mod peers;
+    pub mod snapshot;
+    pub mod framing;
+    pub mod throttle;
+    pub mod transport;
+    pub mod trash;
+    pub mod util;
+    pub mod data;
+    pub mod error;
+}
+
+pub use api::{LocalChange, SyncFolder, Syncing};
+pub use common::config_file::ConfigFile;
+pub use common::conflict::{short_device_id, ConflictPolicy};
+pub use common::control::FolderStatus;
+pub use common::data::{Change, ChangeId, ChangeType, DeviceId, VersionVector};
+pub use common::error::{IllegalState, NotInitialized};
+pub use common::folder::{validate_root, FolderId};
+pub use common::history::{Retention, DEFAULT_KEEP_DAYS, DEFAULT_KEEP_VERSIONS};
+pub use common::peers::Origin;
+pub use common::util::format_utc;
+pub use common::version_control::{is_initialized, ChangeEvent, Settings, DEFAULT_TRASH_DAYS};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Connects to a single server.
+    Client,
+    /// Accepts connections from clients and relays changes between them.
+    Server,
+    /// Accepts connections and dials the configured peers.
+    Peer,
+}
+
+/// Options of the commands that keep folders in sync.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// The synchronized folder, which holds the device identity.
+    pub root: PathBuf,
+    /// Further folders synced by the same process.
+    pub extra_roots: Vec<PathBuf>,
+    /// The server to connect to in client mode, as given.
+    pub address: Option<String>,
+    /// Addresses to accept connections on, the default address if empty.
+    pub listen: Vec<String>,
+    pub mode: Mode,
+    /// Resolved again on every connection attempt.
+    pub peers: Vec<String>,
+    pub discover: bool,
+    pub discovery_interface: Ipv4Addr,
+    pub settings: Settings,
+    /// Limit for sending file contents, in bytes per second.
+    pub upload_limit: Option<u64>,
+    /// Limit for receiving file contents, in bytes per second.
+    pub download_limit: Option<u64>,
+    /// Where metrics are served, if anywhere.
+    pub metrics: Option<String>,
+}
+
+impl Config {
+    /// The server to connect to, always given in client mode.
+    pub fn server(&self) -> &str {
+        self.address.as_deref().unwrap_or_default()
+    }
+
+    /// Every synchronized folder, the one holding the device identity first.
+    pub fn roots(&self) -> Vec<PathBuf> {
+        let mut roots = vec![self.root.clone()];
+        roots.extend(self.extra_roots.iter().cloned());
+        roots
+    }
+
+    /// Names are only resolved when used, this reports malformed addresses right away.
+    pub fn check_addresses(&self) -> Result<(), IllegalState> {
+        for address in self.address.iter().chain(&self.peers).chain(&self.listen).chain(&self.metrics) {
+            net::with_default_port(address).map_err(|err| IllegalState::new(err.to_string()))?;
+        }
+        Ok(())
+    }
+}
+
+/// Locks the folders and keeps them in sync in the mode of the config until the process is
+/// stopped by a signal. With `daemon`, keeps running in the background with the output going to
+/// `.rdovetail/daemon.log` of the first folder.
+pub fn start(config: &Config, daemon: bool) -> Result<(), Box<dyn Error>> {
+    let mut pid_files = PidFile::acquire_all(&config.roots())?;
+    if daemon {
+        // Before any thread is started, the background process keeps the locks
+        daemon::daemonize(&config.root.join(DAEMON_LOG))?;
+        for pid_file in &mut pid_files {
+            pid_file.write_pid()?;
+        }
+    }
+    info!("Mode: {:?}", config.mode);
+    match config.mode {
+        Mode::Server => server::init(config),
+        Mode::Client => client::init(config),
+        Mode::Peer => peer::init(config),
+    }
+}
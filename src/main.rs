@@ -1,76 +1,105 @@
-use std::process;
-use std::net::{AddrParseError, IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
-use std::str::FromStr;
+use std::error::Error;
+use std::process::ExitCode;
 use clap::Parser;
-use common::error::IllegalState;
+use rdovetail::{conflicts, history, init, is_initialized, pair, snapshot, status, sync, trash, IllegalState, NotInitialized};
+use cli::args::{Args, Command, ConfigAction, SnapshotAction, TrashAction};
+use cli::options::{build_config, effective_config, extra_roots, init_logging, settings, sync_root, threads};
+use cli::output;
 
-pub mod server;
-pub mod client;
-pub mod common {
-    pub mod version_control;
-    pub mod message;
-    pub mod util;
-    pub mod data;
-    pub mod error;
+mod cli {
+    pub mod args;
+    pub mod logging;
+    pub mod options;
+    pub mod output;
 }
 
-fn main() {
-    let args = Args::try_parse();
-    let args = args.unwrap_or_else(|help_message| {
-        println!("{}", help_message); // For the info flags like help and version
-        process::exit(0);
-    });
+/// Exit status for failures at runtime.
+const EXIT_FAILURE: u8 = 1;
+/// Exit status for invalid arguments, the same clap uses for parse errors.
+const EXIT_USAGE: u8 = 2;
+/// Exit status for commands run outside of an initialized folder.
+const EXIT_NOT_INITIALIZED: u8 = 3;
 
-    let config = match Config::build(args) {
-        Ok(config) => config,
+fn main() -> ExitCode {
+    // Prints help and version with status 0, and parse errors with EXIT_USAGE
+    let args = Args::parse();
+    match run(args) {
+        Ok(()) => ExitCode::SUCCESS,
         Err(err) => {
-            println!("{}", err);
-            process::exit(1);
-        }
-    };
-
-    println!("Address: {}\nServer mode: {}", config.address, config.server_mode);
-
-    if config.server_mode {
-        server::init(&config);
-    } else {
-        let res = client::init(&config);
+            eprintln!("{}", err);
+            ExitCode::from(exit_code(err.as_ref()))
+        },
     }
 }
 
-#[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
-struct Args {
-    /// The IP-address of the server being linked to
-    #[arg(short, long, default_value_t = String::new())]
-    ip: String,
-
-    /// Indicates that the current machine should act as a server, and receive incoming connections
-    /// from other machines.
-    #[arg(short, action)]
-    server_mode: bool,
-}
-
-pub struct Config {
-    address: SocketAddr, 
-    server_mode: bool,
+fn exit_code(err: &(dyn Error + 'static)) -> u8 {
+    if err.is::<IllegalState>() {
+        EXIT_USAGE
+    } else if err.is::<NotInitialized>() {
+        EXIT_NOT_INITIALIZED
+    } else {
+        EXIT_FAILURE
+    }
 }
 
-impl Config {
-    fn build(args: Args) -> Result<Config, IllegalState> {
-        let address = match SocketAddr::from_str(&args.ip) {
-            Ok(addr) => Some(addr),
-            Err(_) => None,
-        };
-
-        if !args.server_mode && address.is_none() {
-            return Err(IllegalState::new("Invalid ip for client mode".to_string()))
-        }
-
-        Ok(Config {
-            address: address.unwrap_or(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 50010))),
-            server_mode: args.server_mode,
-        })
+fn run(args: Args) -> Result<(), Box<dyn Error>> {
+    let command = args.command.clone();
+    let root = sync_root(&args)?;
+    init_logging(&args, &root)?;
+    // Pairing only needs the device identity, so devices can be paired before the folder is set up
+    if !matches!(command, Command::Init | Command::Pair { .. } | Command::Config { .. }) && !is_initialized(&root) {
+        return Err(Box::new(NotInitialized { dir: root }));
+    }
 
+    match command {
+        Command::Init => {
+            let threads = threads(&effective_config(&args, &root)?);
+            output::initialized(&root, &init::init(&root, args.folder.as_deref(), threads)?);
+        },
+        Command::Start { daemon } => rdovetail::start(&build_config(args, root)?, daemon)?,
+        Command::Status => output::status(&root, &status::status(&root)?),
+        Command::Sync => match sync::request(&root)? {
+            Some(peers) => println!("Syncing with {} connected peer(s)", peers),
+            None => {
+                sync::run(&build_config(args, root)?)?;
+                println!("Sync finished");
+            },
+        },
+        Command::Scan => {
+            let mut roots = vec![root.clone()];
+            roots.extend(extra_roots(&args, &root)?);
+            let settings = settings(&args, &root, &effective_config(&args, &root)?)?;
+            output::scanned(&sync::scan(&roots, &settings)?);
+        },
+        Command::Peers => output::peers(&pair::list(&root)?),
+        Command::Pause => {
+            sync::pause(&root)?;
+            println!("Paused syncing {}", root.display());
+        },
+        Command::Resume => {
+            sync::resume(&root)?;
+            println!("Resumed syncing {}", root.display());
+        },
+        Command::Pair { name } => output::paired(&pair::pair(&build_config(args, root)?, name, output::confirm_pairing)?),
+        Command::Conflicts => output::conflicts(&root, &conflicts::list(&root)?),
+        Command::History { path } => output::history(&path, &history::list(&root, &path)?),
+        Command::Restore { path, at } => output::restored(&path, &history::restore(&root, &path, &at)?),
+        Command::Trash { action: TrashAction::List } => output::trash(&trash::list(&root)?),
+        Command::Trash { action: TrashAction::Restore { id } } => println!("Restored {:?}", trash::restore(&root, &id)?),
+        Command::Trash { action: TrashAction::Empty } => println!("Removed {} files from the trash", trash::empty(&root)?),
+        Command::Snapshot { action: SnapshotAction::Create { name } } => {
+            let snapshot = snapshot::create(&root, name.as_deref())?;
+            println!("Created snapshot {} of {} files", snapshot.name, snapshot.files.len());
+        },
+        Command::Snapshot { action: SnapshotAction::List } => output::snapshots(&snapshot::list(&root)?),
+        Command::Snapshot { action: SnapshotAction::Diff { from, to } } => {
+            output::differences(&snapshot::diff(&root, &from, to.as_deref())?);
+        },
+        Command::Snapshot { action: SnapshotAction::Checkout { name } } => {
+            let backup = snapshot::checkout(&root, &name)?;
+            println!("Checked out snapshot {}, the previous state is kept as snapshot {}", name, backup.name);
+        },
+        Command::Config { action: ConfigAction::Show } => print!("{}", effective_config(&args, &root)?.to_toml()),
     }
+    Ok(())
 }
@@ -0,0 +1,117 @@
+use std::io;
+use std::path::Path;
+use std::sync::mpsc::channel;
+use std::thread;
+use log::info;
+use crate::common::control::{self, Request, Response};
+use crate::common::data::DeviceId;
+use crate::common::folder::{adopt_identity, folder_id, FolderId};
+use crate::common::identity::{self, device_id, fingerprint, pairing_code, TrustedPeers};
+use crate::common::net;
+use crate::common::outbox::Outbox;
+use crate::common::transport;
+use crate::{Config, Mode};
+
+/// What both devices show while pairing, confirmed by the user on each of them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pairing {
+    pub this_device: String,
+    pub other_device: String,
+    /// Derived from the handshake, the same on both devices unless someone is in between.
+    pub code: String,
+}
+
+/// A device added to the trusted peers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Paired {
+    pub name: String,
+    pub folders: Vec<FolderId>,
+}
+
+/// A trusted peer of a folder.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerSummary {
+    pub name: String,
+    pub fingerprint: String,
+    /// Changes the peer has not acknowledged yet.
+    pub unacknowledged: usize,
+    /// Whether the peer is connected to the running process.
+    pub connected: bool,
+}
+
+/// Connects to (or accepts a connection from) another device and adds it to the trusted peers
+/// of every given folder once `confirm` has made sure both devices show the same pairing code.
+pub fn pair(config: &Config, name: Option<String>, confirm: impl FnOnce(&Pairing) -> io::Result<bool>) -> io::Result<Paired> {
+    let roots = config.roots();
+    let keypair = identity::load_or_create_keypair(&config.root.join(".rdovetail"))?;
+    for root in &roots[1..] {
+        adopt_identity(&config.root, root)?;
+    }
+
+    let (connection, peer_address) = if config.mode != Mode::Client {
+        // The first device to connect on any of the addresses is paired with
+        let (tx_accepted, rx_accepted) = channel();
+        for listener in net::bind(&config.listen)? {
+            info!("Waiting for the other device on {}", listener.local_addr()?);
+            let tx_accepted = tx_accepted.clone();
+            thread::spawn(move || tx_accepted.send(listener.accept()));
+        }
+        drop(tx_accepted);
+        let (stream, peer_address) = rx_accepted.recv().map_err(io::Error::other)??;
+        (transport::accept(stream, &keypair)?, peer_address)
+    } else {
+        let stream = net::connect(config.server())?;
+        let peer_address = stream.peer_addr()?;
+        (transport::connect(stream, &keypair)?, peer_address)
+    };
+
+    let pairing = Pairing {
+        this_device: fingerprint(&keypair.public),
+        other_device: fingerprint(&connection.remote_static),
+        code: pairing_code(&connection.handshake_hash),
+    };
+    if !confirm(&pairing)? {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "Pairing was not confirmed"));
+    }
+
+    let name = name.unwrap_or_else(|| peer_address.ip().to_string());
+    let mut folders = Vec::new();
+    for root in &roots {
+        let mut trusted_peers = TrustedPeers::from_file(&root.join(".rdovetail"))?;
+        trusted_peers.add(connection.remote_static.clone(), name.clone());
+        trusted_peers.write_to_file()?;
+        folders.push(folder_id(root)?);
+    }
+    Ok(Paired { name, folders })
+}
+
+/// The trusted peers with the number of changes each has not acknowledged yet.
+pub fn list(root: &Path) -> io::Result<Vec<PeerSummary>> {
+    let dovetail_dir = root.join(".rdovetail");
+    let trusted_peers = TrustedPeers::from_file(&dovetail_dir)?;
+    let outbox = Outbox::open(&dovetail_dir)?;
+    // Devices connected to the running process, if there is one
+    let connected = match control::request(root, &Request::Peers)? {
+        Some(Response::Peers(connected)) => connected,
+        _ => Vec::new(),
+    };
+    Ok(trusted_peers.iter()
+        .map(|peer| {
+            let device = device_id(&peer.public_key);
+            PeerSummary {
+                name: peer.name.clone(),
+                fingerprint: fingerprint(&peer.public_key),
+                unacknowledged: outbox.unacknowledged(device).len(),
+                connected: connected.iter().any(|connected| connected.device == device),
+            }
+        })
+        .collect())
+}
+
+/// The device of the trusted peer with the name, if the folder has one.
+pub fn find(root: &Path, name: &str) -> io::Result<Option<DeviceId>> {
+    Ok(TrustedPeers::from_file(&root.join(".rdovetail"))?
+        .iter()
+        .find(|peer| peer.name == name)
+        .map(|peer| device_id(&peer.public_key)))
+}
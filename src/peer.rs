@@ -0,0 +1,142 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use log::{error, info, warn};
+use crate::common::backoff::Backoff;
+use crate::common::data::DeviceId;
+use crate::common::discovery::{self, Announcement, DiscoveryConfig};
+use crate::common::folder::Folders;
+use crate::common::framing::FrameCodec;
+use crate::common::metrics;
+use crate::common::net;
+use crate::common::identity::{self, device_id, fingerprint};
+use crate::common::peers::{self, Peers};
+use crate::common::transport::Keypair;
+use crate::common::util::sleep_unless_stopped;
+use crate::{client, server, Config};
+
+/// Symmetric mode: listens for peers like a server, while also dialing every configured or
+/// discovered peer.
+/// All connections share one VCS per folder, and changes are forwarded through the mesh until
+/// every device sharing the folder has journaled them.
+pub fn init(config: &Config) -> Result<(), Box<dyn Error>> {
+    let listeners = net::bind(&config.listen)?;
+    let dovetail_dir = config.root.join(".rdovetail");
+    let keypair = identity::load_or_create_keypair(&dovetail_dir)?;
+    info!("Device fingerprint: {}", fingerprint(&keypair.public));
+
+    let peers = Arc::new(Peers::with_limits(config.upload_limit, config.download_limit));
+    let folders = Folders::start(&config.roots(), device_id(&keypair.public), &config.settings, &peers)?;
+    folders.stop_on_signal()?;
+    if let Some(address) = &config.metrics {
+        metrics::serve(address, Arc::clone(&peers.metrics))?;
+    }
+    // Never set, the process exits on a signal
+    let stop = Arc::new(AtomicBool::new(false));
+
+    for address in &config.peers {
+        let address = address.clone();
+        let keypair = keypair.clone();
+        let folders = folders.clone();
+        let peers = Arc::clone(&peers);
+        let stop = Arc::clone(&stop);
+        thread::spawn(move || dial(address, None, keypair, folders, peers, stop));
+    }
+
+    if config.discover {
+        // Peers reach this node on the port of the first listener, whatever address they see
+        let port = listeners[0].local_addr()?.port();
+        start_discovery(config, port, &keypair, &folders, &peers, &stop)?;
+    }
+
+    server::serve(listeners, keypair, folders, peers, stop);
+    Ok(())
+}
+
+/// Announces this node on the local network and dials every trusted node syncing one of the
+/// same folders. Of two nodes that discover each other, only the one with the lower device id
+/// dials. The device id is derived from the announced key rather than taken from the
+/// announcement, and the handshake proves the node holds that key.
+fn start_discovery(
+    config: &Config,
+    port: u16,
+    keypair: &Keypair,
+    folders: &Folders,
+    peers: &Arc<Peers>,
+    stop: &Arc<AtomicBool>,
+) -> Result<(), Box<dyn Error>> {
+    let device = device_id(&keypair.public);
+    let ids: Vec<String> = folders.iter().map(|folder| folder.id.clone()).collect();
+    info!("Discovering peers for folders {:?}", ids);
+    let announcement = Announcement::new(device, keypair.public.clone(), port, ids);
+    let discovery_config = DiscoveryConfig {
+        interface: config.discovery_interface,
+        ..DiscoveryConfig::default()
+    };
+
+    let keypair = keypair.clone();
+    let folders = folders.clone();
+    let peers = Arc::clone(peers);
+    let dial_stop = Arc::clone(stop);
+    let mut dialed: HashSet<DeviceId> = HashSet::new();
+    discovery::start(discovery_config, announcement, Arc::clone(stop), move |remote, address| {
+        if device_id(&remote.public_key) != remote.device {
+            warn!("Ignoring the announcement from {}, its device id does not match its key", address);
+            return;
+        }
+        if remote.device < device || dialed.contains(&remote.device) {
+            return;
+        }
+        // Reloaded for every announcement, so devices paired while running are picked up
+        match folders.is_trusted(&remote.public_key) {
+            Ok(true) => {},
+            Ok(false) => return,
+            Err(err) => {
+                error!("Failed to read trusted peers: {}", err);
+                return;
+            },
+        }
+
+        info!("Discovered {} at {}", fingerprint(&remote.public_key), address);
+        dialed.insert(remote.device);
+        let keypair = keypair.clone();
+        let folders = folders.clone();
+        let peers = Arc::clone(&peers);
+        let stop = Arc::clone(&dial_stop);
+        thread::spawn(move || dial(address.to_string(), Some(remote.public_key), keypair, folders, peers, stop));
+    })?;
+    Ok(())
+}
+
+/// Keeps a connection to the peer open until the flag is set, backing off between failed
+/// attempts. Every attempt resolves the address again. An open connection is not cut when the
+/// flag is set, the peer is no longer dialed once it ends. With an expected key, a node at the
+/// address holding another key is hung up on.
+pub fn dial(
+    address: String,
+    expected: Option<Vec<u8>>,
+    keypair: Keypair,
+    folders: Folders,
+    peers: Arc<Peers>,
+    stop: Arc<AtomicBool>,
+) {
+    let frame_codec = FrameCodec::default();
+    let mut backoff = Backoff::default();
+    while !stop.load(Ordering::SeqCst) {
+        match client::connect(&address, &keypair, &folders, &frame_codec) {
+            Ok((_, _, _, remote_static)) if expected.as_ref().is_some_and(|key| *key != remote_static) => {
+                warn!("{} at {} is not the discovered device", fingerprint(&remote_static), address);
+            },
+            Ok((mut reader, writer, codec, remote_static)) => {
+                info!("Connected to {}, using codec {:?}", address, codec);
+                backoff.reset();
+                let result = peers::run_session(frame_codec, &mut reader, writer, codec, &remote_static, &peers, &folders);
+                server::report_closed(result);
+            },
+            Err(err) => warn!("Failed to connect to {}: {}", address, err),
+        }
+        sleep_unless_stopped(backoff.next_delay(), &stop);
+    }
+}
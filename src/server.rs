@@ -1,22 +1,183 @@
-use std::net::TcpListener;
-use std::io::{BufRead, BufReader, Read};
+use std::error::Error;
+use std::net::{TcpListener, TcpStream};
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+use log::{debug, info, warn};
+use crate::common::compression::{negotiate, Codec, SUPPORTED_CODECS};
+use crate::common::error::ProtocolError;
+use crate::common::framing::FrameCodec;
+use crate::common::folder::Folders;
 use crate::common::message::Message;
+use crate::common::metrics;
+use crate::common::net;
+use crate::common::identity::{self, device_id, fingerprint};
+use crate::common::peers::{self, Peers};
+use crate::common::transport::{self, Keypair, SecureConnection, SecureReader, SecureWriter};
 use crate::Config;
 
-pub fn init(config: &Config) {
-    let listener = TcpListener::bind(config.address).unwrap();
-    for stream in listener.incoming() {
-        println!("Connection made");
-        let socket = stream.unwrap();
-        let mut reader = BufReader::new(socket);
+pub fn init(config: &Config) -> Result<(), Box<dyn Error>> {
+    let listeners = net::bind(&config.listen)?;
+    let dovetail_dir = config.root.join(".rdovetail");
+    let keypair = identity::load_or_create_keypair(&dovetail_dir)?;
+    info!("Device fingerprint: {}", fingerprint(&keypair.public));
 
-        let mut message_length = [0u8; 8];
-        reader.read_exact(&mut message_length).unwrap();
-        let mut buffer: Vec<u8> = vec![0u8; u64::from_be_bytes(message_length) as usize];
+    // One VCS per folder is shared by all connections, changes from one client are fanned out
+    // to the rest
+    let peers = Arc::new(Peers::with_limits(config.upload_limit, config.download_limit));
+    let folders = Folders::start(&config.roots(), device_id(&keypair.public), &config.settings, &peers)?;
+    folders.stop_on_signal()?;
+    if let Some(address) = &config.metrics {
+        metrics::serve(address, Arc::clone(&peers.metrics))?;
+    }
+
+    // Never set, the process exits on a signal
+    serve(listeners, keypair, folders, peers, Arc::new(AtomicBool::new(false)));
+    Ok(())
+}
+
+/// How often a listener checks whether it was stopped while no connections come in.
+const ACCEPT_POLL: Duration = Duration::from_millis(100);
+
+/// Accepts connections on every listener until the flag is set. Connections already accepted
+/// are not cut.
+pub fn serve(
+    listeners: Vec<TcpListener>,
+    keypair: Keypair,
+    folders: Folders,
+    peers: Arc<Peers>,
+    stop: Arc<AtomicBool>,
+) {
+    let handles: Vec<_> = listeners
+        .into_iter()
+        .map(|listener| {
+            if let Ok(address) = listener.local_addr() {
+                info!("Listening on {}", address);
+            }
+            let keypair = keypair.clone();
+            let folders = folders.clone();
+            let peers = Arc::clone(&peers);
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || accept_connections(listener, keypair, folders, peers, &stop))
+        })
+        .collect();
+    for handle in handles {
+        let _ = handle.join();
+    }
+}
+
+/// Serves every incoming connection on its own thread, until the flag is set.
+fn accept_connections(
+    listener: TcpListener,
+    keypair: Keypair,
+    folders: Folders,
+    peers: Arc<Peers>,
+    stop: &AtomicBool,
+) {
+    // Blocking in accept would never see the flag
+    if let Err(err) = listener.set_nonblocking(true) {
+        warn!("Failed to poll listener: {}", err);
+        return;
+    }
+    while !stop.load(Ordering::SeqCst) {
+        let socket = match listener.accept() {
+            // Accepted sockets inherit the mode of the listener on some platforms
+            Ok((socket, _)) => match socket.set_nonblocking(false) {
+                Ok(()) => socket,
+                Err(err) => {
+                    warn!("Failed to accept connection: {}", err);
+                    continue;
+                },
+            },
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(ACCEPT_POLL);
+                continue;
+            },
+            Err(err) => {
+                warn!("Failed to accept connection: {}", err);
+                continue;
+            },
+        };
+        debug!("Connection made");
+
+        let keypair = keypair.clone();
+        let folders = folders.clone();
+        let peers = Arc::clone(&peers);
+        thread::spawn(move || {
+            let result = handle_connection(socket, &keypair, &folders, &peers);
+            report_closed(result);
+        });
+    }
+}
+
+/// Prints why a connection ended.
+pub fn report_closed(result: Result<(), ProtocolError>) {
+    match result {
+        Err(ProtocolError::Io(err)) if err.kind() == io::ErrorKind::UnexpectedEof => {
+            info!("Peer disconnected");
+        },
+        Err(err) => warn!("Connection closed: {}", err),
+        Ok(()) => {},
+    }
+}
+
+fn handle_connection(
+    socket: TcpStream,
+    keypair: &Keypair,
+    folders: &Folders,
+    peers: &Peers,
+) -> Result<(), ProtocolError> {
+    let SecureConnection {
+        mut reader,
+        mut writer,
+        remote_static,
+        ..
+    } = transport::accept(socket, keypair)?;
+    let frame_codec = FrameCodec::default();
+
+    let codec = match greet_peer(&frame_codec, &mut reader, &mut writer, &remote_static, folders) {
+        Ok(codec) => codec,
+        Err(err) => {
+            if err.should_notify_peer() {
+                reply_error(&frame_codec, &mut writer, &err);
+            }
+            return Err(err);
+        },
+    };
 
-        let read = reader.read_exact(&mut buffer).unwrap();
-        let message: Message = bincode::deserialize(&buffer).unwrap();
+    peers::run_session(frame_codec, &mut reader, writer, codec, &remote_static, peers, folders)
+}
+
+/// Checks that the peer is trusted with at least one folder and negotiates the codec for file
+/// payloads.
+fn greet_peer(
+    frame_codec: &FrameCodec,
+    reader: &mut SecureReader,
+    writer: &mut SecureWriter,
+    remote_static: &[u8],
+    folders: &Folders,
+) -> Result<Codec, ProtocolError> {
+    // Reloaded for every connection, so devices paired while the server runs are accepted
+    match folders.peer_name(remote_static)? {
+        Some(name) => info!("Authenticated peer {}", name),
+        None => return Err(ProtocolError::Untrusted(fingerprint(remote_static))),
+    }
+
+    let codec = match frame_codec.read_message(reader)? {
+        Message::Hello { codecs } => negotiate(&SUPPORTED_CODECS, &codecs),
+        other => return Err(ProtocolError::UnexpectedMessage(format!("{:?}", other))),
+    };
+    frame_codec.write_message(writer, &Message::Hello { codecs: SUPPORTED_CODECS.to_vec() })?;
+    debug!("Using codec {:?}", codec);
+    Ok(codec)
+}
 
-        println!("Message: {:?}", &message);
+/// Best effort attempt to tell the peer why the connection is being closed.
+fn reply_error(frame_codec: &FrameCodec, writer: &mut SecureWriter, err: &ProtocolError) {
+    let message = Message::Error { message: err.to_string() };
+    if let Err(err) = frame_codec.write_message(writer, &message) {
+        warn!("Failed to report error to peer: {}", err);
     }
 }
@@ -0,0 +1,78 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::SystemTime;
+use crate::common::objects::ObjectStore;
+use crate::common::snapshot::{self, SnapshotStore};
+use crate::common::util::{as_nanos_since_epoch, format_utc};
+
+pub use crate::common::snapshot::{Difference, Snapshot};
+
+/// Snapshots the folder as it is now, named after the current time unless a name is given.
+pub fn create(root: &Path, name: Option<&str>) -> io::Result<Snapshot> {
+    take(root, name)
+}
+
+/// Every snapshot of the folder, oldest first.
+pub fn list(root: &Path) -> io::Result<Vec<Snapshot>> {
+    open(root)?.list()
+}
+
+/// The files added, modified and removed since the snapshot `from`, up to the snapshot `to` or
+/// the folder as it is now.
+pub fn diff(root: &Path, from: &str, to: Option<&str>) -> io::Result<Vec<Difference>> {
+    let store = open(root)?;
+    let old = store.load(from)?.files;
+    let new = match to {
+        Some(to) => store.load(to)?.files,
+        None => snapshot::scan(root, None)?,
+    };
+    Ok(snapshot::diff(&old, &new))
+}
+
+/// Rolls the whole folder back to the snapshot. The current state is snapshotted first, so the
+/// checkout can be undone, and a running rdovetail announces the changed files to the peers.
+/// Returns the snapshot of the previous state.
+pub fn checkout(root: &Path, name: &str) -> io::Result<Snapshot> {
+    let target = open(root)?.load(name)?;
+    let objects = ObjectStore::open(&root.join(".rdovetail"))?;
+    // Checks every file is still there before touching the folder
+    if let Some((path, _)) = target.files.iter().find(|(_, hash)| !objects.contains(hash)) {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("Content of {:?} is missing", path)));
+    }
+
+    let backup = take(root, Some(&format!("before-{}-{}", name, format_utc(now() / 1_000_000_000))))?;
+    for difference in snapshot::diff(&backup.files, &target.files) {
+        match difference {
+            Difference::Added(path) | Difference::Modified(path) => {
+                let path_in_dir = root.join(&path);
+                if let Some(parent) = path_in_dir.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::write(&path_in_dir, objects.get(&target.files[&path])?)?;
+            },
+            Difference::Removed(path) => fs::remove_file(root.join(&path))?,
+        }
+    }
+    Ok(backup)
+}
+
+fn take(root: &Path, name: Option<&str>) -> io::Result<Snapshot> {
+    let created = now();
+    let objects = ObjectStore::open(&root.join(".rdovetail"))?;
+    let snapshot = Snapshot {
+        name: name.map(str::to_string).unwrap_or_else(|| format_utc(created / 1_000_000_000)),
+        created,
+        files: snapshot::scan(root, Some(&objects))?,
+    };
+    open(root)?.save(&snapshot)?;
+    Ok(snapshot)
+}
+
+fn open(root: &Path) -> io::Result<SnapshotStore> {
+    SnapshotStore::open(&root.join(".rdovetail"))
+}
+
+fn now() -> u64 {
+    as_nanos_since_epoch(&SystemTime::now())
+}
@@ -0,0 +1,99 @@
+use std::error::Error;
+use std::path::Path;
+use crate::common::control::{self, FolderStatus, Request, Response};
+use crate::common::data::Index;
+use crate::common::ignore::IgnoreRules;
+use crate::common::identity::{self, device_id, fingerprint, TrustedPeers};
+use crate::common::journal::Journal;
+use crate::common::outbox::Outbox;
+use crate::common::snapshot::SnapshotStore;
+use crate::common::trash::Trash;
+use crate::common::util::{find_relative_path, hash_file, hash_path, is_safe_relative_path, list_files};
+use crate::conflicts;
+
+/// The state of a folder, read from its files and from the process syncing it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FolderReport {
+    /// Fingerprint of the device, `None` until the identity is created by syncing or pairing.
+    pub device: Option<String>,
+    pub tracked_files: usize,
+    pub journaled_changes: usize,
+    /// When the last change was journaled, in nanoseconds since the epoch.
+    pub last_change: Option<u64>,
+    /// Files changed since the index was last updated, which are not journaled yet.
+    pub unscanned_changed: usize,
+    /// Files removed since the index was last updated, which are not journaled yet.
+    pub unscanned_removed: usize,
+    pub open_conflicts: usize,
+    pub trusted_peers: usize,
+    /// Trusted peers by name, with the number of changes they have not acknowledged yet. Peers
+    /// that acknowledged everything are left out.
+    pub unacknowledged: Vec<(String, usize)>,
+    pub trashed_files: usize,
+    pub snapshots: usize,
+    /// The state kept by the running process, `None` if no process is syncing the folder.
+    pub running: Option<FolderStatus>,
+}
+
+/// Summarizes the state of the folder: the tracked files, changes not announced yet or not
+/// acknowledged by the peers, and what needs attention. Only reads the folder.
+pub fn status(dir: &Path) -> Result<FolderReport, Box<dyn Error>> {
+    let dovetail_dir = dir.join(".rdovetail");
+    let device = identity::load_keypair(&dovetail_dir)?.map(|keypair| fingerprint(&keypair.public));
+
+    let index = Index::from_file(&dovetail_dir.join("index"))?;
+    let tracked_files = index.paths().iter().filter(|path| is_safe_relative_path(path)).count();
+    let changes = Journal::changes_in(&dovetail_dir)?;
+    let (unscanned_changed, unscanned_removed) = unscanned_changes(dir, &index)?;
+
+    let open_conflicts = Journal::conflicts_in(&dovetail_dir)?
+        .iter()
+        .filter(|conflict| conflicts::is_open(dir, conflict))
+        .count();
+
+    let outbox = Outbox::open(&dovetail_dir)?;
+    let trusted_peers = TrustedPeers::from_file(&dovetail_dir)?;
+    let unacknowledged = trusted_peers.iter()
+        .map(|peer| (peer.name.clone(), outbox.unacknowledged(device_id(&peer.public_key)).len()))
+        .filter(|(_, unacknowledged)| *unacknowledged > 0)
+        .collect();
+
+    let running = match control::request(dir, &Request::Status)? {
+        Some(Response::Status(status)) => Some(status),
+        _ => None,
+    };
+    Ok(FolderReport {
+        device,
+        tracked_files,
+        journaled_changes: changes.len(),
+        last_change: changes.iter().map(|change| change.timestamp).max(),
+        unscanned_changed,
+        unscanned_removed,
+        open_conflicts,
+        trusted_peers: trusted_peers.iter().count(),
+        unacknowledged,
+        trashed_files: Trash::open(&dovetail_dir)?.list()?.len(),
+        snapshots: SnapshotStore::open(&dovetail_dir)?.list()?.len(),
+        running,
+    })
+}
+
+/// Files changed and removed since the index was last updated, leaving out ignored paths.
+fn unscanned_changes(dir: &Path, index: &Index) -> Result<(usize, usize), Box<dyn Error>> {
+    let ignore = IgnoreRules::load(dir)?;
+    let changed = list_files(dir)?
+        .iter()
+        .filter(|path| {
+            let relative_path = find_relative_path(dir.iter(), path.iter());
+            !ignore.is_ignored(&relative_path) && match index.get_file_data(&hash_path(&relative_path)) {
+                Some(file_data) => hash_file(path).as_ref() != Some(file_data.get_hash()),
+                None => true,
+            }
+        })
+        .count();
+    let removed = index.paths()
+        .iter()
+        .filter(|path| is_safe_relative_path(path) && !ignore.is_ignored(path) && !dir.join(path).exists())
+        .count();
+    Ok((changed, removed))
+}
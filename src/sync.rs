@@ -0,0 +1,159 @@
+use std::error::Error;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::Duration;
+use log::{info, warn};
+use crate::common::control::{self, Request, Response};
+use crate::common::daemon::PidFile;
+use crate::common::error::IllegalState;
+use crate::common::folder::{self, Folder, FolderId, Folders};
+use crate::common::framing::FrameCodec;
+use crate::common::identity::{self, device_id};
+use crate::common::peers::{self, Peers};
+use crate::common::version_control::{self, Settings};
+use crate::{client, server, Config, Mode};
+
+/// How long no messages may be exchanged, once every peer has sent its state and acknowledged
+/// every change, before a one-shot sync is complete. Covers changes a peer is still replaying.
+const QUIET_PERIOD: Duration = Duration::from_millis(500);
+
+/// Changes journaled by a scan of a folder.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Scanned {
+    pub folder: FolderId,
+    pub changed: usize,
+    pub removed: usize,
+}
+
+/// Makes the process syncing the folder exchange sync states with its connected peers, returns
+/// their number. `None` if no process is syncing the folder.
+pub fn request(root: &Path) -> io::Result<Option<usize>> {
+    match control::request(root, &Request::Sync)? {
+        Some(Response::Syncing(peers)) => Ok(Some(peers)),
+        _ => Ok(None),
+    }
+}
+
+/// Connects to the server of the config, or to every peer, exchanges all changes and returns
+/// once both sides are up to date. The folders are locked meanwhile and shut down cleanly
+/// either way.
+pub fn run(config: &Config) -> Result<(), Box<dyn Error>> {
+    let _pid_files = PidFile::acquire_all(&config.roots())?;
+    let addresses = match config.mode {
+        Mode::Client => vec![config.server().to_string()],
+        Mode::Peer if !config.peers.is_empty() => config.peers.clone(),
+        _ => return Err(Box::new(IllegalState::new("sync needs the server address or --peer addresses".to_string()))),
+    };
+
+    let dovetail_dir = config.root.join(".rdovetail");
+    let keypair = identity::load_or_create_keypair(&dovetail_dir)?;
+    let peers = Arc::new(Peers::with_limits(config.upload_limit, config.download_limit));
+    let folders = Folders::start(&config.roots(), device_id(&keypair.public), &config.settings, &peers)?;
+    folders.stop_on_signal()?;
+
+    let (tx_connected, rx_connected) = channel();
+    for address in &addresses {
+        let address = address.clone();
+        let keypair = keypair.clone();
+        let folders = folders.clone();
+        let peers = Arc::clone(&peers);
+        let tx_connected = tx_connected.clone();
+        thread::spawn(move || {
+            let frame_codec = FrameCodec::default();
+            match client::connect(&address, &keypair, &folders, &frame_codec) {
+                Ok((mut reader, writer, codec, remote_static)) => {
+                    info!("Connected to {}, using codec {:?}", address, codec);
+                    let _ = tx_connected.send(true);
+                    let result = peers::run_session(frame_codec, &mut reader, writer, codec, &remote_static, &peers, &folders);
+                    server::report_closed(result);
+                },
+                Err(err) => {
+                    warn!("Failed to connect to {}: {}", address, err);
+                    let _ = tx_connected.send(false);
+                },
+            }
+        });
+    }
+    // Every attempt reports once, the sessions keep running in their threads
+    let result = if rx_connected.iter().take(addresses.len()).filter(|connected| *connected).count() == 0 {
+        Err(io::Error::new(io::ErrorKind::NotConnected, "Could not connect to anyone"))
+    } else {
+        wait_until_synced(&folders, &peers)
+    };
+    peers.close();
+    folders.close();
+    Ok(result?)
+}
+
+fn wait_until_synced(folders: &Folders, peers: &Peers) -> io::Result<()> {
+    loop {
+        thread::sleep(Duration::from_millis(100));
+        if peers.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::ConnectionAborted, "Connections closed before the sync finished"));
+        }
+        if peers.idle_for().is_some_and(|idle| idle >= QUIET_PERIOD) && is_synced(folders)? {
+            return Ok(());
+        }
+    }
+}
+
+/// Whether every connected peer has sent its sync state and acknowledged every change sent to
+/// it, and no file content is still being received.
+fn is_synced(folders: &Folders) -> io::Result<bool> {
+    let ask = |folder: &Folder, request| match control::ask(&folder.tx_to_vcs, request) {
+        Response::Error(message) => Err(io::Error::other(message)),
+        response => Ok(response),
+    };
+    for folder in folders.iter() {
+        if let Response::Status(status) = ask(folder, Request::Status)? {
+            if status.pending_transfers > 0 {
+                return Ok(false);
+            }
+        }
+        if let Response::Peers(connected) = ask(folder, Request::Peers)? {
+            if connected.iter().any(|peer| !peer.sent_state || peer.unacknowledged > 0) {
+                return Ok(false);
+            }
+        }
+    }
+    Ok(true)
+}
+
+/// Journals the changes made to the folders while rdovetail was not running. A running
+/// process is asked to look for the changes its watcher missed instead.
+pub fn scan(roots: &[PathBuf], settings: &Settings) -> Result<Vec<Scanned>, Box<dyn Error>> {
+    let mut scanned = Vec::new();
+    if control::request(&roots[0], &Request::Status)?.is_some() {
+        for root in roots {
+            if let Response::Scanned { changed, removed } = control::request_running(root, &Request::Rescan)? {
+                scanned.push(Scanned { folder: folder::folder_id(root)?, changed, removed });
+            }
+        }
+        return Ok(scanned);
+    }
+
+    let _pid_files = PidFile::acquire_all(roots)?;
+    for root in roots {
+        // Journaled under the same device id as the changes made while running
+        if let Some(primary) = roots.first().filter(|primary| *primary != root) {
+            folder::adopt_identity(primary, root)?;
+        }
+        let keypair = identity::load_or_create_keypair(&root.join(".rdovetail"))?;
+        let (changed, removed) = version_control::scan(root, device_id(&keypair.public), settings)?;
+        scanned.push(Scanned { folder: folder::folder_id(root)?, changed, removed });
+    }
+    Ok(scanned)
+}
+
+/// Stops the process syncing the folder from exchanging changes with peers.
+pub fn pause(root: &Path) -> io::Result<()> {
+    control::request_running(root, &Request::Pause).map(|_| ())
+}
+
+/// Makes the process syncing the folder catch up with its peers after a pause.
+pub fn resume(root: &Path) -> io::Result<()> {
+    control::request_running(root, &Request::Resume).map(|_| ())
+}
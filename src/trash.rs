@@ -0,0 +1,25 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use crate::common::trash::Trash;
+
+pub use crate::common::trash::TrashEntry;
+
+/// The files deleted by other devices that are still in the trash by id, oldest first.
+pub fn list(root: &Path) -> io::Result<Vec<(String, TrashEntry)>> {
+    open(root)?.list()
+}
+
+/// Puts a trashed file back at its original path, which is returned. A running rdovetail
+/// announces it to the peers like any other new file.
+pub fn restore(root: &Path, id: &str) -> io::Result<PathBuf> {
+    open(root)?.restore(id, root)
+}
+
+/// Removes every file from the trash, returns their number.
+pub fn empty(root: &Path) -> io::Result<usize> {
+    open(root)?.empty()
+}
+
+fn open(root: &Path) -> io::Result<Trash> {
+    Trash::open(&root.join(".rdovetail"))
+}
@@ -0,0 +1,102 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+/// A directory of its own for every test, also used as the home directory so the config of the
+/// user running the tests is not read.
+struct Sandbox {
+    dir: PathBuf,
+}
+
+impl Sandbox {
+    fn new(name: &str) -> Self {
+        let dir = env::temp_dir().join(format!("rdovetail-cli-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("folder")).unwrap();
+        Sandbox { dir }
+    }
+
+    fn root(&self) -> PathBuf {
+        self.dir.join("folder")
+    }
+
+    /// Runs the command on the folder of the sandbox.
+    fn run(&self, args: &[&str]) -> Output {
+        self.run_in(&self.root(), args)
+    }
+
+    fn run_in(&self, root: &Path, args: &[&str]) -> Output {
+        Command::new(env!("CARGO_BIN_EXE_rdovetail"))
+            .args(args)
+            .arg("--root")
+            .arg(root)
+            .env("HOME", &self.dir)
+            .env_remove("XDG_CONFIG_HOME")
+            .output()
+            .unwrap()
+    }
+}
+
+impl Drop for Sandbox {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+#[test]
+fn commands_inspect_and_maintain_a_folder() {
+    let sandbox = Sandbox::new("commands");
+    let init = sandbox.run(&["init", "--folder", "notes"]);
+    assert_eq!(init.status.code(), Some(0));
+    assert!(stdout(&init).contains("as folder notes"));
+    assert!(stdout(&sandbox.run(&["init"])).contains("is already an rdovetail folder"));
+
+    fs::write(sandbox.root().join("a.txt"), "hello").unwrap();
+    let scan = sandbox.run(&["scan"]);
+    assert_eq!(scan.status.code(), Some(0));
+    assert!(stdout(&scan).contains("Scanned folder notes: 1 changed, 0 removed"));
+
+    let status = stdout(&sandbox.run(&["status"]));
+    assert!(status.contains("Tracked files: 1"));
+    assert!(status.contains("Running: no"));
+    assert!(stdout(&sandbox.run(&["peers"])).contains("No trusted peers"));
+    assert!(stdout(&sandbox.run(&["conflicts"])).contains("No conflicts"));
+    assert!(stdout(&sandbox.run(&["trash", "list"])).contains("The trash is empty"));
+    assert_eq!(stdout(&sandbox.run(&["history", "a.txt"])).lines().count(), 1);
+
+    let created = sandbox.run(&["snapshot", "create", "first"]);
+    assert_eq!(created.status.code(), Some(0));
+    assert!(stdout(&created).contains("Created snapshot first of 1 files"));
+    assert!(stdout(&sandbox.run(&["snapshot", "list"])).starts_with("first created "));
+    fs::write(sandbox.root().join("b.txt"), "new").unwrap();
+    sandbox.run(&["scan"]);
+    assert_eq!(stdout(&sandbox.run(&["snapshot", "diff", "first"])), "A ./b.txt\n");
+
+    let config = stdout(&sandbox.run(&["config", "show", "--threads", "2"]));
+    assert!(config.contains("threads = 2"));
+}
+
+#[test]
+fn exit_codes_tell_failures_apart() {
+    let sandbox = Sandbox::new("exit-codes");
+    // Outside of an initialized folder
+    let status = sandbox.run(&["status"]);
+    assert_eq!(status.status.code(), Some(3));
+    assert!(String::from_utf8_lossy(&status.stderr).contains("init"));
+
+    // Invalid arguments, whether clap or rdovetail rejects them
+    assert_eq!(sandbox.run(&["frobnicate"]).status.code(), Some(2));
+    assert_eq!(sandbox.run(&["init", "--folder", " "]).status.code(), Some(2));
+    assert_eq!(sandbox.run(&["init"]).status.code(), Some(0));
+    assert_eq!(sandbox.run(&["scan", "--snapshot-every", "0"]).status.code(), Some(2));
+    assert_eq!(sandbox.run_in(&sandbox.dir.join("missing"), &["status"]).status.code(), Some(2));
+
+    // Failures at runtime, here no process is running to pause
+    assert_eq!(sandbox.run(&["pause"]).status.code(), Some(1));
+    assert_eq!(sandbox.run(&["restore", "a.txt", "--at", "20240101-000000"]).status.code(), Some(1));
+}